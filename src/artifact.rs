@@ -0,0 +1,100 @@
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A downloaded extension which is stored in the extensions directory.
+pub struct Artifact {
+    /// The path of the stored `.xpi` or `.crx` file.
+    pub path: PathBuf,
+    /// The Firefox add-on guid or the Chromium extension ID.
+    pub id: String,
+    pub version: String,
+    /// The URL the file was (or would have been) downloaded from.
+    pub url: String,
+    /// The SHA-256 of the stored file.
+    pub sha256: String,
+}
+
+/// sha256 computes the hex encoded SHA-256 of a file.
+pub async fn sha256<P: AsRef<Path>>(path: P) -> Result<String> {
+    let contents = tokio::fs::read(path).await?;
+    Ok(format!("{:x}", Sha256::digest(&contents)))
+}
+
+/// store writes a downloaded file atomically, since profiles link the stored file. The file is
+/// readable by all users, which the browsers of other users need for system-wide installs.
+pub async fn store(path: &Path, contents: &[u8]) -> Result<()> {
+    create_dir(path.parent().unwrap()).await?;
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, contents).await?;
+    set_mode(&tmp, 0o644).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// create_dir creates a directory of the extensions directory and its missing parents, which are
+/// accessible by all users like the files stored by [`store`].
+pub async fn create_dir(dir: &Path) -> Result<()> {
+    let mut missing = Vec::new();
+    let mut ancestor = Some(dir);
+    while let Some(dir) = ancestor.filter(|dir| !dir.as_os_str().is_empty() && !dir.exists()) {
+        missing.push(dir);
+        ancestor = dir.parent();
+    }
+    for dir in missing.into_iter().rev() {
+        match tokio::fs::create_dir(dir).await {
+            Ok(()) => set_mode(dir, 0o755).await?,
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sha256() {
+        assert_eq!(
+            sha256("tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx")
+                .await
+                .unwrap(),
+            "0da10cd4dc8c5fc44c06f5a82153a199f63f69eeba1c235f4459f002e2d41d55"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_store() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = temp_dir::TempDir::new().unwrap();
+        let dir = tmp_dir.path().join("system").join("chromium");
+        let file = dir.join("foo.crx");
+        store(&file, b"foo").await.unwrap();
+        store(&file, b"bar").await.unwrap();
+
+        assert_eq!(tokio::fs::read(&file).await.unwrap(), b"bar");
+        assert!(!file.with_extension("tmp").exists());
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&file), 0o644);
+        assert_eq!(mode(&dir), 0o755);
+        assert_eq!(mode(dir.parent().unwrap()), 0o755);
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::Result;
+use tracing::warn;
+
+use crate::lockfile::LockedExtension;
+use crate::manifest::Manifest;
+use crate::policy::Policy;
+
+#[derive(Debug, Clone, Default)]
+/// The checks a downloaded extension must pass before it is stored and installed.
+pub struct Checks {
+    /// The locked version of the extension. Installing fails if it doesn't match.
+    pub locked: Option<LockedExtension>,
+    /// Hold updates which add permissions instead of installing them.
+    pub hold_new_permissions: bool,
+    /// The held version which was approved despite adding permissions, if any.
+    pub approved_version: Option<String>,
+    /// The policy the extension must comply with.
+    pub policy: Policy,
+    /// The version of the (oldest) browser which loads the extension, if known.
+    pub browser_version: Option<String>,
+    /// The locale names and descriptions are resolved in (default: the locale of the
+    /// environment).
+    pub locale: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An update which was not installed because it adds permissions.
+pub struct HeldUpdate {
+    pub name: String,
+    pub from: String,
+    pub to: String,
+    /// The added permissions.
+    pub permissions: Vec<String>,
+}
+
+impl std::fmt::Display for HeldUpdate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: update {} -> {} adds permissions {} and is held for approval",
+            self.name,
+            self.from,
+            self.to,
+            self.permissions.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for HeldUpdate {}
+
+impl Checks {
+    /// check_manifest checks the manifest of a downloaded extension before it is stored.
+    ///
+    /// * `old` - The manifest of the stored version, if any.
+    pub fn check_manifest(&self, name: &str, old: Option<&Manifest>, new: &Manifest) -> Result<()> {
+        self.policy.check(name, new)?;
+        self.review(name, old, new)
+    }
+
+    /// review compares the permissions of an update with the installed version. Added
+    /// permissions are reported and the update is held if configured.
+    ///
+    /// * `old` - The manifest of the stored version, if any.
+    fn review(&self, name: &str, old: Option<&Manifest>, new: &Manifest) -> Result<()> {
+        let Some(old) = old else {
+            return Ok(());
+        };
+        let permissions = new.added_permissions(old);
+        if permissions.is_empty() {
+            return Ok(());
+        }
+        let update = HeldUpdate {
+            name: name.to_string(),
+            from: old.version.clone(),
+            to: new.version.clone(),
+            permissions,
+        };
+        if self.hold_new_permissions && self.approved_version.as_ref() != Some(&new.version) {
+            return Err(update.into());
+        }
+        warn!(
+            "{name}: update {} -> {} adds permissions {}",
+            update.from,
+            update.to,
+            update.permissions.join(", ")
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(version: &str, permissions: &[&str]) -> Manifest {
+        Manifest {
+            name: "Foo".to_string(),
+            version: version.to_string(),
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_review() {
+        let old = manifest("1.0", &["storage"]);
+        let new = manifest("1.1", &["storage", "<all_urls>"]);
+        let mut checks = Checks::default();
+
+        // new installations and unchanged permissions are fine
+        checks.review("foo", None, &new).unwrap();
+        checks.review("foo", Some(&new), &new).unwrap();
+
+        // added permissions are only reported by default
+        checks.review("foo", Some(&old), &new).unwrap();
+
+        checks.hold_new_permissions = true;
+        let err = checks.review("foo", Some(&old), &new).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HeldUpdate>(),
+            Some(&HeldUpdate {
+                name: "foo".to_string(),
+                from: "1.0".to_string(),
+                to: "1.1".to_string(),
+                permissions: vec!["<all_urls>".to_string()],
+            })
+        );
+        assert_eq!(
+            err.to_string(),
+            "foo: update 1.0 -> 1.1 adds permissions <all_urls> and is held for approval"
+        );
+
+        checks.approved_version = Some("1.1".to_string());
+        checks.review("foo", Some(&old), &new).unwrap();
+    }
+
+    #[test]
+    fn test_check_manifest() {
+        let checks = Checks {
+            policy: Policy {
+                deny: vec![crate::policy::Rule {
+                    permissions: vec!["<all_urls>".to_string()],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        checks
+            .check_manifest("foo", None, &manifest("1.0", &["storage"]))
+            .unwrap();
+        assert_eq!(
+            checks
+                .check_manifest("foo", None, &manifest("1.1", &["<all_urls>"]))
+                .unwrap_err()
+                .to_string(),
+            "foo: denied by policy (requests <all_urls>)"
+        );
+    }
+}
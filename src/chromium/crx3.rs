@@ -1,8 +1,13 @@
 use anyhow::{anyhow, Result};
+use p256::pkcs8::DecodePublicKey;
+use sha2::{Digest, Sha256};
 use std::path::Path;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
+/// The prefix of the data which is signed by every key proof of a CRX₃ file.
+const SIGNATURE_CONTEXT: &[u8] = b"CRX3 SignedData\x00";
+
 #[derive(Debug)]
 #[allow(dead_code)]
 /// A CRX₃ file is a binary file of the following format:
@@ -20,32 +25,148 @@ pub struct CrxFile {
     pub version: u32,
     /// Length of the header section
     pub length: u32,
-    /// CrxFileHeader (unparsed)
-    pub header: Vec<u8>,
+    /// CrxFileHeader
+    pub header: CrxFileHeader,
     pub zip_archive: Vec<u8>,
 }
 
+#[derive(Debug, Default, PartialEq, Eq)]
+/// The header of a CRX₃ file.
+pub struct CrxFileHeader {
+    /// PKCS#1 v1.5 signatures with RSA public keys and SHA-256.
+    pub sha256_with_rsa: Vec<AsymmetricKeyProof>,
+    /// ECDSA signatures with P-256 public keys and SHA-256.
+    pub sha256_with_ecdsa: Vec<AsymmetricKeyProof>,
+    /// The binary encoding of a SignedData message.
+    pub signed_header_data: Vec<u8>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+/// A public key (DER-encoded SubjectPublicKeyInfo) and a signature made with it.
+pub struct AsymmetricKeyProof {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl CrxFile {
+    /// id returns the ID of the extension the CRX file claims to belong to. Use `verify` to
+    /// check the claim.
+    pub fn id(&self) -> Result<String> {
+        let crx_id = parse_signed_data(&self.header.signed_header_data)?;
+        Ok(encode_id(&crx_id))
+    }
+
+    /// verify checks all signatures of the CRX file and makes sure that it belongs to the
+    /// extension with the given ID, i.e. the ID in the signed header data is derived from
+    /// one of the signing keys and equals `extension_id`.
+    pub fn verify(&self, extension_id: &str) -> Result<()> {
+        if self.version != 3 {
+            return Err(anyhow!(
+                "{extension_id}: unsupported CRX version {}",
+                self.version
+            ));
+        }
+
+        let crx_id = self.id()?;
+        if crx_id != extension_id {
+            return Err(anyhow!(
+                "{extension_id}: CRX file belongs to extension {crx_id}"
+            ));
+        }
+
+        let mut message =
+            Vec::with_capacity(SIGNATURE_CONTEXT.len() + 4 + self.header.signed_header_data.len());
+        message.extend_from_slice(SIGNATURE_CONTEXT);
+        message.extend_from_slice(&(self.header.signed_header_data.len() as u32).to_le_bytes());
+        message.extend_from_slice(&self.header.signed_header_data);
+        message.extend_from_slice(&self.zip_archive);
+
+        let mut id_verified = false;
+        for proof in &self.header.sha256_with_rsa {
+            verify_rsa(proof, &message)
+                .map_err(|err| anyhow!("{extension_id}: invalid RSA signature: {err}"))?;
+            id_verified |= derive_id(&proof.public_key) == crx_id;
+        }
+        for proof in &self.header.sha256_with_ecdsa {
+            verify_ecdsa(proof, &message)
+                .map_err(|err| anyhow!("{extension_id}: invalid ECDSA signature: {err}"))?;
+            id_verified |= derive_id(&proof.public_key) == crx_id;
+        }
+        if !id_verified {
+            return Err(anyhow!(
+                "{extension_id}: CRX file is not signed by the extension's key"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// derive_id derives the 32 character extension ID from a DER-encoded public key.
+fn derive_id(public_key: &[u8]) -> String {
+    let hash = Sha256::digest(public_key);
+    encode_id(&hash[..16])
+}
+
+/// encode_id encodes an extension ID using the characters a-p (one character per nibble).
+fn encode_id(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|b| [b >> 4, b & 0x0f])
+        .map(|nibble| (b'a' + nibble) as char)
+        .collect()
+}
+
+fn verify_rsa(proof: &AsymmetricKeyProof, message: &[u8]) -> Result<()> {
+    use rsa::signature::Verifier;
+
+    let public_key = rsa::RsaPublicKey::from_public_key_der(&proof.public_key)?;
+    let verifying_key = rsa::pkcs1v15::VerifyingKey::<Sha256>::new(public_key);
+    let signature = rsa::pkcs1v15::Signature::try_from(proof.signature.as_slice())?;
+    verifying_key.verify(message, &signature)?;
+    Ok(())
+}
+
+fn verify_ecdsa(proof: &AsymmetricKeyProof, message: &[u8]) -> Result<()> {
+    use p256::ecdsa::signature::Verifier;
+
+    let verifying_key = p256::ecdsa::VerifyingKey::from_public_key_der(&proof.public_key)?;
+    let signature = p256::ecdsa::Signature::from_der(&proof.signature)?;
+    verifying_key.verify(message, &signature)?;
+    Ok(())
+}
+
 #[allow(dead_code)]
 pub async fn parse_file<P: AsRef<Path>>(path: P) -> Result<CrxFile> {
     let mut file = File::open(path).await?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents).await?;
+    parse_bytes(contents)
+}
+
+pub fn parse_bytes(mut contents: Vec<u8>) -> Result<CrxFile> {
+    if contents.len() < 12 {
+        return Err(anyhow!("Invalid CRX file: too short"));
+    }
 
     let mut magic = [0u8; 4];
-    file.read_exact(&mut magic).await?;
+    magic.copy_from_slice(&contents[0..4]);
 
     // Check if the file signature matches "Cr24"
     if &magic != b"Cr24" {
         return Err(anyhow!("Invalid CRX file signature"));
     }
 
-    let version = read_u32(&mut file).await?;
-    let length = read_u32(&mut file).await?;
+    let version = read_u32(&contents[4..8]);
+    let length = read_u32(&contents[8..12]);
 
-    let mut header = vec![0u8; length as usize];
-    file.read_exact(&mut header).await?;
+    let header_end = 12usize
+        .checked_add(length as usize)
+        .filter(|end| *end <= contents.len())
+        .ok_or_else(|| anyhow!("Invalid CRX file: header exceeds file size"))?;
+    let header = parse_header(&contents[12..header_end])?;
 
-    // Read the rest of the file as ZIP archive
-    let mut zip_archive = Vec::new();
-    file.read_to_end(&mut zip_archive).await?;
+    // The rest of the file is the ZIP archive
+    let zip_archive = contents.split_off(header_end);
 
     Ok(CrxFile {
         magic,
@@ -57,10 +178,104 @@ pub async fn parse_file<P: AsRef<Path>>(path: P) -> Result<CrxFile> {
 }
 
 #[inline(always)]
-async fn read_u32(file: &mut File) -> Result<u32> {
-    let mut buf = [0u8; 4];
-    file.read_exact(&mut buf).await?;
-    Ok(u32::from_le_bytes(buf))
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+/// parse_header decodes a CrxFileHeader protobuf message.
+fn parse_header(buf: &[u8]) -> Result<CrxFileHeader> {
+    let mut header = CrxFileHeader::default();
+    for field in ProtoFields(buf) {
+        match field? {
+            (2, data) => header.sha256_with_rsa.push(parse_proof(data)?),
+            (3, data) => header.sha256_with_ecdsa.push(parse_proof(data)?),
+            (10000, data) => header.signed_header_data = data.to_vec(),
+            _ => {}
+        }
+    }
+    Ok(header)
+}
+
+/// parse_proof decodes an AsymmetricKeyProof protobuf message.
+fn parse_proof(buf: &[u8]) -> Result<AsymmetricKeyProof> {
+    let mut proof = AsymmetricKeyProof::default();
+    for field in ProtoFields(buf) {
+        match field? {
+            (1, data) => proof.public_key = data.to_vec(),
+            (2, data) => proof.signature = data.to_vec(),
+            _ => {}
+        }
+    }
+    Ok(proof)
+}
+
+/// parse_signed_data decodes a SignedData protobuf message and returns the `crx_id`.
+fn parse_signed_data(buf: &[u8]) -> Result<Vec<u8>> {
+    for field in ProtoFields(buf) {
+        if let (1, data) = field? {
+            return Ok(data.to_vec());
+        }
+    }
+    Err(anyhow!("CRX file header is missing the signed crx_id"))
+}
+
+/// An iterator over the length-delimited fields of a protobuf message.
+/// Varint and fixed-size fields are skipped since CRX headers don't use them.
+struct ProtoFields<'a>(&'a [u8]);
+
+impl<'a> Iterator for ProtoFields<'a> {
+    type Item = Result<(u64, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.0.is_empty() {
+                return None;
+            }
+            let result = (|| {
+                let key = read_varint(&mut self.0)?;
+                let (field, wire_type) = (key >> 3, key & 0x7);
+                let len = match wire_type {
+                    0 => {
+                        read_varint(&mut self.0)?;
+                        return Ok(None);
+                    }
+                    1 => 8,
+                    2 => read_varint(&mut self.0)? as usize,
+                    5 => 4,
+                    _ => return Err(anyhow!("Unsupported protobuf wire type {wire_type}")),
+                };
+                if len > self.0.len() {
+                    return Err(anyhow!("Truncated protobuf message"));
+                }
+                let (data, rest) = self.0.split_at(len);
+                self.0 = rest;
+                Ok((wire_type == 2).then_some((field, data)))
+            })();
+            match result {
+                Ok(Some(field)) => return Some(Ok(field)),
+                Ok(None) => continue,
+                Err(err) => {
+                    self.0 = &[];
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf
+            .split_first()
+            .ok_or_else(|| anyhow!("Truncated protobuf varint"))?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("Invalid protobuf varint"))
 }
 
 #[cfg(test)]
@@ -69,14 +284,17 @@ mod tests {
     use temp_dir::TempDir;
     use tokio::io::AsyncWriteExt;
 
+    const FIXTURE: &str = "tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx";
+
     #[tokio::test]
     async fn test_parse_file() {
-        let path = "tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx";
-        let crx_file = parse_file(path).await.unwrap();
+        let crx_file = parse_file(FIXTURE).await.unwrap();
         assert_eq!(crx_file.magic, *b"Cr24");
         assert_eq!(crx_file.version, 3);
         assert_eq!(crx_file.length, 1049);
         assert_eq!(crx_file.zip_archive.len(), 277495);
+        assert!(!crx_file.header.sha256_with_rsa.is_empty());
+        assert!(!crx_file.header.signed_header_data.is_empty());
     }
 
     #[tokio::test]
@@ -89,4 +307,48 @@ mod tests {
         }
         assert!(parse_file(&path).await.is_err());
     }
+
+    #[test]
+    fn test_parse_bytes_truncated_header() {
+        let mut contents = b"Cr24".to_vec();
+        contents.extend_from_slice(&3u32.to_le_bytes());
+        contents.extend_from_slice(&1000u32.to_le_bytes());
+        contents.extend_from_slice(b"short");
+        assert!(parse_bytes(contents).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let crx_file = parse_file(FIXTURE).await.unwrap();
+        crx_file.verify("dbepggeogbaibhgnhhndojpepiihcmeb").unwrap();
+        assert_eq!(crx_file.id().unwrap(), "dbepggeogbaibhgnhhndojpepiihcmeb");
+    }
+
+    #[tokio::test]
+    async fn test_verify_wrong_id() {
+        let crx_file = parse_file(FIXTURE).await.unwrap();
+        let err = crx_file
+            .verify("cjpalhdlnbpafiamejdnhcphjbkeiagm")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cjpalhdlnbpafiamejdnhcphjbkeiagm: CRX file belongs to extension dbepggeogbaibhgnhhndojpepiihcmeb"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_tampered() {
+        let mut crx_file = parse_file(FIXTURE).await.unwrap();
+        let last = crx_file.zip_archive.len() - 1;
+        crx_file.zip_archive[last] ^= 0xff;
+        let err = crx_file
+            .verify("dbepggeogbaibhgnhhndojpepiihcmeb")
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid RSA signature"));
+    }
+
+    #[test]
+    fn test_encode_id() {
+        assert_eq!(encode_id(&[0x01, 0x23, 0xab, 0xff]), "abcdklpp");
+    }
 }
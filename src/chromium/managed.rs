@@ -0,0 +1,262 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Map, Value};
+use std::path::Path;
+use tokio::fs;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::config::{ExtensionName, InstallMode, Target};
+use crate::enterprise;
+
+/// The file written to the managed policy directory.
+pub const POLICY_FILE: &str = "extension-downloader.json";
+
+/// The managed policy directory of Chromium on Linux.
+const DEFAULT_POLICY_DIR: &str = "/etc/chromium/policies/managed";
+
+/// The update URL of the Chrome Web Store.
+const STORE_UPDATE_URL: &str = "https://clients2.google.com/service/update2/crx";
+
+/// Extensions are installed concurrently, so updates of a policy file are serialized.
+static LOCK: Mutex<()> = Mutex::const_new(());
+
+/// update_url returns the (self-hosted) update URL the browser fetches force-installed
+/// extensions from, which is the one of the Chrome Web Store unless configured otherwise.
+pub fn update_url(configured: Option<&str>) -> &str {
+    configured.unwrap_or(STORE_UPDATE_URL)
+}
+
+/// check_not_pinned fails if the extension is pinned but installed from the Chrome Web Store,
+/// which only offers the latest version.
+pub fn check_not_pinned(extension: &ExtensionName, update_url: &str) -> Result<()> {
+    match extension.version() {
+        Some(_) if update_url == STORE_UPDATE_URL => Err(anyhow!(
+            "{}: pinning a version requires a self-hosted update URL for policies",
+            extension.name()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// is_policy_only returns whether all targets are policy files, i.e. the browser downloads the
+/// extension itself and nothing needs to be downloaded or stored.
+pub fn is_policy_only(targets: &[Target]) -> bool {
+    !targets.is_empty()
+        && targets
+            .iter()
+            .all(|target| target.install_mode == InstallMode::Policies)
+}
+
+/// is_policy_file returns whether the path names a policy file rather than the managed policy
+/// directory.
+fn is_policy_file(target: &str) -> bool {
+    Path::new(target)
+        .extension()
+        .is_some_and(|extension| extension == "json")
+}
+
+/// policy_path returns the policy file the `profile` of a config entry refers to: either the
+/// file itself, the managed policy directory or `default` for Chromium's directory.
+pub fn policy_path(profile: &str) -> Result<String> {
+    if profile == crate::profiles::DEFAULT_PROFILE {
+        if !cfg!(target_os = "linux") {
+            return Err(anyhow!(
+                "install_mode 'policies' requires the managed policy directory as profile"
+            ));
+        }
+        return policy_path(DEFAULT_POLICY_DIR);
+    }
+    if is_policy_file(profile) {
+        return Ok(profile.to_string());
+    }
+    Ok(Path::new(profile)
+        .join(POLICY_FILE)
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// install force-installs the extension from `update_url` using the `ExtensionSettings`
+/// policy. All other policies are kept.
+pub async fn install(extension_id: &str, update_url: &str, policy_file: &Path) -> Result<()> {
+    let _guard = LOCK.lock().await;
+    let mut policies = enterprise::read(policy_file).await?;
+    let settings = json!({
+        "installation_mode": "force_installed",
+        "update_url": update_url,
+    });
+    let extensions = extension_settings(&mut policies, policy_file)?;
+    if extensions.get(extension_id) == Some(&settings) {
+        debug!("{extension_id} is already installed in {:?}", policy_file);
+        return Ok(());
+    }
+    info!("Installing {extension_id} in {:?}", policy_file);
+    extensions.insert(extension_id.to_string(), settings);
+    enterprise::write(policy_file, &policies).await?;
+    warn_conflicts(policy_file).await;
+    Ok(())
+}
+
+/// uninstall removes the settings of the extension stored in `crx_file`.
+pub async fn uninstall(crx_file: &Path, policy_file: &Path) -> Result<()> {
+    // only `<id>.crx` is installed, see `install_file`
+    let Some(extension_id) = crx_file.file_stem().and_then(|stem| stem.to_str()) else {
+        return Ok(());
+    };
+    remove(extension_id, policy_file).await
+}
+
+/// remove removes the settings of the extension from the policy file.
+pub async fn remove(extension_id: &str, policy_file: &Path) -> Result<()> {
+    let _guard = LOCK.lock().await;
+    if fs::metadata(policy_file).await.is_err() {
+        return Ok(());
+    }
+    let mut policies = enterprise::read(policy_file).await?;
+    if extension_settings(&mut policies, policy_file)?
+        .remove(extension_id)
+        .is_none()
+    {
+        return Ok(());
+    }
+    debug!("Removing {extension_id} from {:?}", policy_file);
+    enterprise::write(policy_file, &policies).await
+}
+
+/// is_installed returns whether the extension is force-installed from `update_url`.
+pub async fn is_installed(extension_id: &str, update_url: &str, policy_file: &Path) -> bool {
+    let Ok(mut policies) = enterprise::read(policy_file).await else {
+        return false;
+    };
+    extension_settings(&mut policies, policy_file).is_ok_and(|extensions| {
+        extensions.get(extension_id).is_some_and(|settings| {
+            settings["installation_mode"] == "force_installed"
+                && settings["update_url"] == update_url
+        })
+    })
+}
+
+/// extension_settings returns the `ExtensionSettings` policy, which is added if missing.
+fn extension_settings<'a>(
+    policies: &'a mut Value,
+    policy_file: &Path,
+) -> Result<&'a mut Map<String, Value>> {
+    policies
+        .as_object_mut()
+        .map(|policies| {
+            policies
+                .entry("ExtensionSettings")
+                .or_insert_with(|| json!({}))
+        })
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| anyhow!("Unexpected structure of {:?}", policy_file))
+}
+
+/// warn_conflicts warns about other files in the policy directory which set `ExtensionSettings`
+/// or `ExtensionInstallForcelist`, since the browser only applies one of them.
+async fn warn_conflicts(policy_file: &Path) {
+    let Some(dir) = policy_file.parent() else {
+        return;
+    };
+    let Ok(mut read_dir) = fs::read_dir(dir).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = read_dir.next_entry().await {
+        let path = entry.path();
+        if path == policy_file || !is_policy_file(&path.to_string_lossy()) {
+            continue;
+        }
+        let Ok(policies) = enterprise::read(&path).await else {
+            continue;
+        };
+        for policy in ["ExtensionSettings", "ExtensionInstallForcelist"] {
+            if policies.get(policy).is_some() {
+                warn!(
+                    "{:?} also sets {policy}, which conflicts with {:?}",
+                    path, policy_file
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn test_policy_path() {
+        assert_eq!(
+            policy_path("/etc/opt/chrome/policies/managed").unwrap(),
+            "/etc/opt/chrome/policies/managed/extension-downloader.json"
+        );
+        assert_eq!(
+            policy_path("/etc/chromium/policies/managed/extensions.json").unwrap(),
+            "/etc/chromium/policies/managed/extensions.json"
+        );
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            policy_path("default").unwrap(),
+            "/etc/chromium/policies/managed/extension-downloader.json"
+        );
+        assert!(!is_policy_file("/home/user/.config/chromium"));
+    }
+
+    #[tokio::test]
+    async fn test_install() {
+        let dir = TempDir::new().unwrap();
+        let policy_file = dir.path().join(POLICY_FILE);
+        let extension_id = "a".repeat(32);
+        let other_id = "b".repeat(32);
+
+        // policies not managed by us are kept
+        fs::write(
+            &policy_file,
+            r#"{"BrowserSignin": 0, "ExtensionSettings": {"*": {"installation_mode": "blocked"}}}"#,
+        )
+        .await
+        .unwrap();
+
+        assert!(!is_installed(&extension_id, STORE_UPDATE_URL, &policy_file).await);
+        install(&extension_id, STORE_UPDATE_URL, &policy_file)
+            .await
+            .unwrap();
+        install(&other_id, STORE_UPDATE_URL, &policy_file)
+            .await
+            .unwrap();
+        assert!(is_installed(&extension_id, STORE_UPDATE_URL, &policy_file).await);
+        assert!(!is_installed(&extension_id, "https://example.com/update", &policy_file).await);
+
+        // installing again doesn't change anything
+        let contents = fs::read(&policy_file).await.unwrap();
+        install(&extension_id, STORE_UPDATE_URL, &policy_file)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&policy_file).await.unwrap(), contents);
+
+        let policies = enterprise::read(&policy_file).await.unwrap();
+        assert_eq!(policies["BrowserSignin"], 0);
+        assert_eq!(
+            policies["ExtensionSettings"][&extension_id],
+            json!({
+                "installation_mode": "force_installed",
+                "update_url": STORE_UPDATE_URL,
+            })
+        );
+
+        let crx_file = dir
+            .path()
+            .join("chromium")
+            .join(format!("{extension_id}.crx"));
+        uninstall(&crx_file, &policy_file).await.unwrap();
+        assert!(!is_installed(&extension_id, STORE_UPDATE_URL, &policy_file).await);
+        let policies = enterprise::read(&policy_file).await.unwrap();
+        assert!(policies["ExtensionSettings"].get(&other_id).is_some());
+        assert!(policies["ExtensionSettings"].get("*").is_some());
+
+        // uninstalling from a missing file is a no-op
+        uninstall(&crx_file, &dir.path().join("missing.json"))
+            .await
+            .unwrap();
+    }
+}
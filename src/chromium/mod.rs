@@ -1,11 +1,21 @@
-mod crx3;
+pub mod crx3;
+#[cfg_attr(target_os = "windows", allow(dead_code))]
+pub mod managed;
+#[cfg_attr(target_os = "windows", allow(dead_code))]
+pub mod update;
 
 #[cfg(not(target_os = "windows"))]
 mod unix;
 #[cfg(not(target_os = "windows"))]
-pub use unix::install;
+pub use unix::{
+    check_updates, install, install_file, installed_extensions, is_installed, plan,
+    uninstall_extension,
+};
 
 #[cfg(target_os = "windows")]
 mod windows;
 #[cfg(target_os = "windows")]
-pub use windows::install;
+pub use windows::{
+    check_updates, install, install_file, installed_extensions, is_installed, plan,
+    uninstall_extension,
+};
@@ -1,15 +1,28 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use reqwest_middleware::ClientWithMiddleware;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio::fs::{create_dir_all, try_exists, File};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::time::sleep;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use super::crx3;
-use crate::manifest::{self, Manifest};
+use super::managed;
+use super::update::{self, UpdateInfo};
+use crate::artifact::{self, Artifact};
+use crate::checks::Checks;
+use crate::config::{ExtensionName, InstallMode, LinkMode, Target};
+use crate::link;
+use crate::manifest::{self, compare_versions, Manifest};
+use crate::plan::{Change, Plan};
+use crate::profiles;
+use crate::unpacked;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 struct ExternalExt {
@@ -19,16 +32,88 @@ struct ExternalExt {
 
 const DEFAULT_BASE_URL_GOOGLE: &str = "https://clients2.google.com";
 
+/// check_updates asks the update service for the latest versions of the given extensions
+/// using as few requests as possible. Extensions missing in the result (e.g. because a request
+/// failed) are checked individually by `install` and `plan`.
+///
+/// * `browser_version` - The Chromium version the extensions must support, if known.
+pub async fn check_updates(
+    client: &ClientWithMiddleware,
+    base_url: Option<String>,
+    extension_ids: Vec<String>,
+    browser_version: Option<&str>,
+) -> HashMap<String, UpdateInfo> {
+    let base_url: String = base_url.unwrap_or(DEFAULT_BASE_URL_GOOGLE.to_string());
+    let mut result = HashMap::with_capacity(extension_ids.len());
+    for chunk in extension_ids.chunks(update::MAX_IDS_PER_REQUEST) {
+        match update::check_batch(client, &base_url, chunk, browser_version).await {
+            Ok(infos) => {
+                result.extend(infos.into_iter().map(|info| (info.app_id.clone(), info)));
+            }
+            Err(err) => warn!(
+                "Failed to check {} extensions for updates: {err}",
+                chunk.len()
+            ),
+        }
+    }
+    result
+}
+
+/// install downloads the extension (if needed) and installs it into all given profiles.
+///
+/// * `update_url` - The (self-hosted) update URL of extensions installed using policies, if
+///   configured.
+/// * `extension` - The ID of the extension and its pinned version, if any.
+/// * `update` - The result of a previous (batched) update check, if any.
+/// * `checks` - The checks the downloaded extension must pass.
+#[allow(clippy::too_many_arguments)]
 pub async fn install(
     client: ClientWithMiddleware,
     base_url: Option<String>,
-    extension_id: String,
+    update_url: Option<String>,
+    extension: ExtensionName,
+    update: Option<UpdateInfo>,
+    checks: Checks,
     dest_dir: PathBuf,
-    profiles: Vec<String>,
-) -> Result<Option<PathBuf>> {
-    let (ext, manifest) = download_extension(client, base_url, extension_id, &dest_dir).await?;
-    for p in profiles {
-        let check_result = check_installed(&ext, &p).await?;
+    profiles: Vec<Target>,
+) -> Result<Option<Artifact>> {
+    // the browser downloads extensions installed using policies itself
+    if managed::is_policy_only(&profiles) {
+        let update_url = managed::update_url(update_url.as_deref());
+        managed::check_not_pinned(&extension, update_url)?;
+        for target in profiles {
+            managed::install(extension.name(), update_url, Path::new(&target.profile)).await?;
+        }
+        return Ok(None);
+    }
+    let (artifact, manifest) = download_extension(
+        client,
+        base_url,
+        extension.name().to_string(),
+        extension.version(),
+        update,
+        &checks,
+        &dest_dir,
+    )
+    .await?;
+    let ext = ExternalExt {
+        external_crx: artifact.path.clone(),
+        external_version: artifact.version.clone(),
+    };
+    for target in profiles {
+        let p = target.profile.as_str();
+        if target.install_mode == InstallMode::Policies {
+            let update_url = managed::update_url(update_url.as_deref());
+            managed::check_not_pinned(&extension, update_url)?;
+            managed::install(&artifact.id, update_url, Path::new(p)).await?;
+            continue;
+        }
+        if target.install_mode == InstallMode::Unpacked {
+            unpacked::install(&artifact.path, &artifact.id, Path::new(p)).await?;
+            continue;
+        }
+        warn_incompatible(&manifest, p).await;
+        let check_result = check_installed(&ext, &artifact.id, &target).await?;
         match (check_result.installed, check_result.latest) {
             (true, true) => {
                 debug!(
@@ -44,38 +129,260 @@ pub async fn install(
                     manifest.version,
                     p
                 );
-                install_extension(&ext, &p).await?;
+                install_extension(&ext, &artifact.id, &target).await?;
             }
             (false, _) => {
                 info!(
                     "installing {} {} into {}",
                     manifest.name, manifest.version, p
                 );
-                install_extension(&ext, &p).await?;
+                install_extension(&ext, &artifact.id, &target).await?;
+            }
+        }
+    }
+    Ok(Some(artifact))
+}
+
+/// plan determines the changes `install` would perform without downloading or installing anything.
+///
+/// * `update_url` - The (self-hosted) update URL of extensions installed using policies, if
+///   configured.
+/// * `browser_version` - The Chromium version the extension must support, if known.
+#[allow(clippy::too_many_arguments)]
+pub async fn plan(
+    client: ClientWithMiddleware,
+    base_url: Option<String>,
+    update_url: Option<String>,
+    extension: ExtensionName,
+    update: Option<UpdateInfo>,
+    browser_version: Option<String>,
+    dest_dir: PathBuf,
+    profiles: Vec<Target>,
+) -> Result<Plan> {
+    let extension_id = extension.name().to_string();
+    // the browser downloads extensions installed using policies itself
+    if managed::is_policy_only(&profiles) {
+        let mut changes = Vec::new();
+        for target in profiles {
+            changes.extend(plan_policies(&extension, update_url.as_deref(), &target).await?);
+        }
+        return Ok(Plan {
+            file: None,
+            changes,
+        });
+    }
+    let destination = dest_dir.join(format!("{extension_id}.crx"));
+    let cached_version = cached_manifest(&destination, None)
+        .await?
+        .map(|mf| mf.version);
+
+    let new_version = match (extension.version(), cached_version.as_deref()) {
+        (Some(pinned), Some(cached)) if pinned == cached => pinned.to_string(),
+        (pinned, _) => {
+            let info = check_update(
+                &client,
+                base_url,
+                &extension_id,
+                update,
+                browser_version.as_deref(),
+            )
+            .await?;
+            let latest = info.version.ok_or_else(|| {
+                anyhow!(
+                    "{extension_id}: update service returned status '{}'",
+                    info.status
+                )
+            })?;
+            if let Some(pinned) = pinned {
+                check_pinned(&extension_id, pinned, &latest)?;
+            }
+            latest
+        }
+    };
+
+    let mut changes = Vec::new();
+    match cached_version {
+        Some(old_version) => {
+            let outdated = match extension.version() {
+                Some(_) => old_version != new_version,
+                None => compare_versions(&new_version, &old_version) == Ordering::Greater,
+            };
+            if outdated {
+                changes.push(Change::Update {
+                    name: extension_id.clone(),
+                    from: old_version,
+                    to: new_version.clone(),
+                    path: destination.clone(),
+                });
+            }
+        }
+        None => changes.push(Change::Download {
+            name: extension_id.clone(),
+            version: new_version.clone(),
+            path: destination.clone(),
+        }),
+    }
+
+    let ext = ExternalExt {
+        external_crx: destination.clone(),
+        external_version: new_version.clone(),
+    };
+    for target in profiles {
+        let p = target.profile.as_str();
+        if target.install_mode == InstallMode::Policies {
+            changes.extend(plan_policies(&extension, update_url.as_deref(), &target).await?);
+            continue;
+        }
+        if target.install_mode == InstallMode::Unpacked {
+            let dir = Path::new(p);
+            if !unpacked::is_installed(&destination, &extension_id, &new_version, dir).await {
+                changes.push(Change::Install {
+                    location: unpacked::current_dir(&extension_id, dir),
+                    source: destination.clone(),
+                    version: new_version.clone(),
+                });
             }
+            continue;
         }
+        let check_result = check_installed(&ext, &extension_id, &target).await?;
+        match (check_result.installed, check_result.latest) {
+            (true, true) => {}
+            (true, false) => changes.push(Change::Upgrade {
+                location: create_json_path(&extension_id, &target),
+                from: check_result.ext.unwrap().external_version,
+                to: new_version.clone(),
+            }),
+            (false, _) => changes.push(Change::Install {
+                location: create_json_path(&extension_id, &target),
+                source: destination.clone(),
+                version: new_version.clone(),
+            }),
+        }
+    }
+
+    Ok(Plan {
+        file: Some(destination),
+        changes,
+    })
+}
+
+/// plan_policies returns the change installing the extension using the policy file `target`
+/// would perform, if any.
+async fn plan_policies(
+    extension: &ExtensionName,
+    update_url: Option<&str>,
+    target: &Target,
+) -> Result<Option<Change>> {
+    let update_url = managed::update_url(update_url);
+    managed::check_not_pinned(extension, update_url)?;
+    if managed::is_installed(extension.name(), update_url, Path::new(&target.profile)).await {
+        return Ok(None);
     }
-    Ok(Some(ext.external_crx))
+    Ok(Some(Change::Install {
+        location: PathBuf::from(&target.profile),
+        source: PathBuf::from(update_url),
+        version: "latest".to_string(),
+    }))
 }
 
 /// download_extension downloads a chromium extension from the Chrome Web Store.
 ///
+/// The update service is asked for the latest version first. The CRX file is only downloaded
+/// if it is newer than the cached one in `dest_dir`.
+///
+/// The update service only offers the latest version, so a pinned version is taken from the
+/// cache. It is only downloaded if it happens to be the latest version.
+///
 /// * `client` - A reqwest client with middleware.
 /// * `base_url` - Use this to override the default base URL.
 /// * `extension_id` - The ID of the extension to download.
+/// * `version` - The pinned version, if any.
+/// * `update` - The result of a previous update check, if any.
+/// * `checks` - The checks the downloaded extension must pass.
 /// * `dest_dir` - The directory to save the extension to.
 async fn download_extension(
     client: ClientWithMiddleware,
     base_url: Option<String>,
     extension_id: String,
+    version: Option<&str>,
+    update: Option<UpdateInfo>,
+    checks: &Checks,
     dest_dir: &Path,
-) -> Result<(ExternalExt, Manifest)> {
+) -> Result<(Artifact, Manifest)> {
     debug!("Downloading Chromium extension {extension_id}");
 
-    let base_url: String = base_url.unwrap_or(DEFAULT_BASE_URL_GOOGLE.to_string());
-    let url = format!(
-        "{base_url}/service/update2/crx?response=redirect&prodversion=119.0.6045.199&acceptformat=crx2,crx3&x=id%3D{extension_id}%26installsource%3Dondemand%26uc"
-    );
+    let destination = dest_dir.join(format!("{extension_id}.crx"));
+    let cached = cached_manifest(&destination, checks.locale.as_deref()).await?;
+
+    // a locked extension is installed exactly as locked, like a pinned one
+    let locked = checks
+        .locked
+        .as_ref()
+        .filter(|locked| !locked.url.is_empty());
+    if let (Some(pinned), Some(manifest)) = (
+        locked.map(|locked| locked.version.as_str()).or(version),
+        &cached,
+    ) {
+        if manifest.version == pinned {
+            debug!("{extension_id} is pinned to {pinned}");
+            let artifact =
+                cached_artifact(destination, extension_id, manifest, String::new(), checks).await?;
+            return Ok((artifact, cached.unwrap()));
+        }
+    }
+
+    let (new_version, url, hash_sha256) = match locked {
+        // the SHA-256 is checked against the lockfile below
+        Some(locked) => (locked.version.clone(), locked.url.clone(), None),
+        None => {
+            let info = check_update(
+                &client,
+                base_url,
+                &extension_id,
+                update,
+                checks.browser_version.as_deref(),
+            )
+            .await?;
+            match (info.version, info.codebase) {
+                (Some(version), Some(codebase)) => (version, codebase, info.hash_sha256),
+                _ => {
+                    return Err(anyhow!(
+                        "{extension_id}: update service returned status '{}'",
+                        info.status
+                    ))
+                }
+            }
+        }
+    };
+    if let Some(pinned) = version.filter(|_| locked.is_none()) {
+        check_pinned(&extension_id, pinned, &new_version)?;
+    }
+    if let Some(locked) = &checks.locked {
+        locked.check_version(&new_version)?;
+    }
+
+    if let Some(manifest) = &cached {
+        // a pinned or locked version is never replaced by a newer one, see above
+        if version.is_none()
+            && locked.is_none()
+            && compare_versions(&new_version, &manifest.version) != Ordering::Greater
+            && check_compatible(&extension_id, manifest, checks).is_ok()
+        {
+            debug!(
+                "{extension_id} {} already up-to-date ({})",
+                manifest.version,
+                dest_dir.to_string_lossy()
+            );
+            let artifact =
+                cached_artifact(destination, extension_id, manifest, url, checks).await?;
+            return Ok((artifact, cached.unwrap()));
+        }
+        info!(
+            "{extension_id}: updating {} -> {new_version}",
+            manifest.version
+        );
+    }
+
     debug!("Downloading {url}");
 
     let mut retries = 3;
@@ -89,7 +396,7 @@ async fn download_extension(
 
         retries -= 1;
         if retries == 0 {
-            return Err(anyhow::anyhow!(
+            return Err(anyhow!(
                 "{extension_id}: failed to fetch non-empty body after 3 retries"
             ));
         }
@@ -98,25 +405,122 @@ async fn download_extension(
         sleep(Duration::from_secs(1)).await; // Optional delay between retries
     };
 
-    create_dir_all(&dest_dir).await?;
+    let hash_computed = format!("{:x}", Sha256::digest(&body));
+    if let Some(hash_expected) = hash_sha256 {
+        if hash_computed != hash_expected {
+            return Err(anyhow!(
+                "Hash mismatch! Expected {hash_expected}, found {hash_computed}"
+            ));
+        }
+        debug!("Hash verified successfully");
+    }
+
+    // reject tampered files and files of other extensions before storing them
+    let crx_file = crx3::parse_bytes(body.to_vec())?;
+    crx_file.verify(&extension_id)?;
+    debug!("Signature verified successfully");
+    let manifest = manifest::from_bytes(&crx_file.zip_archive, checks.locale.as_deref())?;
+    if let Err(err) = check_compatible(&extension_id, &manifest, checks) {
+        // keep the stored version if the browser can load it
+        return match cached {
+            Some(cached) if check_compatible(&extension_id, &cached, checks).is_ok() => {
+                warn!("{err}, keeping {}", cached.version);
+                let artifact =
+                    cached_artifact(destination, extension_id, &cached, String::new(), checks)
+                        .await?;
+                Ok((artifact, cached))
+            }
+            _ => Err(err),
+        };
+    }
+    checks.check_manifest(&extension_id, cached.as_ref(), &manifest)?;
+    let artifact = Artifact {
+        path: destination.clone(),
+        id: extension_id.clone(),
+        version: manifest.version.clone(),
+        url,
+        sha256: hash_computed,
+    };
+    if let Some(locked) = &checks.locked {
+        locked.check_artifact(&artifact)?;
+    }
+
+    artifact::store(&destination, &body).await?;
 
-    let destination = dest_dir.join(format!("{extension_id}.crx"));
-    let mut file = File::create(&destination).await?;
-    file.write_all(&body).await?;
-    file.flush().await?; // ensure file is fully persisted, otherwise install_extension can fail
-                         // due to 'early eof'
-    std::mem::drop(file); // close file
-
-    let crx_file = crx3::parse_file(&destination).await?;
-    let manifest = manifest::from_bytes(&crx_file.zip_archive)?;
     debug!("Downloaded {extension_id} with manifest: {manifest}");
-    Ok((
-        ExternalExt {
-            external_crx: destination,
-            external_version: manifest.version.clone(),
-        },
-        manifest,
-    ))
+    Ok((artifact, manifest))
+}
+
+/// cached_manifest returns the manifest of the cached CRX file, if any.
+async fn cached_manifest(path: &Path, locale: Option<&str>) -> Result<Option<Manifest>> {
+    if let Ok(true) = try_exists(path).await {
+        let crx_file = crx3::parse_file(path).await?;
+        return Ok(Some(manifest::from_bytes(&crx_file.zip_archive, locale)?));
+    }
+    Ok(None)
+}
+
+/// cached_artifact returns the stored version of the extension after checking it like a download.
+async fn cached_artifact(
+    destination: PathBuf,
+    extension_id: String,
+    manifest: &Manifest,
+    url: String,
+    checks: &Checks,
+) -> Result<Artifact> {
+    let artifact = Artifact {
+        sha256: artifact::sha256(&destination).await?,
+        path: destination,
+        id: extension_id,
+        version: manifest.version.clone(),
+        url,
+    };
+    if let Some(locked) = &checks.locked {
+        locked.check_artifact(&artifact)?;
+    }
+    checks.policy.check(&artifact.id, manifest)?;
+    check_compatible(&artifact.id, manifest, checks)?;
+    Ok(artifact)
+}
+
+/// check_compatible fails if the Chromium version which loads the extension can't load it.
+fn check_compatible(extension_id: &str, manifest: &Manifest, checks: &Checks) -> Result<()> {
+    let Some(browser_version) = &checks.browser_version else {
+        return Ok(());
+    };
+    match manifest.chromium_incompatibility(browser_version) {
+        Some(reason) => Err(anyhow!(
+            "{extension_id}: {manifest} {reason}, but Chromium {browser_version} loads it"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// check_pinned fails if a pinned version can't be downloaded because it's not the latest one.
+fn check_pinned(extension_id: &str, pinned: &str, latest: &str) -> Result<()> {
+    if pinned != latest {
+        return Err(anyhow!(
+            "{extension_id}: pinned version {pinned} is not cached and the update service only offers {latest}"
+        ));
+    }
+    Ok(())
+}
+
+/// check_update returns the given update check result or queries the update service.
+async fn check_update(
+    client: &ClientWithMiddleware,
+    base_url: Option<String>,
+    extension_id: &str,
+    update: Option<UpdateInfo>,
+    browser_version: Option<&str>,
+) -> Result<UpdateInfo> {
+    match update {
+        Some(info) => Ok(info),
+        None => {
+            let base_url: String = base_url.unwrap_or(DEFAULT_BASE_URL_GOOGLE.to_string());
+            update::check(client, &base_url, extension_id, browser_version).await
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -126,29 +530,46 @@ struct CheckResult {
     ext: Option<ExternalExt>,
 }
 
-/// is_up_to_date checks if the extension is already installed and up-to-date.
-async fn check_installed(ext: &ExternalExt, profile_dir: &str) -> Result<CheckResult> {
-    let json_path = create_json_path(ext, profile_dir);
+/// check_installed checks if the extension is already installed and up-to-date. Hardlinks and
+/// copies must also match the stored file.
+async fn check_installed(
+    ext: &ExternalExt,
+    extension_id: &str,
+    target: &Target,
+) -> Result<CheckResult> {
+    let json_path = create_json_path(extension_id, target);
     if let Ok(true) = try_exists(&json_path).await {
         let installed = true;
         // parse json file and check if version matches
         let mut json_file = File::open(&json_path).await?;
         let mut contents = Vec::new();
         json_file.read_to_end(&mut contents).await?;
-        let installed_ext: ExternalExt = serde_json::from_slice(&contents).unwrap();
-        if installed_ext.external_version == ext.external_version {
-            return Ok(CheckResult {
-                installed,
-                latest: true,
-                ext: Some(installed_ext),
-            });
-        } else {
-            return Ok(CheckResult {
-                installed,
-                latest: false,
-                ext: Some(installed_ext),
-            });
-        }
+        let installed_ext: ExternalExt = match serde_json::from_slice(&contents) {
+            Ok(installed_ext) => installed_ext,
+            Err(err) => {
+                // the entry is replaced when installing
+                warn!("Ignoring invalid {:?}: {}", json_path, err);
+                return Ok(CheckResult {
+                    installed: false,
+                    latest: false,
+                    ext: None,
+                });
+            }
+        };
+        let latest = installed_ext.external_version == ext.external_version
+            && match target.link_mode {
+                LinkMode::Symlink => true,
+                mode => {
+                    let placed = create_crx_path(extension_id, target);
+                    installed_ext.external_crx == placed
+                        && link::is_placed(&ext.external_crx, &placed, mode).await
+                }
+            };
+        return Ok(CheckResult {
+            installed,
+            latest,
+            ext: Some(installed_ext),
+        });
     }
     Ok(CheckResult {
         installed: false,
@@ -157,30 +578,197 @@ async fn check_installed(ext: &ExternalExt, profile_dir: &str) -> Result<CheckRe
     })
 }
 
-async fn install_extension(ext: &ExternalExt, profile_dir: &str) -> Result<()> {
+/// install_extension writes the `External Extensions` entry of the extension. Unless the stored
+/// file is referenced directly, it is hardlinked or copied next to the entry first.
+async fn install_extension(ext: &ExternalExt, extension_id: &str, target: &Target) -> Result<()> {
+    let profile_dir = target.profile.as_str();
     let path = ext.external_crx.to_str().unwrap();
     debug!("{profile_dir}: installing Chromium extension {path}");
 
-    let json_path = create_json_path(ext, profile_dir);
-    let profile_extensions = PathBuf::from(profile_dir).join("External Extensions");
+    let json_path = create_json_path(extension_id, target);
+    let profile_extensions = external_extensions_dir(target);
     create_dir_all(&profile_extensions).await?;
 
+    let placed;
+    let ext = match target.link_mode {
+        LinkMode::Symlink => ext,
+        mode => {
+            let crx_path = create_crx_path(extension_id, target);
+            link::place(&ext.external_crx, &crx_path, mode).await?;
+            placed = ExternalExt {
+                external_crx: crx_path,
+                external_version: ext.external_version.clone(),
+            };
+            &placed
+        }
+    };
+
     let mut json_file = File::create(&json_path).await?;
-    let contents = serde_json::to_vec_pretty(&ext).unwrap();
+    let contents = serde_json::to_vec_pretty(ext).unwrap();
     json_file.write_all(&contents).await?;
+    if target.install_mode == InstallMode::System {
+        // the browser runs as another user
+        let permissions = std::fs::Permissions::from_mode(0o644);
+        tokio::fs::set_permissions(&json_path, permissions).await?;
+    }
     Ok(())
 }
 
-fn create_json_path(ext: &ExternalExt, profile_dir: &str) -> PathBuf {
-    let profile_extensions = PathBuf::from(profile_dir).join("External Extensions");
-    let mut json_path = profile_extensions.join(ext.external_crx.file_name().unwrap());
-    json_path.set_extension("json");
-    json_path
+/// install_file installs a stored CRX file into the given profile, replacing the installed
+/// version of the extension (if any).
+pub async fn install_file(
+    crx_file: &Path,
+    extension_id: &str,
+    version: &str,
+    target: &Target,
+) -> Result<()> {
+    let profile_dir = target.profile.as_str();
+    if target.install_mode == InstallMode::Policies {
+        return Err(anyhow!(
+            "{extension_id}: installing a stored version is not supported for policies, the browser downloads extensions itself"
+        ));
+    }
+    if target.install_mode == InstallMode::Unpacked {
+        return unpacked::install(crx_file, extension_id, Path::new(profile_dir)).await;
+    }
+    let ext = ExternalExt {
+        external_crx: crx_file.to_path_buf(),
+        external_version: version.to_string(),
+    };
+    install_extension(&ext, extension_id, target).await
+}
+
+/// is_installed returns whether the given version of a stored CRX file is installed in the
+/// profile.
+pub async fn is_installed(
+    crx_file: &Path,
+    extension_id: &str,
+    version: &str,
+    target: &Target,
+) -> bool {
+    let profile_dir = target.profile.as_str();
+    if target.install_mode == InstallMode::Policies {
+        // stored versions are never installed using policies, see `install_file`
+        return false;
+    }
+    if target.install_mode == InstallMode::Unpacked {
+        return unpacked::is_installed(crx_file, extension_id, version, Path::new(profile_dir))
+            .await;
+    }
+    let ext = ExternalExt {
+        external_crx: crx_file.to_path_buf(),
+        external_version: version.to_string(),
+    };
+    check_installed(&ext, extension_id, target)
+        .await
+        .is_ok_and(|result| result.latest)
+}
+
+/// uninstall_extension removes the `External Extensions` entries referencing `crx_file` or one of
+/// its hardlinks or copies from the given profile.
+pub async fn uninstall_extension(crx_file: &Path, target: &Target) -> Result<()> {
+    let profile_dir = target.profile.as_str();
+    if target.install_mode == InstallMode::Policies {
+        return managed::uninstall(crx_file, Path::new(profile_dir)).await;
+    }
+    if target.install_mode == InstallMode::Unpacked {
+        return unpacked::uninstall(crx_file, Path::new(profile_dir)).await;
+    }
+    let profile_extensions = external_extensions_dir(target);
+    let mut read_dir = match tokio::fs::read_dir(&profile_extensions).await {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = read_dir.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let contents = tokio::fs::read(&path).await?;
+        let Ok(ext) = serde_json::from_slice::<ExternalExt>(&contents) else {
+            continue;
+        };
+        if ext.external_crx == crx_file {
+            debug!("Removing {:?}", path);
+            tokio::fs::remove_file(&path).await?;
+        } else if ext.external_crx.parent() == Some(profile_extensions.as_path())
+            && link::refers_to(&ext.external_crx, crx_file).await
+        {
+            debug!("Removing {:?} and {:?}", path, ext.external_crx);
+            tokio::fs::remove_file(&path).await?;
+            tokio::fs::remove_file(&ext.external_crx).await?;
+        }
+    }
+    Ok(())
+}
+
+/// installed_extensions returns the CRX files referenced by the `External Extensions` of a profile.
+/// Hardlinks and copies are returned as placed in the profile.
+///
+/// Policy files reference no files since the browser downloads the extensions itself.
+pub async fn installed_extensions(target: &Target) -> Result<Vec<PathBuf>> {
+    let profile_dir = target.profile.as_str();
+    if target.install_mode == InstallMode::Policies {
+        return Ok(Vec::new());
+    }
+    if target.install_mode == InstallMode::Unpacked {
+        return unpacked::installed_extensions(Path::new(profile_dir)).await;
+    }
+    let profile_extensions = external_extensions_dir(target);
+    let mut result = Vec::new();
+    let mut read_dir = match tokio::fs::read_dir(&profile_extensions).await {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(result),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = read_dir.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let contents = tokio::fs::read(&path).await?;
+        match serde_json::from_slice::<ExternalExt>(&contents) {
+            Ok(ext) => result.push(ext.external_crx),
+            Err(err) => debug!("Ignoring {:?}: {}", path, err),
+        }
+    }
+    Ok(result)
+}
+
+/// warn_incompatible warns if the Chromium version which last used the user data directory
+/// can't load the extension.
+async fn warn_incompatible(manifest: &Manifest, profile_dir: &str) {
+    let Some(version) = profiles::chromium_version(Path::new(profile_dir)).await else {
+        return;
+    };
+    if let Some(reason) = manifest.chromium_incompatibility(&version) {
+        warn!("{manifest} {reason}, but {profile_dir} was last used by Chromium {version}");
+    }
+}
+
+fn create_json_path(extension_id: &str, target: &Target) -> PathBuf {
+    external_extensions_dir(target).join(format!("{extension_id}.json"))
+}
+
+/// create_crx_path returns where the CRX file is hardlinked or copied to.
+fn create_crx_path(extension_id: &str, target: &Target) -> PathBuf {
+    external_extensions_dir(target).join(format!("{extension_id}.crx"))
+}
+
+/// external_extensions_dir returns the directory of the external extension files of a target:
+/// the system extension directory itself or the `External Extensions` of a user data directory.
+fn external_extensions_dir(target: &Target) -> PathBuf {
+    if target.install_mode == InstallMode::System {
+        return PathBuf::from(&target.profile);
+    }
+    PathBuf::from(&target.profile).join("External Extensions")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lockfile::LockedExtension;
 
     use reqwest_middleware::ClientBuilder;
     use temp_dir::TempDir;
@@ -195,11 +783,10 @@ mod tests {
 
         let extension_id = "dbepggeogbaibhgnhhndojpepiihcmeb";
 
-        let url = format!("/service/update2/crx?response=redirect&prodversion=119.0.6045.199&acceptformat=crx2,crx3&x=id%3D{extension_id}%26installsource%3Dondemand%26uc");
-        let url_str: &str = &url;
-        let m1 = server
-            .mock("GET", url_str)
-            .with_header("content-type", "application/json")
+        let m1 = mock_updatecheck(&mut server, extension_id, "2.1.2", None).await;
+        let m2 = server
+            .mock("GET", "/vimium.crx")
+            .with_header("content-type", "application/x-chrome-extension")
             .with_body_from_file("tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx")
             .with_status(200)
             .create_async()
@@ -266,11 +853,14 @@ mod tests {
         install(
             ClientBuilder::new(reqwest::Client::new()).build(),
             Some(server.url()),
-            extension_id.to_string(),
+            None,
+            extension_id.into(),
+            None,
+            Checks::default(),
             dest_dir,
             all_profiles
                 .iter()
-                .map(|p| p.to_string_lossy().to_string())
+                .map(|p| Target::from(p.to_str().unwrap()))
                 .collect(),
         )
         .await
@@ -294,5 +884,466 @@ mod tests {
             assert!(content.contains("2.1.2"));
         }
         m1.assert_async().await;
+        m2.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_download_extension_up_to_date() {
+        let mut server = mockito::Server::new_async().await;
+        let extension_id = "dbepggeogbaibhgnhhndojpepiihcmeb";
+        let m1 = mock_updatecheck(&mut server, extension_id, "2.1.2", None).await;
+        let m2 = server
+            .mock("GET", "/vimium.crx")
+            .expect(0)
+            .create_async()
+            .await;
+
+        let dest_dir = TempDir::new().unwrap();
+        fs::copy(
+            "tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx",
+            dest_dir.path().join(format!("{extension_id}.crx")),
+        )
+        .await
+        .unwrap();
+
+        let (artifact, manifest) = download_extension(
+            ClientBuilder::new(reqwest::Client::new()).build(),
+            Some(server.url()),
+            extension_id.to_string(),
+            None,
+            None,
+            &Checks::default(),
+            dest_dir.path(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(artifact.version, "2.1.2");
+        assert_eq!(
+            artifact.sha256,
+            "0da10cd4dc8c5fc44c06f5a82153a199f63f69eeba1c235f4459f002e2d41d55"
+        );
+        assert_eq!(manifest.version, "2.1.2");
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_download_extension_hash_mismatch() {
+        let mut server = mockito::Server::new_async().await;
+        let extension_id = "dbepggeogbaibhgnhhndojpepiihcmeb";
+        let m1 = mock_updatecheck(&mut server, extension_id, "2.1.2", Some("aaaa")).await;
+        let m2 = server
+            .mock("GET", "/vimium.crx")
+            .with_body_from_file("tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let dest_dir = TempDir::new().unwrap();
+        let result = download_extension(
+            ClientBuilder::new(reqwest::Client::new()).build(),
+            Some(server.url()),
+            extension_id.to_string(),
+            None,
+            None,
+            &Checks::default(),
+            dest_dir.path(),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Hash mismatch! Expected aaaa, found 0da10cd4dc8c5fc44c06f5a82153a199f63f69eeba1c235f4459f002e2d41d55"
+        );
+        assert!(!dest_dir.path().join(format!("{extension_id}.crx")).exists());
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_check_updates_batched() {
+        let mut server = mockito::Server::new_async().await;
+        let ids = vec!["a".repeat(32), "b".repeat(32)];
+        let url = format!("/service/update2/crx?response=updatecheck&prodversion=119.0.6045.199&acceptformat=crx2,crx3&x=id%3D{}%26installsource%3Dondemand%26uc&x=id%3D{}%26installsource%3Dondemand%26uc", ids[0], ids[1]);
+        let m1 = server
+            .mock("GET", url.as_str())
+            .with_header("content-type", "text/xml")
+            .with_body(format!(
+                r#"<gupdate protocol="2.0"><app appid="{}" status="ok"><updatecheck codebase="https://example.com/a.crx" status="ok" version="1.0"/></app><app appid="{}" status="ok"><updatecheck codebase="https://example.com/b.crx" status="ok" version="2.0"/></app></gupdate>"#,
+                ids[0], ids[1]
+            ))
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let client = ClientBuilder::new(reqwest::Client::new()).build();
+        let updates = check_updates(&client, Some(server.url()), ids.clone(), None).await;
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[&ids[0]].version.as_deref(), Some("1.0"));
+        assert_eq!(updates[&ids[1]].version.as_deref(), Some("2.0"));
+
+        m1.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_check_updates_failure() {
+        let mut server = mockito::Server::new_async().await;
+        let m1 = server
+            .mock("GET", mockito::Matcher::Any)
+            .with_status(500)
+            .create_async()
+            .await;
+
+        let client = ClientBuilder::new(reqwest::Client::new()).build();
+        let updates = check_updates(&client, Some(server.url()), vec!["a".repeat(32)], None).await;
+        assert!(updates.is_empty());
+
+        m1.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_download_extension_wrong_id() {
+        let mut server = mockito::Server::new_async().await;
+        // a mirror serving a different extension under the requested ID
+        let extension_id = "cjpalhdlnbpafiamejdnhcphjbkeiagm";
+        let m1 = mock_updatecheck(&mut server, extension_id, "2.1.2", None).await;
+        let m2 = server
+            .mock("GET", "/vimium.crx")
+            .with_body_from_file("tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let dest_dir = TempDir::new().unwrap();
+        let result = download_extension(
+            ClientBuilder::new(reqwest::Client::new()).build(),
+            Some(server.url()),
+            extension_id.to_string(),
+            None,
+            None,
+            &Checks::default(),
+            dest_dir.path(),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "cjpalhdlnbpafiamejdnhcphjbkeiagm: CRX file belongs to extension dbepggeogbaibhgnhhndojpepiihcmeb"
+        );
+        assert!(!dest_dir.path().join(format!("{extension_id}.crx")).exists());
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_download_extension_locked() {
+        let mut server = mockito::Server::new_async().await;
+        let extension_id = "dbepggeogbaibhgnhhndojpepiihcmeb";
+        // the update service isn't asked, the locked URL is downloaded instead
+        let m1 = server
+            .mock("GET", mockito::Matcher::Regex("updatecheck".to_string()))
+            .expect(0)
+            .create_async()
+            .await;
+        let m2 = server
+            .mock("GET", "/vimium-2.1.2.crx")
+            .with_body_from_file("tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx")
+            .with_status(200)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let mut locked = LockedExtension {
+            browser: crate::config::BrowserKind::Chromium,
+            name: extension_id.to_string(),
+            id: extension_id.to_string(),
+            version: "2.1.2".to_string(),
+            url: format!("{}/vimium-2.1.2.crx", server.url()),
+            sha256: "0da10cd4dc8c5fc44c06f5a82153a199f63f69eeba1c235f4459f002e2d41d55".to_string(),
+        };
+        let checks = Checks {
+            locked: Some(locked.clone()),
+            ..Default::default()
+        };
+        let dest_dir = TempDir::new().unwrap();
+        let (artifact, _) = download_extension(
+            ClientBuilder::new(reqwest::Client::new()).build(),
+            Some(server.url()),
+            extension_id.to_string(),
+            None,
+            None,
+            &checks,
+            dest_dir.path(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(artifact.version, "2.1.2");
+        assert_eq!(artifact.url, locked.url);
+
+        // the cached file is used as long as it matches
+        download_extension(
+            ClientBuilder::new(reqwest::Client::new()).build(),
+            Some(server.url()),
+            extension_id.to_string(),
+            None,
+            None,
+            &checks,
+            dest_dir.path(),
+        )
+        .await
+        .unwrap();
+
+        locked.sha256 = "aaaa".to_string();
+        let checks = Checks {
+            locked: Some(locked),
+            ..Default::default()
+        };
+        let dest_dir = TempDir::new().unwrap();
+        let result = download_extension(
+            ClientBuilder::new(reqwest::Client::new()).build(),
+            Some(server.url()),
+            extension_id.to_string(),
+            None,
+            None,
+            &checks,
+            dest_dir.path(),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "dbepggeogbaibhgnhhndojpepiihcmeb: SHA-256 0da10cd4dc8c5fc44c06f5a82153a199f63f69eeba1c235f4459f002e2d41d55 does not match locked SHA-256 aaaa"
+        );
+        assert!(!dest_dir.path().join(format!("{extension_id}.crx")).exists());
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_download_extension_pinned() {
+        let mut server = mockito::Server::new_async().await;
+        let extension_id = "dbepggeogbaibhgnhhndojpepiihcmeb";
+        let m1 = mock_updatecheck(&mut server, extension_id, "2.1.3", None).await;
+        let m2 = server
+            .mock("GET", "/vimium.crx")
+            .expect(0)
+            .create_async()
+            .await;
+        let client = ClientBuilder::new(reqwest::Client::new()).build();
+        let dest_dir = TempDir::new().unwrap();
+
+        // the pinned version is neither cached nor the latest one
+        let result = download_extension(
+            client.clone(),
+            Some(server.url()),
+            extension_id.to_string(),
+            Some("2.1.2"),
+            None,
+            &Checks::default(),
+            dest_dir.path(),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "dbepggeogbaibhgnhhndojpepiihcmeb: pinned version 2.1.2 is not cached and the update service only offers 2.1.3"
+        );
+
+        // the cached version is kept although a newer one is available
+        fs::copy(
+            "tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx",
+            dest_dir.path().join(format!("{extension_id}.crx")),
+        )
+        .await
+        .unwrap();
+        let (artifact, _) = download_extension(
+            client,
+            Some(server.url()),
+            extension_id.to_string(),
+            Some("2.1.2"),
+            None,
+            &Checks::default(),
+            dest_dir.path(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(artifact.version, "2.1.2");
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_uninstall_extension() {
+        let dir = TempDir::new().unwrap();
+        let profile = Target::from(dir.path().join("profile").to_str().unwrap());
+        let ext = ExternalExt {
+            external_crx: dir.path().join("storage").join("foo.crx"),
+            external_version: "1.0".to_string(),
+        };
+        let other = ExternalExt {
+            external_crx: dir.path().join("storage").join("bar.crx"),
+            external_version: "1.0".to_string(),
+        };
+        install_extension(&ext, "foo", &profile).await.unwrap();
+        install_extension(&other, "bar", &profile).await.unwrap();
+
+        uninstall_extension(&ext.external_crx, &profile)
+            .await
+            .unwrap();
+        assert_eq!(
+            installed_extensions(&profile).await.unwrap(),
+            std::slice::from_ref(&other.external_crx)
+        );
+
+        // an older version replaces the installed one
+        let older = dir.path().join("versions").join("bar").join("0.9.crx");
+        install_file(&older, "bar", "0.9", &profile).await.unwrap();
+        assert_eq!(installed_extensions(&profile).await.unwrap(), [older]);
+    }
+
+    #[tokio::test]
+    async fn test_install_extension_copy() {
+        let dir = TempDir::new().unwrap();
+        let profile = Target {
+            link_mode: LinkMode::Copy,
+            ..Target::from(dir.path().join("profile").to_str().unwrap())
+        };
+        let crx_file = dir.path().join("storage").join("foo.crx");
+        create_dir_all(crx_file.parent().unwrap()).await.unwrap();
+        tokio::fs::write(&crx_file, b"1.0").await.unwrap();
+        let ext = ExternalExt {
+            external_crx: crx_file.clone(),
+            external_version: "1.0".to_string(),
+        };
+        install_extension(&ext, "foo", &profile).await.unwrap();
+
+        // the entry references the copy in the profile
+        let copy = create_crx_path("foo", &profile);
+        assert_eq!(tokio::fs::read(&copy).await.unwrap(), b"1.0");
+        assert_eq!(
+            installed_extensions(&profile).await.unwrap(),
+            std::slice::from_ref(&copy)
+        );
+        assert!(check_installed(&ext, "foo", &profile).await.unwrap().latest);
+
+        // a copy which differs from the stored file is stale
+        tokio::fs::write(&crx_file, b"1.0 repacked").await.unwrap();
+        assert!(!check_installed(&ext, "foo", &profile).await.unwrap().latest);
+        install_extension(&ext, "foo", &profile).await.unwrap();
+        assert!(check_installed(&ext, "foo", &profile).await.unwrap().latest);
+
+        uninstall_extension(&crx_file, &profile).await.unwrap();
+        assert!(installed_extensions(&profile).await.unwrap().is_empty());
+        assert!(!try_exists(&copy).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_policies() {
+        let dir = TempDir::new().unwrap();
+        // the install mode decides, not the file name
+        let policy_file = dir.path().join("policy");
+        let target = Target {
+            install_mode: InstallMode::Policies,
+            ..Target::from(policy_file.to_str().unwrap())
+        };
+        let update_url = "https://extensions.example.com/service/update2/crx";
+        let crx_file = dir.path().join("storage").join("foo.crx");
+        managed::install("foo", update_url, &policy_file)
+            .await
+            .unwrap();
+        assert!(install_file(&crx_file, "foo", "1.0", &target)
+            .await
+            .is_err());
+        assert!(installed_extensions(&target).await.unwrap().is_empty());
+
+        uninstall_extension(&crx_file, &target).await.unwrap();
+        assert!(!managed::is_installed("foo", update_url, &policy_file).await);
+    }
+
+    #[tokio::test]
+    async fn test_install_file_unpacked() {
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path().join("unpacked").to_string_lossy().into_owned();
+        let target = Target {
+            install_mode: InstallMode::Unpacked,
+            ..Target::from(dir.as_str())
+        };
+        let crx_file = Path::new("tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx");
+        let extension_id = "dbepggeogbaibhgnhhndojpepiihcmeb";
+        install_file(crx_file, extension_id, "2.1.2", &target)
+            .await
+            .unwrap();
+
+        // the ZIP archive of the CRX file is extracted
+        let current = unpacked::current_dir(extension_id, Path::new(&dir));
+        assert!(current.join("manifest.json").exists());
+        assert_eq!(installed_extensions(&target).await.unwrap(), [crx_file]);
+
+        uninstall_extension(crx_file, &target).await.unwrap();
+        assert!(installed_extensions(&target).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_install_file_invalid_entry() {
+        let dir = TempDir::new().unwrap();
+        let target = Target::from(dir.path().to_str().unwrap());
+        let json_path = create_json_path("foo", &target);
+        create_dir_all(json_path.parent().unwrap()).await.unwrap();
+        tokio::fs::write(&json_path, b"{").await.unwrap();
+
+        // a broken entry counts as not installed and is replaced
+        let crx_file = dir.path().join("storage").join("foo.crx");
+        assert!(!is_installed(&crx_file, "foo", "1.0", &target).await);
+        install_file(&crx_file, "foo", "1.0", &target)
+            .await
+            .unwrap();
+        assert!(is_installed(&crx_file, "foo", "1.0", &target).await);
+    }
+
+    #[tokio::test]
+    async fn test_install_extension_system() {
+        let dir = TempDir::new().unwrap();
+        let system_dir = dir.path().join("extensions");
+        let target = Target {
+            install_mode: InstallMode::System,
+            ..Target::from(system_dir.to_str().unwrap())
+        };
+        let crx_file = dir.path().join("storage").join("foo.crx");
+        install_file(&crx_file, "foo", "1.0", &target)
+            .await
+            .unwrap();
+
+        // the file is placed directly in the system extension directory
+        let json_path = system_dir.join("foo.json");
+        let mode = tokio::fs::metadata(&json_path)
+            .await
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o644);
+        assert_eq!(installed_extensions(&target).await.unwrap(), [crx_file]);
+    }
+
+    async fn mock_updatecheck(
+        server: &mut mockito::ServerGuard,
+        extension_id: &str,
+        version: &str,
+        hash_sha256: Option<&str>,
+    ) -> mockito::Mock {
+        let url = format!("/service/update2/crx?response=updatecheck&prodversion=119.0.6045.199&acceptformat=crx2,crx3&x=id%3D{extension_id}%26installsource%3Dondemand%26uc");
+        let body = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><gupdate xmlns="http://www.google.com/update2/response" protocol="2.0"><app appid="{extension_id}" status="ok"><updatecheck codebase="{}/vimium.crx" hash_sha256="{}" status="ok" version="{version}"/></app></gupdate>"#,
+            server.url(),
+            hash_sha256.unwrap_or_default()
+        );
+        server
+            .mock("GET", url.as_str())
+            .with_header("content-type", "text/xml")
+            .with_body(body)
+            .with_status(200)
+            .create_async()
+            .await
     }
 }
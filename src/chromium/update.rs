@@ -0,0 +1,185 @@
+use anyhow::{anyhow, Result};
+use reqwest_middleware::ClientWithMiddleware;
+use tracing::debug;
+
+/// The Chromium version which is advertised to the update service if the browser version is
+/// unknown.
+pub const PROD_VERSION: &str = "119.0.6045.199";
+
+/// The maximum number of extensions which are checked in a single request
+/// (keeps the request URL well below common length limits).
+pub const MAX_IDS_PER_REQUEST: usize = 25;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The `<updatecheck>` result for a single extension of an update service response.
+///
+/// See https://github.com/google/omaha/blob/main/doc/ServerProtocolV2.md
+pub struct UpdateInfo {
+    /// The extension ID.
+    pub app_id: String,
+    /// `ok` if an update is available, `noupdate` if not, an error otherwise.
+    pub status: String,
+    /// The latest version of the extension.
+    pub version: Option<String>,
+    /// The URL to download the latest version from.
+    pub codebase: Option<String>,
+    /// The SHA-256 of the CRX file.
+    pub hash_sha256: Option<String>,
+}
+
+/// check queries the update service for the latest version of an extension.
+///
+/// * `prod_version` - The Chromium version the extension must support (default: `PROD_VERSION`).
+pub async fn check(
+    client: &ClientWithMiddleware,
+    base_url: &str,
+    extension_id: &str,
+    prod_version: Option<&str>,
+) -> Result<UpdateInfo> {
+    check_batch(client, base_url, &[extension_id.to_string()], prod_version)
+        .await?
+        .into_iter()
+        .find(|info| info.app_id == extension_id)
+        .ok_or_else(|| anyhow!("{extension_id}: missing in update service response"))
+}
+
+/// check_batch queries the update service for the latest versions of several extensions
+/// using a single request.
+///
+/// * `prod_version` - The Chromium version the extensions must support (default: `PROD_VERSION`).
+pub async fn check_batch(
+    client: &ClientWithMiddleware,
+    base_url: &str,
+    extension_ids: &[String],
+    prod_version: Option<&str>,
+) -> Result<Vec<UpdateInfo>> {
+    let url = request_url(base_url, extension_ids, prod_version);
+    debug!("Checking for updates: {url}");
+    let body = client.get(&url).send().await?.text().await?;
+    parse_response(&body)
+}
+
+fn request_url(base_url: &str, extension_ids: &[String], prod_version: Option<&str>) -> String {
+    let prod_version = prod_version.unwrap_or(PROD_VERSION);
+    let mut url = format!(
+        "{base_url}/service/update2/crx?response=updatecheck&prodversion={prod_version}&acceptformat=crx2,crx3"
+    );
+    for extension_id in extension_ids {
+        url.push_str(&format!(
+            "&x=id%3D{extension_id}%26installsource%3Dondemand%26uc"
+        ));
+    }
+    url
+}
+
+/// parse_response parses the `<gupdate>` XML document returned by the update service.
+pub fn parse_response(xml: &str) -> Result<Vec<UpdateInfo>> {
+    let doc = roxmltree::Document::parse(xml)?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "gupdate" {
+        return Err(anyhow!(
+            "Unexpected update service response: <{}>",
+            root.tag_name().name()
+        ));
+    }
+
+    let mut result = Vec::new();
+    for app in root.children().filter(|n| n.has_tag_name("app")) {
+        let app_id = app.attribute("appid").unwrap_or_default().to_string();
+        let app_status = app.attribute("status").unwrap_or("ok");
+        let updatecheck = app.children().find(|n| n.has_tag_name("updatecheck"));
+        let info = match updatecheck {
+            Some(uc) if app_status == "ok" => UpdateInfo {
+                app_id,
+                status: uc.attribute("status").unwrap_or("ok").to_string(),
+                version: uc.attribute("version").map(str::to_string),
+                codebase: uc.attribute("codebase").map(str::to_string),
+                hash_sha256: uc
+                    .attribute("hash_sha256")
+                    .filter(|h| !h.is_empty())
+                    .map(str::to_string),
+            },
+            _ => UpdateInfo {
+                app_id,
+                status: app_status.to_string(),
+                version: None,
+                codebase: None,
+                hash_sha256: None,
+            },
+        };
+        result.push(info);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gupdate xmlns="http://www.google.com/update2/response" protocol="2.0" server="prod">
+  <daystart elapsed_days="6543" elapsed_seconds="42"/>
+  <app appid="dbepggeogbaibhgnhhndojpepiihcmeb" cohort="1::" status="ok">
+    <updatecheck codebase="https://example.com/vimium.crx" hash_sha256="abc" size="278556" status="ok" version="2.1.2"/>
+  </app>
+  <app appid="aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa" status="error-unknownApplication"/>
+  <app appid="bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb" status="ok">
+    <updatecheck status="noupdate"/>
+  </app>
+</gupdate>"#;
+        let infos = parse_response(xml).unwrap();
+        assert_eq!(
+            infos,
+            vec![
+                UpdateInfo {
+                    app_id: "dbepggeogbaibhgnhhndojpepiihcmeb".to_string(),
+                    status: "ok".to_string(),
+                    version: Some("2.1.2".to_string()),
+                    codebase: Some("https://example.com/vimium.crx".to_string()),
+                    hash_sha256: Some("abc".to_string()),
+                },
+                UpdateInfo {
+                    app_id: "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_string(),
+                    status: "error-unknownApplication".to_string(),
+                    version: None,
+                    codebase: None,
+                    hash_sha256: None,
+                },
+                UpdateInfo {
+                    app_id: "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb".to_string(),
+                    status: "noupdate".to_string(),
+                    version: None,
+                    codebase: None,
+                    hash_sha256: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_request_url() {
+        let url = request_url(
+            "https://example.com",
+            &["a".repeat(32), "b".repeat(32)],
+            None,
+        );
+        assert_eq!(
+            url,
+            format!("https://example.com/service/update2/crx?response=updatecheck&prodversion={PROD_VERSION}&acceptformat=crx2,crx3&x=id%3D{}%26installsource%3Dondemand%26uc&x=id%3D{}%26installsource%3Dondemand%26uc", "a".repeat(32), "b".repeat(32))
+        );
+
+        let url = request_url("https://example.com", &["a".repeat(32)], Some("126.0"));
+        assert_eq!(
+            url,
+            format!("https://example.com/service/update2/crx?response=updatecheck&prodversion=126.0&acceptformat=crx2,crx3&x=id%3D{}%26installsource%3Dondemand%26uc", "a".repeat(32))
+        );
+    }
+
+    #[test]
+    fn test_parse_response_invalid() {
+        assert!(parse_response("<html></html>").is_err());
+        assert!(parse_response("Cr24").is_err());
+    }
+}
@@ -1,31 +1,48 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use reqwest_middleware::ClientWithMiddleware;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use tracing::{debug, info};
 
+use super::update::UpdateInfo;
+use crate::artifact::Artifact;
+use crate::checks::Checks;
+use crate::config::{ExtensionName, Target};
+use crate::plan::{Change, Plan};
+
+const UPDATE_URL: &str = "https://clients2.google.com/service/update2/crx";
+
+/// check_updates returns no results on Windows since the browser updates extensions itself.
+pub async fn check_updates(
+    _client: &ClientWithMiddleware,
+    _base_url: Option<String>,
+    _extension_ids: Vec<String>,
+    _browser_version: Option<&str>,
+) -> HashMap<String, UpdateInfo> {
+    HashMap::new()
+}
+
+/// install registers the extension in the registry. The browser downloads it from the Chrome
+/// Web Store itself, so nothing is stored and the extension can't be locked to a version.
 pub async fn install(
     _client: ClientWithMiddleware,
     _base_url: Option<String>,
-    extension_id: String,
+    _update_url: Option<String>,
+    extension: ExtensionName,
+    _update: Option<UpdateInfo>,
+    _checks: Checks,
     _dest_dir: PathBuf,
-    profiles: Vec<String>,
-) -> Result<Option<PathBuf>> {
+    profiles: Vec<Target>,
+) -> Result<Option<Artifact>> {
+    let extension_id = check_not_pinned(&extension)?;
     let hklm = winreg::RegKey::predef(winreg::enums::HKEY_LOCAL_MACHINE);
-    let os_arch = std::env::var("PROCESSOR_ARCHITECTURE").unwrap_or_default();
 
-    for path in profiles {
-        let path = if os_arch == "AMD64" {
-            format!("Software\\Wow6432Node\\{path}\\Extensions\\{extension_id}")
-        } else {
-            format!("Software\\{path}\\Extensions\\{extension_id}")
-        };
+    for target in profiles {
+        let path = registry_path(&target.profile, extension_id);
 
         let (key, disp) = hklm.create_subkey(&path)?;
         if disp == winreg::enums::RegDisposition::REG_CREATED_NEW_KEY {
-            key.set_value(
-                "update_url",
-                &"https://clients2.google.com/service/update2/crx",
-            )?;
+            key.set_value("update_url", &UPDATE_URL)?;
             info!("Installed extension {extension_id} for {path}");
         } else {
             debug!("Extension {extension_id} already installed for {path}");
@@ -34,3 +51,87 @@ pub async fn install(
 
     Ok(None)
 }
+
+/// plan determines the registry keys `install` would create.
+pub async fn plan(
+    _client: ClientWithMiddleware,
+    _base_url: Option<String>,
+    _update_url: Option<String>,
+    extension: ExtensionName,
+    _update: Option<UpdateInfo>,
+    _browser_version: Option<String>,
+    _dest_dir: PathBuf,
+    profiles: Vec<Target>,
+) -> Result<Plan> {
+    let extension_id = check_not_pinned(&extension)?;
+    let hklm = winreg::RegKey::predef(winreg::enums::HKEY_LOCAL_MACHINE);
+    let mut changes = Vec::new();
+    for target in profiles {
+        let path = registry_path(&target.profile, extension_id);
+        if hklm.open_subkey(&path).is_err() {
+            changes.push(Change::Install {
+                location: PathBuf::from(format!("HKLM\\{path}")),
+                source: PathBuf::from(UPDATE_URL),
+                version: "latest".to_string(),
+            });
+        }
+    }
+    Ok(Plan {
+        file: None,
+        changes,
+    })
+}
+
+/// check_not_pinned returns the extension ID. The browser installs the latest version from the
+/// store, so pinning a version is not supported.
+fn check_not_pinned(extension: &ExtensionName) -> Result<&str> {
+    match extension.version() {
+        Some(_) => Err(anyhow!(
+            "{}: pinning a version is not supported on Windows",
+            extension.name()
+        )),
+        None => Ok(extension.name()),
+    }
+}
+
+fn registry_path(path: &str, extension_id: &str) -> String {
+    let os_arch = std::env::var("PROCESSOR_ARCHITECTURE").unwrap_or_default();
+    if os_arch == "AMD64" {
+        format!("Software\\Wow6432Node\\{path}\\Extensions\\{extension_id}")
+    } else {
+        format!("Software\\{path}\\Extensions\\{extension_id}")
+    }
+}
+
+/// install_file fails on Windows since the browser downloads extensions itself.
+pub async fn install_file(
+    _crx_file: &Path,
+    extension_id: &str,
+    _version: &str,
+    _target: &Target,
+) -> Result<()> {
+    Err(anyhow!(
+        "{extension_id}: installing a stored version is not supported on Windows"
+    ))
+}
+
+/// is_installed returns false on Windows since stored files are never installed.
+pub async fn is_installed(
+    _crx_file: &Path,
+    _extension_id: &str,
+    _version: &str,
+    _target: &Target,
+) -> bool {
+    false
+}
+
+/// uninstall_extension is a no-op on Windows since `installed_extensions` returns no files.
+pub async fn uninstall_extension(_crx_file: &Path, _target: &Target) -> Result<()> {
+    Ok(())
+}
+
+/// installed_extensions returns no files on Windows since extensions are installed
+/// through the registry and fetched by the browser itself.
+pub async fn installed_extensions(_target: &Target) -> Result<Vec<PathBuf>> {
+    Ok(Vec::new())
+}
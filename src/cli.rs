@@ -0,0 +1,156 @@
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Debug, Default, Parser)]
+#[command(
+    version,
+    about = "Download browser extensions for Firefox and Chromium-based browsers"
+)]
+/// Command line arguments.
+pub struct Cli {
+    /// Path to the configuration file (default: <config dir>/extension-downloader/config.toml).
+    #[arg(short, long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Directory where downloaded extensions are stored (overrides `extensions_dir` from the config).
+    #[arg(long, global = true, value_name = "DIR")]
+    pub extensions_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+/// The available subcommands. Defaults to `sync` if none is given.
+pub enum Command {
+    /// Download, update and install all configured extensions.
+    Sync(SyncArgs),
+    /// Download the latest versions of all configured extensions and update the lockfile
+    /// without installing them.
+    Lock,
+    /// Show what a sync would change without writing anything (dry-run).
+    Plan,
+    /// List the configured extensions and the profiles they are installed into.
+    List,
+    /// List the browser profiles found in the configured user data directories.
+    Profiles,
+    /// Show the managed extensions, their versions and the profiles they are installed in.
+    Status,
+    /// Remove managed extensions which are not installed in any profile.
+    Prune,
+    /// Install an earlier version of an extension and keep it until it is released.
+    Rollback(RollbackArgs),
+    /// Release a rolled back extension so the next sync updates it again.
+    Release(ExtensionArgs),
+    /// Approve the held update of an extension which adds permissions.
+    Approve(ExtensionArgs),
+    /// Print the command-line flags which make Chromium load the unpacked extensions.
+    Flags,
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Command::Sync(SyncArgs::default())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Args)]
+/// Arguments of the `sync` subcommand.
+pub struct SyncArgs {
+    /// Install exactly the versions recorded in the lockfile and fail if upstream no longer
+    /// matches.
+    #[arg(long)]
+    pub frozen: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+/// Arguments of the `rollback` subcommand.
+pub struct RollbackArgs {
+    /// The name of the extension as used in the config.
+    pub name: String,
+    /// The version to roll back to (default: the newest kept version older than the installed one).
+    #[arg(long, value_name = "VERSION")]
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+/// Arguments of the subcommands which refer to a single extension.
+pub struct ExtensionArgs {
+    /// The name of the extension as used in the config.
+    pub name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_verify_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse() {
+        let cli = Cli::try_parse_from([
+            "extension-downloader",
+            "status",
+            "--config",
+            "/tmp/config.toml",
+            "--extensions-dir",
+            "/tmp/extensions",
+        ])
+        .unwrap();
+        assert_eq!(cli.command, Some(Command::Status));
+        assert_eq!(cli.config, Some(PathBuf::from("/tmp/config.toml")));
+        assert_eq!(cli.extensions_dir, Some(PathBuf::from("/tmp/extensions")));
+    }
+
+    #[test]
+    fn test_parse_default() {
+        let cli = Cli::try_parse_from(["extension-downloader"]).unwrap();
+        assert_eq!(
+            cli.command.unwrap_or_default(),
+            Command::Sync(SyncArgs { frozen: false })
+        );
+        assert!(cli.config.is_none());
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let cli = Cli::try_parse_from(["extension-downloader", "flags"]).unwrap();
+        assert_eq!(cli.command, Some(Command::Flags));
+    }
+
+    #[test]
+    fn test_parse_frozen() {
+        let cli = Cli::try_parse_from(["extension-downloader", "sync", "--frozen"]).unwrap();
+        assert_eq!(cli.command, Some(Command::Sync(SyncArgs { frozen: true })));
+    }
+
+    #[test]
+    fn test_parse_rollback() {
+        let cli = Cli::try_parse_from(["extension-downloader", "rollback", "vimium-ff"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Rollback(RollbackArgs {
+                name: "vimium-ff".to_string(),
+                to: None
+            }))
+        );
+
+        let cli = Cli::try_parse_from([
+            "extension-downloader",
+            "rollback",
+            "vimium-ff",
+            "--to",
+            "2.1.1",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Rollback(RollbackArgs {
+                name: "vimium-ff".to_string(),
+                to: Some("2.1.1".to_string())
+            }))
+        );
+    }
+}
@@ -1,29 +1,447 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use tokio::fs;
 use tracing::debug;
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::policy::Policy;
+use crate::profiles;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 /// The configuration for the extension manager.
 pub struct Config {
     pub base_url_mozilla: Option<String>,
     pub base_url_google: Option<String>,
     /// The directory where the browser extensions are stored.
     pub extensions_dir: Option<PathBuf>,
+    /// The CA certificate (PEM or DER) the signatures of Firefox extensions must chain up to
+    /// instead of the bundled AMO signing CA, e.g. Mozilla's `root-ca-production-amo`.
+    pub mozilla_root_certificate: Option<PathBuf>,
+    /// The number of versions of each extension kept for rollbacks (default: 3).
+    pub keep_versions: Option<usize>,
+    /// The number of days unused extension files are kept before they are purged (default: 0).
+    pub purge_grace_days: Option<u64>,
+    /// Hold updates which add permissions until they are approved.
+    #[serde(default)]
+    pub hold_new_permissions: bool,
+    /// The (self-hosted) update URL of Chromium extensions installed using policies (default:
+    /// the Chrome Web Store).
+    pub chromium_update_url: Option<String>,
+    /// The locale (e.g. `de` or `pt_BR`) extension names are shown in (default: the locale of
+    /// the environment).
+    pub locale: Option<String>,
     /// A list of extensions to install.
     pub extensions: Vec<Extension>,
+    /// The rules which extensions must comply with.
+    #[serde(default)]
+    pub policy: Policy,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "ExtensionEntry")]
 /// A browser extension to install.
 pub struct Extension {
     // The kind of browser to install the extension for.
     pub browser: BrowserKind,
     // Either a file path to the browser profile directory or the Windows registry key.
+    // Profiles can also be referred to by name, "default" or "*" (all profiles).
     pub profile: String,
+    // The directory containing Firefox's profiles.ini (default: ~/.mozilla/firefox) or
+    // Chromium's "Local State".
+    pub user_data_dir: Option<String>,
+    // The version of the browser which loads the extensions, detected from the profile if
+    // omitted. Extensions the browser can't load are not installed.
+    pub browser_version: Option<String>,
+    // How the extensions are installed.
+    pub install_mode: InstallMode,
+    // How the stored extension files are put into the profile.
+    pub link_mode: LinkMode,
     // The extensions to install.
-    pub names: Vec<String>,
+    pub names: Vec<ExtensionName>,
+}
+
+#[derive(Deserialize)]
+/// An `[[extensions]]` entry as written in the config file.
+struct ExtensionEntry {
+    browser: Browser,
+    #[serde(default = "default_profile")]
+    profile: String,
+    user_data_dir: Option<String>,
+    browser_version: Option<String>,
+    #[serde(default)]
+    install_mode: InstallMode,
+    link_mode: Option<LinkMode>,
+    names: Vec<ExtensionName>,
+}
+
+fn default_profile() -> String {
+    profiles::DEFAULT_PROFILE.to_string()
+}
+
+impl From<ExtensionEntry> for Extension {
+    fn from(entry: ExtensionEntry) -> Self {
+        let profile = match entry.browser.default_location(entry.install_mode) {
+            Some(dir) if entry.profile == profiles::DEFAULT_PROFILE => dir.to_string(),
+            _ => entry.profile,
+        };
+        Extension {
+            browser: entry.browser.kind(),
+            profile,
+            user_data_dir: entry
+                .user_data_dir
+                .or_else(|| entry.browser.user_data_dir()),
+            browser_version: entry.browser_version,
+            install_mode: entry.install_mode,
+            link_mode: entry
+                .link_mode
+                .unwrap_or_else(|| entry.browser.default_link_mode()),
+            names: entry.names,
+        }
+    }
+}
+
+#[derive(
+    Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+/// How the extensions of a config entry are installed.
+pub enum InstallMode {
+    /// Link the extensions into the profile.
+    #[default]
+    Profile,
+    /// Force-install the extensions using enterprise policies. The profile is the policies file
+    /// (Firefox) or managed policy file (Chromium), the directory containing it or `default` for
+    /// the system-wide location.
+    Policies,
+    /// Install the extensions for all users into a system extension directory, e.g.
+    /// `/usr/share/chromium/extensions`. The directory of the browser preset is used for
+    /// `default`.
+    System,
+    /// Extract the extensions into versioned directories in the profile, which is the directory
+    /// loaded by e.g. Chromium's `--load-extension`.
+    Unpacked,
+}
+
+impl InstallMode {
+    /// is_system_wide returns whether the extensions are installed for all users.
+    pub fn is_system_wide(self) -> bool {
+        matches!(self, InstallMode::Policies | InstallMode::System)
+    }
+}
+
+#[derive(
+    Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+/// How the stored extension files are put into a profile.
+pub enum LinkMode {
+    /// Symlink the stored files (Firefox) or reference them directly (Chromium).
+    #[default]
+    Symlink,
+    /// Hardlink the stored files into the profile, which must be on the same file system.
+    Hardlink,
+    /// Copy the stored files into the profile, e.g. for sandboxed browsers (Flatpak, Snap) which
+    /// can't access the extensions directory. Copies are refreshed when the stored file changes.
+    Copy,
+}
+
+impl std::fmt::Display for LinkMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkMode::Symlink => write!(f, "symlink"),
+            LinkMode::Hardlink => write!(f, "hardlink"),
+            LinkMode::Copy => write!(f, "copy"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(from = "TargetEntry")]
+/// A profile (or other install target) and how extensions are installed into it.
+pub struct Target {
+    pub profile: String,
+    pub install_mode: InstallMode,
+    pub link_mode: LinkMode,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+/// A target as recorded in the state file. Older state files only contain the profile.
+enum TargetEntry {
+    Profile(String),
+    Target {
+        profile: String,
+        #[serde(default)]
+        install_mode: InstallMode,
+        #[serde(default)]
+        link_mode: LinkMode,
+    },
+}
+
+impl From<TargetEntry> for Target {
+    fn from(entry: TargetEntry) -> Self {
+        match entry {
+            TargetEntry::Profile(profile) => Target::from(profile.as_str()),
+            TargetEntry::Target {
+                profile,
+                install_mode,
+                link_mode,
+            } => Target {
+                profile,
+                install_mode,
+                link_mode,
+            },
+        }
+    }
+}
+
+impl From<&str> for Target {
+    fn from(profile: &str) -> Self {
+        Target {
+            profile: profile.to_string(),
+            install_mode: InstallMode::default(),
+            link_mode: LinkMode::default(),
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.profile)
+    }
+}
+
+impl Extension {
+    /// target returns the (resolved) profile of the entry and how extensions are installed into
+    /// it.
+    pub fn target(&self) -> Target {
+        Target {
+            profile: self.profile.clone(),
+            install_mode: self.install_mode,
+            link_mode: self.link_mode,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+/// A browser or one of the presets for Firefox and Chromium-based browsers.
+enum Browser {
+    Firefox,
+    FirefoxFlatpak,
+    FirefoxSnap,
+    Librewolf,
+    Chromium,
+    ChromiumFlatpak,
+    ChromiumSnap,
+    Chrome,
+    Brave,
+    Vivaldi,
+    Edge,
+}
+
+impl Browser {
+    fn kind(self) -> BrowserKind {
+        match self {
+            Browser::Firefox
+            | Browser::FirefoxFlatpak
+            | Browser::FirefoxSnap
+            | Browser::Librewolf => BrowserKind::Firefox,
+            _ => BrowserKind::Chromium,
+        }
+    }
+
+    /// user_data_dir returns the default user data directory of the browser on Linux.
+    fn user_data_dir(self) -> Option<String> {
+        if !cfg!(target_os = "linux") {
+            return None;
+        }
+        let dir = match self {
+            Browser::Firefox => return None,
+            Browser::FirefoxFlatpak => {
+                dirs::home_dir()?.join(".var/app/org.mozilla.firefox/.mozilla/firefox")
+            }
+            Browser::FirefoxSnap => dirs::home_dir()?.join("snap/firefox/common/.mozilla/firefox"),
+            Browser::Librewolf => dirs::home_dir()?.join(".librewolf"),
+            Browser::Chromium => dirs::config_dir()?.join("chromium"),
+            Browser::ChromiumFlatpak => {
+                dirs::home_dir()?.join(".var/app/org.chromium.Chromium/config/chromium")
+            }
+            Browser::ChromiumSnap => dirs::home_dir()?.join("snap/chromium/common/chromium"),
+            Browser::Chrome => dirs::config_dir()?.join("google-chrome"),
+            Browser::Brave => dirs::config_dir()?.join("BraveSoftware/Brave-Browser"),
+            Browser::Vivaldi => dirs::config_dir()?.join("vivaldi"),
+            Browser::Edge => dirs::config_dir()?.join("microsoft-edge"),
+        };
+        Some(dir.to_string_lossy().into_owned())
+    }
+
+    /// default_link_mode returns how extensions are put into the profiles of the browser unless
+    /// configured otherwise. Sandboxed browsers can't follow symlinks out of their sandbox.
+    fn default_link_mode(self) -> LinkMode {
+        match self {
+            Browser::FirefoxFlatpak
+            | Browser::FirefoxSnap
+            | Browser::ChromiumFlatpak
+            | Browser::ChromiumSnap => LinkMode::Copy,
+            _ => LinkMode::Symlink,
+        }
+    }
+
+    /// default_location returns where the browser reads extensions installed with the given
+    /// mode from on Linux, if known.
+    fn default_location(self, mode: InstallMode) -> Option<&'static str> {
+        if !cfg!(target_os = "linux") {
+            return None;
+        }
+        match (mode, self) {
+            (InstallMode::Policies, Browser::Chrome) => Some("/etc/opt/chrome/policies/managed"),
+            (InstallMode::Policies, Browser::Brave) => Some("/etc/brave/policies/managed"),
+            (InstallMode::Policies, Browser::Edge) => Some("/etc/opt/edge/policies/managed"),
+            (InstallMode::System, Browser::Firefox) => {
+                Some("/usr/lib/mozilla/extensions/{ec8030f7-c20a-464f-9b0e-13a3a9e97384}")
+            }
+            (InstallMode::System, Browser::Chromium) => Some("/usr/share/chromium/extensions"),
+            (InstallMode::System, Browser::Chrome) => Some("/opt/google/chrome/extensions"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(untagged, try_from = "ExtensionNameEntry")]
+/// An extension to install: either the plain name (Firefox) or ID (Chromium), or a table which
+/// pins the extension to a specific version, e.g. `{ name = "ublock-origin", version = "1.57.2" }`.
+///
+/// Extensions which are not in a store are installed from a local `.xpi`/`.crx` file or a plain
+/// URL instead, e.g. `{ path = "/srv/extensions/foo.xpi", sha256 = "..." }`.
+pub enum ExtensionName {
+    Latest(String),
+    Pinned {
+        name: String,
+        version: String,
+    },
+    File {
+        path: String,
+        sha256: Option<String>,
+    },
+    Url {
+        url: String,
+        sha256: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(
+    untagged,
+    expecting = "expected an extension name or a table with either `name` and `version`, `path` or `url`"
+)]
+/// An entry of `names` as written in the config file.
+enum ExtensionNameEntry {
+    Name(String),
+    Table {
+        name: Option<String>,
+        version: Option<String>,
+        path: Option<String>,
+        url: Option<String>,
+        sha256: Option<String>,
+    },
+}
+
+impl TryFrom<ExtensionNameEntry> for ExtensionName {
+    type Error = String;
+
+    fn try_from(entry: ExtensionNameEntry) -> Result<Self, Self::Error> {
+        let (name, version, path, url, sha256) = match entry {
+            ExtensionNameEntry::Name(name) => return Ok(ExtensionName::Latest(name)),
+            ExtensionNameEntry::Table {
+                name,
+                version,
+                path,
+                url,
+                sha256,
+            } => (name, version, path, url, sha256),
+        };
+        match (name, path, url) {
+            (Some(name), None, None) => match (version, sha256) {
+                (Some(version), None) => Ok(ExtensionName::Pinned { name, version }),
+                (None, _) => Err(format!(
+                    "extension '{name}' needs a `version` to pin, use the plain name to install \
+                     the latest version"
+                )),
+                (Some(_), Some(_)) => Err(format!(
+                    "extension '{name}': `sha256` is only checked for extensions installed from \
+                     a `path` or `url`"
+                )),
+            },
+            (None, Some(path), None) if version.is_none() => {
+                Ok(ExtensionName::File { path, sha256 })
+            }
+            (None, None, Some(url)) if version.is_none() => Ok(ExtensionName::Url { url, sha256 }),
+            (None, None, None) => Err("extension table needs a `name`, `path` or `url`".into()),
+            (None, Some(source), None) | (None, None, Some(source)) => Err(format!(
+                "extension '{source}': only extensions from the store can be pinned to a `version`"
+            )),
+            _ => Err("extension table needs exactly one of `name`, `path` or `url`".into()),
+        }
+    }
+}
+
+impl ExtensionName {
+    /// name returns the name or ID in the store, or the path or URL of other extensions.
+    pub fn name(&self) -> &str {
+        match self {
+            ExtensionName::Latest(name) => name,
+            ExtensionName::Pinned { name, .. } => name,
+            ExtensionName::File { path, .. } => path,
+            ExtensionName::Url { url, .. } => url,
+        }
+    }
+
+    /// version returns the pinned version, if any.
+    pub fn version(&self) -> Option<&str> {
+        match self {
+            ExtensionName::Pinned { version, .. } => Some(version),
+            _ => None,
+        }
+    }
+
+    /// sha256 returns the expected SHA-256 of an extension installed from a file or URL, if any.
+    pub fn sha256(&self) -> Option<&str> {
+        match self {
+            ExtensionName::File { sha256, .. } | ExtensionName::Url { sha256, .. } => {
+                sha256.as_deref()
+            }
+            _ => None,
+        }
+    }
+
+    /// is_store returns whether the extension is downloaded from addons.mozilla.org or the
+    /// Chrome Web Store.
+    pub fn is_store(&self) -> bool {
+        matches!(
+            self,
+            ExtensionName::Latest(_) | ExtensionName::Pinned { .. }
+        )
+    }
+}
+
+impl From<&str> for ExtensionName {
+    fn from(name: &str) -> Self {
+        ExtensionName::Latest(name.to_string())
+    }
+}
+
+impl std::fmt::Display for ExtensionName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExtensionName::Latest(name) => write!(f, "{name}"),
+            ExtensionName::Pinned { name, version } => write!(f, "{name} (pinned to {version})"),
+            ExtensionName::File { path, .. } => write!(f, "{path}"),
+            ExtensionName::Url { url, .. } => write!(f, "{url}"),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -34,6 +452,15 @@ pub enum BrowserKind {
     Chromium,
 }
 
+impl std::fmt::Display for BrowserKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BrowserKind::Firefox => write!(f, "firefox"),
+            BrowserKind::Chromium => write!(f, "chromium"),
+        }
+    }
+}
+
 pub async fn from_file(path: &Path) -> Result<Config> {
     debug!("Loading config file {:?}", path);
     let contents = fs::read_to_string(path).await?;
@@ -42,11 +469,104 @@ pub async fn from_file(path: &Path) -> Result<Config> {
     // expand user
     for ext in cfg.extensions.iter_mut() {
         ext.profile = expand_tilde(&ext.profile);
+        ext.user_data_dir = ext.user_data_dir.as_deref().map(expand_tilde);
+        for name in ext.names.iter_mut() {
+            if let ExtensionName::File { path, .. } = name {
+                *path = expand_tilde(path);
+            }
+        }
     }
+    validate_pins(&cfg)?;
     debug!("Loaded config: {:?}", cfg);
     Ok(cfg)
 }
 
+/// resolve returns the config with the profile names of its entries replaced by their
+/// directories, see [`profiles::resolve`]. Entries which can't be resolved are returned with
+/// their error instead, so only their extensions fail.
+pub async fn resolve(cfg: &Config) -> (Config, Vec<(Extension, anyhow::Error)>) {
+    let mut extensions = Vec::with_capacity(cfg.extensions.len());
+    let mut unresolved = Vec::new();
+    let mut link_modes = HashMap::new();
+    for ext in &cfg.extensions {
+        match resolve_extension(ext, &mut link_modes).await {
+            Ok(resolved) => extensions.extend(resolved),
+            Err(err) => unresolved.push((ext.clone(), err)),
+        }
+    }
+    let cfg = Config {
+        extensions,
+        ..cfg.clone()
+    };
+    debug!("Resolved config: {:?}", cfg);
+    (cfg, unresolved)
+}
+
+/// resolve_extension returns an entry per profile the given entry refers to.
+///
+/// * `link_modes` - The link modes of the profiles resolved so far. A profile can't be
+///   configured with different link modes, since each extension is put into a profile only once.
+async fn resolve_extension(
+    ext: &Extension,
+    link_modes: &mut HashMap<(BrowserKind, String), LinkMode>,
+) -> Result<Vec<Extension>> {
+    let profiles = profiles::resolve(ext).await?;
+    for profile in &profiles {
+        match link_modes.get(&(ext.browser, profile.clone())) {
+            Some(mode) if *mode != ext.link_mode => {
+                return Err(anyhow!(
+                    "{} {}: conflicting link modes {} and {}",
+                    ext.browser,
+                    profile,
+                    mode,
+                    ext.link_mode
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(profiles
+        .into_iter()
+        .map(|profile| {
+            link_modes.insert((ext.browser, profile.clone()), ext.link_mode);
+            Extension {
+                profile,
+                ..ext.clone()
+            }
+        })
+        .collect())
+}
+
+/// validate_pins makes sure an extension is not pinned to different versions or hashes, since
+/// each extension is stored only once.
+fn validate_pins(cfg: &Config) -> Result<()> {
+    let mut pins: HashMap<(BrowserKind, &str), &ExtensionName> = HashMap::new();
+    for ext in &cfg.extensions {
+        for name in &ext.names {
+            let pinned = pins.entry((ext.browser, name.name())).or_insert(name);
+            if pinned.version() != name.version() {
+                return Err(anyhow!(
+                    "{} {}: conflicting versions {} and {}",
+                    ext.browser,
+                    name.name(),
+                    pinned.version().unwrap_or("latest"),
+                    name.version().unwrap_or("latest")
+                ));
+            }
+            if pinned.sha256() != name.sha256() {
+                return Err(anyhow!(
+                    "{} {}: conflicting sha256 {} and {}",
+                    ext.browser,
+                    name.name(),
+                    pinned.sha256().unwrap_or("none"),
+                    name.sha256().unwrap_or("none")
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
 fn expand_tilde(path: &str) -> String {
     match (path.starts_with("~/"), dirs::home_dir()) {
         (true, Some(home)) => {
@@ -89,7 +609,399 @@ mod tests {
         assert_eq!(cfg.extensions.len(), 1);
         assert_eq!(cfg.extensions[0].browser, BrowserKind::Firefox);
         assert_eq!(cfg.extensions[0].profile, "/tmp");
-        assert_eq!(cfg.extensions[0].names, vec!["foo".to_string()]);
+        assert_eq!(cfg.extensions[0].names, vec!["foo".into()]);
+    }
+
+    #[tokio::test]
+    async fn test_from_file_pinned() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = r#"
+            [[extensions]]
+            browser = "firefox"
+            profile = "/tmp"
+            names = ["foo", { name = "ublock-origin", version = "1.57.2" }]
+        "#;
+        fs::write(&path, contents).await.unwrap();
+
+        let cfg = from_file(&path).await.unwrap();
+        let names = &cfg.extensions[0].names;
+        assert_eq!(names[0].name(), "foo");
+        assert_eq!(names[0].version(), None);
+        assert_eq!(names[1].name(), "ublock-origin");
+        assert_eq!(names[1].version(), Some("1.57.2"));
+        assert_eq!(names[1].to_string(), "ublock-origin (pinned to 1.57.2)");
+    }
+
+    #[tokio::test]
+    async fn test_from_file_sources() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = r#"
+            [[extensions]]
+            browser = "firefox"
+            profile = "/tmp"
+            names = [
+                { path = "~/extensions/foo.xpi" },
+                { url = "https://example.com/bar.xpi", sha256 = "abcd" },
+            ]
+        "#;
+        fs::write(&path, contents).await.unwrap();
+
+        let cfg = from_file(&path).await.unwrap();
+        let names = &cfg.extensions[0].names;
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(
+            names[0],
+            ExtensionName::File {
+                path: home
+                    .join("extensions/foo.xpi")
+                    .to_string_lossy()
+                    .into_owned(),
+                sha256: None,
+            }
+        );
+        assert!(!names[0].is_store());
+        assert_eq!(names[1].name(), "https://example.com/bar.xpi");
+        assert_eq!(names[1].sha256(), Some("abcd"));
+        assert_eq!(names[1].version(), None);
+    }
+
+    #[tokio::test]
+    async fn test_from_file_conflicting_hashes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = r#"
+            [[extensions]]
+            browser = "chromium"
+            profile = "/tmp/a"
+            names = [{ url = "https://example.com/foo.crx", sha256 = "abcd" }]
+
+            [[extensions]]
+            browser = "chromium"
+            profile = "/tmp/b"
+            names = [{ url = "https://example.com/foo.crx" }]
+        "#;
+        fs::write(&path, contents).await.unwrap();
+
+        assert_eq!(
+            from_file(&path).await.unwrap_err().to_string(),
+            "chromium https://example.com/foo.crx: conflicting sha256 abcd and none"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_from_file_malformed_names() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        for (names, message) in [
+            (
+                r#"[{ name = "x" }]"#,
+                "extension 'x' needs a `version` to pin",
+            ),
+            (
+                r#"[{ name = "x", version = "1.0", sha256 = "abcd" }]"#,
+                "extension 'x': `sha256` is only checked",
+            ),
+            (
+                r#"[{ path = "/tmp/x.crx", version = "1.0" }]"#,
+                "extension '/tmp/x.crx': only extensions from the store can be pinned",
+            ),
+            (
+                r#"[{ path = "/tmp/x.crx", url = "https://example.com/x.crx" }]"#,
+                "extension table needs exactly one of `name`, `path` or `url`",
+            ),
+            (
+                r#"[{ sha256 = "abcd" }]"#,
+                "extension table needs a `name`, `path` or `url`",
+            ),
+            (
+                "[1]",
+                "expected an extension name or a table with either `name` and `version`",
+            ),
+        ] {
+            let contents = format!("[[extensions]]\nbrowser = \"chromium\"\nnames = {names}\n");
+            fs::write(&path, contents).await.unwrap();
+            let err = format!("{:#}", from_file(&path).await.unwrap_err());
+            assert!(err.contains(message), "{names}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_from_file_conflicting_pins() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = r#"
+            [[extensions]]
+            browser = "firefox"
+            profile = "/tmp/a"
+            names = [{ name = "foo", version = "1.0" }]
+
+            [[extensions]]
+            browser = "firefox"
+            profile = "/tmp/b"
+            names = ["foo"]
+        "#;
+        fs::write(&path, contents).await.unwrap();
+
+        assert_eq!(
+            from_file(&path).await.unwrap_err().to_string(),
+            "firefox foo: conflicting versions 1.0 and latest"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_profile_names() {
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("profiles.ini"),
+            "[Profile0]\nName=work\nIsRelative=1\nPath=abcd.work\n\n[Profile1]\nName=private\nIsRelative=1\nPath=efgh.private\n",
+        )
+        .await
+        .unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = format!(
+            r#"
+            [[extensions]]
+            browser = "firefox"
+            profile = "*"
+            user_data_dir = "{}"
+            names = ["foo"]
+        "#,
+            dir.path().display()
+        );
+        fs::write(&path, contents).await.unwrap();
+
+        let cfg = from_file(&path).await.unwrap();
+        // profiles are resolved when a command runs
+        assert_eq!(cfg.extensions[0].profile, "*");
+        let (cfg, unresolved) = resolve(&cfg).await;
+        assert!(unresolved.is_empty());
+        let profiles: Vec<_> = cfg.extensions.iter().map(|e| e.profile.clone()).collect();
+        assert_eq!(
+            profiles,
+            [
+                dir.path().join("abcd.work").to_string_lossy(),
+                dir.path().join("efgh.private").to_string_lossy()
+            ]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_from_file_presets() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = r#"
+            [[extensions]]
+            browser = "brave"
+            profile = "/tmp/brave"
+            names = ["foo"]
+
+            [[extensions]]
+            browser = "librewolf"
+            profile = "/tmp/librewolf"
+            names = ["bar"]
+        "#;
+        fs::write(&path, contents).await.unwrap();
+
+        let cfg = from_file(&path).await.unwrap();
+        assert_eq!(cfg.extensions[0].browser, BrowserKind::Chromium);
+        assert_eq!(
+            cfg.extensions[0].user_data_dir,
+            Some(
+                dirs::config_dir()
+                    .unwrap()
+                    .join("BraveSoftware/Brave-Browser")
+                    .to_string_lossy()
+                    .into_owned()
+            )
+        );
+        assert_eq!(cfg.extensions[1].browser, BrowserKind::Firefox);
+        assert_eq!(
+            cfg.extensions[1].user_data_dir,
+            Some(
+                dirs::home_dir()
+                    .unwrap()
+                    .join(".librewolf")
+                    .to_string_lossy()
+                    .into_owned()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_from_file_policies() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = r#"
+            [[extensions]]
+            browser = "firefox"
+            install_mode = "policies"
+            profile = "/usr/lib/firefox/distribution"
+            names = ["foo"]
+        "#;
+        fs::write(&path, contents).await.unwrap();
+
+        let (cfg, _) = resolve(&from_file(&path).await.unwrap()).await;
+        assert_eq!(cfg.extensions[0].install_mode, InstallMode::Policies);
+        assert_eq!(
+            cfg.extensions[0].profile,
+            "/usr/lib/firefox/distribution/policies.json"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_from_file_policies_presets() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = r#"
+            chromium_update_url = "https://extensions.example.com/update"
+
+            [[extensions]]
+            browser = "chrome"
+            install_mode = "policies"
+            names = ["foo"]
+
+            [[extensions]]
+            browser = "chromium"
+            install_mode = "policies"
+            names = ["foo"]
+        "#;
+        fs::write(&path, contents).await.unwrap();
+
+        let (cfg, _) = resolve(&from_file(&path).await.unwrap()).await;
+        assert_eq!(
+            cfg.chromium_update_url.as_deref(),
+            Some("https://extensions.example.com/update")
+        );
+        assert_eq!(
+            cfg.extensions[0].profile,
+            "/etc/opt/chrome/policies/managed/extension-downloader.json"
+        );
+        assert_eq!(
+            cfg.extensions[1].profile,
+            "/etc/chromium/policies/managed/extension-downloader.json"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_from_file_system() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = r#"
+            [[extensions]]
+            browser = "chrome"
+            install_mode = "system"
+            names = ["foo"]
+
+            [[extensions]]
+            browser = "firefox"
+            install_mode = "system"
+            names = ["bar"]
+        "#;
+        fs::write(&path, contents).await.unwrap();
+
+        let cfg = from_file(&path).await.unwrap();
+        assert_eq!(cfg.extensions[0].install_mode, InstallMode::System);
+        assert_eq!(cfg.extensions[0].profile, "/opt/google/chrome/extensions");
+        assert_eq!(
+            cfg.extensions[1].profile,
+            "/usr/lib/mozilla/extensions/{ec8030f7-c20a-464f-9b0e-13a3a9e97384}"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_from_file_sandboxed_presets() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = r#"
+            [[extensions]]
+            browser = "firefox-flatpak"
+            profile = "/tmp/firefox"
+            names = ["foo"]
+
+            [[extensions]]
+            browser = "chromium-snap"
+            profile = "/tmp/chromium"
+            link_mode = "hardlink"
+            names = ["bar"]
+
+            [[extensions]]
+            browser = "firefox"
+            profile = "/tmp/default"
+            names = ["foo"]
+        "#;
+        fs::write(&path, contents).await.unwrap();
+
+        let cfg = from_file(&path).await.unwrap();
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(cfg.extensions[0].browser, BrowserKind::Firefox);
+        assert_eq!(cfg.extensions[0].link_mode, LinkMode::Copy);
+        assert_eq!(
+            cfg.extensions[0].user_data_dir.as_deref(),
+            home.join(".var/app/org.mozilla.firefox/.mozilla/firefox")
+                .to_str()
+        );
+        assert_eq!(cfg.extensions[1].browser, BrowserKind::Chromium);
+        assert_eq!(cfg.extensions[1].link_mode, LinkMode::Hardlink);
+        assert_eq!(
+            cfg.extensions[1].user_data_dir.as_deref(),
+            home.join("snap/chromium/common/chromium").to_str()
+        );
+        assert_eq!(cfg.extensions[2].link_mode, LinkMode::Symlink);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_conflicting_link_modes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = r#"
+            [[extensions]]
+            browser = "firefox"
+            profile = "/tmp/a"
+            link_mode = "copy"
+            names = ["foo"]
+
+            [[extensions]]
+            browser = "firefox"
+            profile = "/tmp/a"
+            names = ["bar"]
+        "#;
+        fs::write(&path, contents).await.unwrap();
+
+        // only the conflicting entry fails
+        let (cfg, unresolved) = resolve(&from_file(&path).await.unwrap()).await;
+        assert_eq!(cfg.extensions.len(), 1);
+        assert_eq!(cfg.extensions[0].link_mode, LinkMode::Copy);
+        assert_eq!(unresolved.len(), 1);
+        assert_eq!(unresolved[0].0.names[0].name(), "bar");
+        assert_eq!(
+            unresolved[0].1.to_string(),
+            "firefox /tmp/a: conflicting link modes copy and symlink"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_from_file_unpacked() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let contents = r#"
+            [[extensions]]
+            browser = "chromium"
+            install_mode = "unpacked"
+            profile = "/opt/kiosk/extensions"
+            names = ["foo"]
+        "#;
+        fs::write(&path, contents).await.unwrap();
+
+        let cfg = from_file(&path).await.unwrap();
+        assert_eq!(cfg.extensions[0].install_mode, InstallMode::Unpacked);
+        assert_eq!(cfg.extensions[0].profile, "/opt/kiosk/extensions");
+        assert!(!cfg.extensions[0].install_mode.is_system_wide());
     }
 
     #[test]
@@ -0,0 +1,245 @@
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+/// The file Firefox reads enterprise policies from, see
+/// https://mozilla.github.io/policy-templates/
+pub const POLICIES_FILE: &str = "policies.json";
+
+/// The system-wide policies file read by Firefox on Linux.
+const DEFAULT_POLICIES_PATH: &str = "/etc/firefox/policies/policies.json";
+
+/// Extensions are installed concurrently, so updates of a policies file are serialized.
+static LOCK: Mutex<()> = Mutex::const_new(());
+
+/// is_policies_file returns whether the configured profile names a policies file rather than the
+/// directory containing it.
+fn is_policies_file(target: &str) -> bool {
+    Path::new(target)
+        .file_name()
+        .is_some_and(|name| name == POLICIES_FILE)
+}
+
+/// policies_path returns the policies file the `profile` of a config entry refers to: either
+/// the file itself, the directory containing it (e.g. Firefox's `distribution` directory) or
+/// `default` for the system-wide file.
+pub fn policies_path(profile: &str) -> Result<String> {
+    if profile == crate::profiles::DEFAULT_PROFILE {
+        if !cfg!(target_os = "linux") {
+            return Err(anyhow!(
+                "install_mode 'policies' requires the path of the policies.json as profile"
+            ));
+        }
+        return Ok(DEFAULT_POLICIES_PATH.to_string());
+    }
+    if is_policies_file(profile) {
+        return Ok(profile.to_string());
+    }
+    Ok(Path::new(profile)
+        .join(POLICIES_FILE)
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// install force-installs the XPI file from its `file://` URL, replacing the settings of the
+/// extension. All other policies are kept.
+pub async fn install(xpi_file: &Path, guid: &str, policies_file: &Path) -> Result<()> {
+    let _guard = LOCK.lock().await;
+    let mut policies = read(policies_file).await?;
+    let settings = json!({
+        "installation_mode": "force_installed",
+        "install_url": file_url(xpi_file)?,
+    });
+    let extensions = extension_settings(&mut policies, policies_file)?;
+    if extensions.get(guid) == Some(&settings) {
+        debug!("{:?} is already installed in {:?}", xpi_file, policies_file);
+        return Ok(());
+    }
+    info!("Installing {:?} in {:?}", xpi_file, policies_file);
+    extensions.insert(guid.to_string(), settings);
+    write(policies_file, &policies).await
+}
+
+/// uninstall removes the settings of all extensions installed from `xpi_file`.
+pub async fn uninstall(xpi_file: &Path, policies_file: &Path) -> Result<()> {
+    let _guard = LOCK.lock().await;
+    if fs::metadata(policies_file).await.is_err() {
+        return Ok(());
+    }
+    let mut policies = read(policies_file).await?;
+    let url = file_url(xpi_file)?;
+    let extensions = extension_settings(&mut policies, policies_file)?;
+    let count = extensions.len();
+    extensions.retain(|_, settings| settings["install_url"] != url.as_str());
+    if extensions.len() == count {
+        return Ok(());
+    }
+    debug!("Removing {:?} from {:?}", xpi_file, policies_file);
+    write(policies_file, &policies).await
+}
+
+/// installed_extensions returns the files of all extensions installed from a `file://` URL.
+pub async fn installed_extensions(policies_file: &Path) -> Result<Vec<PathBuf>> {
+    if fs::metadata(policies_file).await.is_err() {
+        return Ok(Vec::new());
+    }
+    let mut policies = read(policies_file).await?;
+    Ok(extension_settings(&mut policies, policies_file)?
+        .values()
+        .filter_map(|settings| settings["install_url"].as_str())
+        .filter_map(|url| Url::parse(url).ok()?.to_file_path().ok())
+        .collect())
+}
+
+/// is_installed returns whether the XPI file is force-installed by the policies file.
+pub async fn is_installed(xpi_file: &Path, guid: &str, policies_file: &Path) -> bool {
+    let Ok(mut policies) = read(policies_file).await else {
+        return false;
+    };
+    let Ok(url) = file_url(xpi_file) else {
+        return false;
+    };
+    extension_settings(&mut policies, policies_file).is_ok_and(|extensions| {
+        extensions.get(guid).is_some_and(|settings| {
+            settings["installation_mode"] == "force_installed" && settings["install_url"] == url
+        })
+    })
+}
+
+/// extension_settings returns the `ExtensionSettings` policy, which is added if missing.
+fn extension_settings<'a>(
+    policies: &'a mut Value,
+    policies_file: &Path,
+) -> Result<&'a mut Map<String, Value>> {
+    policies
+        .as_object_mut()
+        .map(|root| root.entry("policies").or_insert_with(|| json!({})))
+        .and_then(Value::as_object_mut)
+        .map(|policies| {
+            policies
+                .entry("ExtensionSettings")
+                .or_insert_with(|| json!({}))
+        })
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| anyhow!("Unexpected structure of {:?}", policies_file))
+}
+
+fn file_url(path: &Path) -> Result<String> {
+    Url::from_file_path(path)
+        .map(String::from)
+        .map_err(|_| anyhow!("{:?} is not an absolute path", path))
+}
+
+/// read reads a JSON policies file, which is empty if missing.
+pub async fn read(path: &Path) -> Result<Value> {
+    match fs::read(path).await {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map_err(|err| anyhow!("Failed to parse {:?}: {}", path, err)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(json!({})),
+        Err(err) => Err(anyhow!("Failed to read {:?}: {}", path, err)),
+    }
+}
+
+/// write replaces a JSON policies file atomically.
+pub async fn write(path: &Path, policies: &Value) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(policies)?).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[test]
+    fn test_policies_path() {
+        assert_eq!(
+            policies_path("/usr/lib/firefox/distribution").unwrap(),
+            "/usr/lib/firefox/distribution/policies.json"
+        );
+        assert_eq!(
+            policies_path("/etc/firefox/policies/policies.json").unwrap(),
+            "/etc/firefox/policies/policies.json"
+        );
+        #[cfg(target_os = "linux")]
+        assert_eq!(policies_path("default").unwrap(), DEFAULT_POLICIES_PATH);
+        assert!(is_policies_file("/etc/firefox/policies/policies.json"));
+        assert!(!is_policies_file(
+            "/home/user/.mozilla/firefox/abcd.default"
+        ));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[tokio::test]
+    async fn test_install() {
+        let dir = TempDir::new().unwrap();
+        let policies_file = dir.path().join("distribution").join(POLICIES_FILE);
+        let xpi_file = dir.path().join("firefox").join("foo@example.com.xpi");
+        let other_file = dir.path().join("firefox").join("bar@example.com.xpi");
+
+        // policies not managed by us are kept
+        fs::create_dir_all(policies_file.parent().unwrap())
+            .await
+            .unwrap();
+        fs::write(
+            &policies_file,
+            r#"{"policies": {"DisableTelemetry": true, "ExtensionSettings": {"*": {"installation_mode": "blocked"}}}}"#,
+        )
+        .await
+        .unwrap();
+
+        assert!(!is_installed(&xpi_file, "foo@example.com", &policies_file).await);
+        install(&xpi_file, "foo@example.com", &policies_file)
+            .await
+            .unwrap();
+        install(&other_file, "bar@example.com", &policies_file)
+            .await
+            .unwrap();
+        assert!(is_installed(&xpi_file, "foo@example.com", &policies_file).await);
+
+        // installing again doesn't change anything
+        let contents = fs::read(&policies_file).await.unwrap();
+        install(&xpi_file, "foo@example.com", &policies_file)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&policies_file).await.unwrap(), contents);
+
+        let policies = read(&policies_file).await.unwrap();
+        assert_eq!(policies["policies"]["DisableTelemetry"], true);
+        assert_eq!(
+            policies["policies"]["ExtensionSettings"]["foo@example.com"],
+            json!({
+                "installation_mode": "force_installed",
+                "install_url": format!("file://{}", xpi_file.display()),
+            })
+        );
+        let mut installed = installed_extensions(&policies_file).await.unwrap();
+        installed.sort();
+        assert_eq!(installed, [other_file.clone(), xpi_file.clone()]);
+
+        uninstall(&xpi_file, &policies_file).await.unwrap();
+        assert_eq!(
+            installed_extensions(&policies_file).await.unwrap(),
+            [other_file]
+        );
+        let policies = read(&policies_file).await.unwrap();
+        assert_eq!(
+            policies["policies"]["ExtensionSettings"]["*"]["installation_mode"],
+            "blocked"
+        );
+
+        // uninstalling from a missing file is a no-op
+        uninstall(&xpi_file, &dir.path().join("missing").join(POLICIES_FILE))
+            .await
+            .unwrap();
+    }
+}
@@ -2,15 +2,23 @@ use anyhow::{anyhow, Result};
 use reqwest_middleware::ClientWithMiddleware;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256, Sha512};
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use tokio::{
-    fs::{self, File},
-    io::AsyncWriteExt,
-    task::JoinSet,
-};
+use std::sync::Arc;
+use tokio::{fs, task::JoinSet};
 use tracing::{debug, info, warn};
 
-use crate::manifest;
+use crate::artifact::{self, Artifact};
+use crate::checks::Checks;
+use crate::config::{ExtensionName, InstallMode, LinkMode, Target};
+use crate::enterprise;
+use crate::link;
+use crate::manifest::{self, compare_versions, satisfies_max_version, Manifest};
+use crate::plan::{Change, Plan};
+use crate::profiles;
+use crate::unpacked;
+use crate::xpi::{self, TrustAnchor};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Extension {
@@ -22,6 +30,15 @@ struct Extension {
 struct Metadata {
     version: String,
     files: Vec<Src>,
+    /// The application versions the version supports, keyed by application (e.g. `firefox`).
+    #[serde(default)]
+    compatibility: HashMap<String, Compatibility>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Compatibility {
+    min: Option<String>,
+    max: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -32,63 +49,95 @@ struct Src {
 
 const DEFAULT_BASE_URL_MOZILLA: &str = "https://services.addons.mozilla.org";
 
+/// install downloads the extension (if needed) and links it into all given profiles.
+///
+/// * `extension` - The name of the extension and its pinned version, if any.
+/// * `anchor` - The CA certificate the extension's signature must chain up to.
+/// * `checks` - The checks the downloaded extension must pass.
 pub async fn install(
     client: ClientWithMiddleware,
     base_url: Option<String>,
-    name: String,
+    extension: ExtensionName,
+    anchor: Arc<TrustAnchor>,
+    checks: Checks,
     dest_dir: PathBuf,
-    profiles: Vec<String>,
-) -> Result<Option<PathBuf>> {
-    let xpi_path =
-        download_extension(client.clone(), base_url, name.to_string(), &dest_dir).await?;
+    profiles: Vec<Target>,
+) -> Result<Option<Artifact>> {
+    let (artifact, manifest) = download_extension(
+        client.clone(),
+        base_url,
+        extension.name().to_string(),
+        extension.version(),
+        &anchor,
+        &checks,
+        &dest_dir,
+    )
+    .await?;
+    let xpi_path = artifact.path.clone();
+    for p in &profiles {
+        warn_incompatible(&manifest, &p.profile).await;
+    }
 
     let mut set = JoinSet::new();
     for p in profiles {
-        set.spawn(install_extension(xpi_path.clone(), p));
+        set.spawn(install_extension(xpi_path.clone(), artifact.id.clone(), p));
     }
     while let Some(res) = set.join_next().await {
         res??;
     }
-    Ok(Some(xpi_path))
+    Ok(Some(artifact))
 }
 
 async fn download_extension(
     client: ClientWithMiddleware,
     base_url: Option<String>,
     name: String,
+    version: Option<&str>,
+    anchor: &TrustAnchor,
+    checks: &Checks,
     dest_dir: &Path,
-) -> Result<PathBuf> {
+) -> Result<(Artifact, Manifest)> {
     debug!("Downloading Firefox extension {name}");
 
-    let base_url: String = base_url.unwrap_or(DEFAULT_BASE_URL_MOZILLA.to_string());
-    let url = format!("{base_url}/api/v4/addons/addon/{name}/");
-    debug!("Fetching metadata from {url}");
-    let ext: Extension = client.get(url).send().await?.json().await?;
-    debug!("Successfully parsed metadata");
+    // a locked extension is installed exactly as locked instead of the current version
+    let locked_version = checks.locked.as_ref().map(|locked| locked.version.as_str());
+    let ext = fetch_metadata(&client, base_url, &name, locked_version.or(version)).await?;
 
-    fs::create_dir_all(&dest_dir).await?;
     let destination = dest_dir.join(format!("{}.xpi", ext.guid));
-    let new_version = ext.current_version.version;
+    let new_version = ext.current_version.version.clone();
+    let url = match &checks.locked {
+        Some(locked) if !locked.url.is_empty() => locked.url.clone(),
+        _ => ext.current_version.files[0].url.clone(),
+    };
 
-    if fs::metadata(&destination).await.is_ok() {
-        let mf = manifest::from_file(&destination).await?;
-        let old_version = mf.version;
-        if old_version == new_version {
+    let old_manifest = match fs::metadata(&destination).await {
+        Ok(_) => Some(manifest::from_file(&destination, checks.locale.as_deref()).await?),
+        Err(_) => None,
+    };
+    let browser_version = checks.browser_version.as_deref();
+    if let Err(err) = check_compatible(&name, &ext.current_version, browser_version) {
+        return keep_compatible(err, &name, destination, ext.guid, old_manifest, checks).await;
+    }
+    if let Some(mf) = old_manifest.as_ref() {
+        let old_version = &mf.version;
+        if *old_version == new_version {
             debug!(
                 "{name} {old_version} already up-to-date ({})",
                 dest_dir.to_string_lossy()
             );
-            return Ok(destination);
+            let artifact = cached_artifact(destination, ext.guid, &name, mf, url, checks).await?;
+            return Ok((artifact, old_manifest.unwrap()));
+        }
+        match version {
+            Some(_) => info!("{name}: switching {old_version} -> pinned {new_version}"),
+            None => info!("{name}: updating {old_version} -> {new_version}"),
         }
-        info!("{name}: updating {old_version} -> {new_version}");
     } else {
         debug!("Downloading Firefox extension {name} {new_version}");
     }
 
-    let url = &ext.current_version.files[0].url;
-
     debug!("Downloading Firefox extension from {url}");
-    let body = client.get(url).send().await?.bytes().await?;
+    let body = client.get(&url).send().await?.bytes().await?;
 
     let mut split = ext.current_version.files[0].hash.split(':');
     let algo = split.next().unwrap();
@@ -103,48 +152,370 @@ async fn download_extension(
         debug!("Hash verified successfully");
     }
 
-    let mut file = File::create(&destination).await?;
-    file.write_all(&body).await?;
-    file.flush().await?;
-    std::mem::drop(file);
+    xpi::verify(&body, &ext.guid, anchor)?;
+    let manifest = manifest::from_bytes(&body, checks.locale.as_deref())?;
+    check_gecko_id(&name, &manifest, &ext.guid)?;
+    if let Err(err) = check_manifest_compatible(&name, &manifest, browser_version) {
+        return keep_compatible(err, &name, destination, ext.guid, old_manifest, checks).await;
+    }
+    checks.check_manifest(&name, old_manifest.as_ref(), &manifest)?;
+
+    let artifact = Artifact {
+        path: destination.clone(),
+        id: ext.guid,
+        version: new_version,
+        url,
+        sha256: format!("{:x}", Sha256::digest(&body)),
+    };
+    if let Some(locked) = &checks.locked {
+        locked.check_artifact(&artifact)?;
+    }
 
-    Ok(destination)
+    artifact::store(&destination, &body).await?;
+    Ok((artifact, manifest))
 }
 
-async fn install_extension(xpi_file: PathBuf, profile_dir: String) -> Result<()> {
-    let ext_dir = PathBuf::from(profile_dir).join("extensions");
-    let fname = xpi_file.file_name().unwrap();
+/// check_gecko_id fails if the add-on ID declared by the manifest differs from the one reported
+/// by addons.mozilla.org.
+fn check_gecko_id(name: &str, manifest: &Manifest, guid: &str) -> Result<()> {
+    match manifest.gecko_id() {
+        Some(id) if id != guid => Err(anyhow!(
+            "{name}: manifest declares add-on ID {id}, but addons.mozilla.org reports {guid}"
+        )),
+        _ => Ok(()),
+    }
+}
 
-    let bak = ext_dir.join(format!("{}.bak", fname.to_str().unwrap()));
-    let dst = ext_dir.join(fname);
+/// cached_artifact returns the stored version of the extension after checking it like a download.
+async fn cached_artifact(
+    destination: PathBuf,
+    guid: String,
+    name: &str,
+    manifest: &Manifest,
+    url: String,
+    checks: &Checks,
+) -> Result<Artifact> {
+    let artifact = Artifact {
+        sha256: artifact::sha256(&destination).await?,
+        path: destination,
+        id: guid,
+        version: manifest.version.clone(),
+        url,
+    };
+    if let Some(locked) = &checks.locked {
+        locked.check_artifact(&artifact)?;
+    }
+    checks.policy.check(name, manifest)?;
+    Ok(artifact)
+}
 
-    // check if xpi file already points to dst
-    if let Ok(link) = fs::read_link(&dst).await {
-        if link == xpi_file {
-            debug!("{:?} is already installed in {:?}", xpi_file, dst);
-            return Ok(());
+/// keep_compatible keeps the stored version of an extension instead of an incompatible update
+/// if the browser can load it, and fails with `err` otherwise.
+async fn keep_compatible(
+    err: anyhow::Error,
+    name: &str,
+    destination: PathBuf,
+    guid: String,
+    old_manifest: Option<Manifest>,
+    checks: &Checks,
+) -> Result<(Artifact, Manifest)> {
+    let browser_version = checks.browser_version.as_deref();
+    match old_manifest {
+        Some(mf) if check_manifest_compatible(name, &mf, browser_version).is_ok() => {
+            warn!("{err}, keeping {}", mf.version);
+            let artifact =
+                cached_artifact(destination, guid, name, &mf, String::new(), checks).await?;
+            Ok((artifact, mf))
         }
+        _ => Err(err),
     }
+}
 
-    info!("Installing {:?} as {:?}", xpi_file, dst);
-    fs::create_dir_all(&ext_dir).await?;
-    create_symlink(&xpi_file, &bak).await?;
-    fs::rename(&bak, &dst).await?;
+/// check_compatible fails if the Firefox version which loads the extension is outside of the
+/// range addons.mozilla.org reports for the version.
+fn check_compatible(name: &str, metadata: &Metadata, firefox_version: Option<&str>) -> Result<()> {
+    let Some(firefox_version) = firefox_version else {
+        return Ok(());
+    };
+    if metadata.compatibility.is_empty() {
+        return Ok(());
+    }
+    let version = &metadata.version;
+    let Some(range) = metadata.compatibility.get("firefox") else {
+        return Err(anyhow!("{name}: {version} does not support Firefox"));
+    };
+    if let Some(min) = &range.min {
+        if compare_versions(firefox_version, min) == Ordering::Less {
+            return Err(anyhow!(
+                "{name}: {version} requires Firefox {min} or newer, but Firefox {firefox_version} loads it"
+            ));
+        }
+    }
+    if let Some(max) = &range.max {
+        if !satisfies_max_version(firefox_version, max) {
+            return Err(anyhow!(
+                "{name}: {version} requires Firefox {max} or older, but Firefox {firefox_version} loads it"
+            ));
+        }
+    }
     Ok(())
 }
 
-#[cfg(target_os = "windows")]
-async fn create_symlink(src: &Path, dst: &Path) -> Result<()> {
-    fs::symlink_file(src, dst).await?;
+/// check_manifest_compatible fails if the Firefox version which loads the extension can't load
+/// it according to its manifest.
+fn check_manifest_compatible(
+    name: &str,
+    manifest: &Manifest,
+    firefox_version: Option<&str>,
+) -> Result<()> {
+    let Some(firefox_version) = firefox_version else {
+        return Ok(());
+    };
+    match manifest.firefox_incompatibility(firefox_version) {
+        Some(reason) => Err(anyhow!(
+            "{name}: {manifest} {reason}, but Firefox {firefox_version} loads it"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// warn_incompatible warns if the Firefox version which last used the profile can't load the
+/// extension.
+async fn warn_incompatible(manifest: &Manifest, profile_dir: &str) {
+    let Some(version) = profiles::firefox_version(Path::new(profile_dir)).await else {
+        return;
+    };
+    if let Some(reason) = manifest.firefox_incompatibility(&version) {
+        warn!("{manifest} {reason}, but {profile_dir} was last used by Firefox {version}");
+    }
+}
+
+/// plan determines the changes `install` would perform without downloading or installing anything.
+///
+/// * `browser_version` - The Firefox version the extension must support, if known.
+pub async fn plan(
+    client: ClientWithMiddleware,
+    base_url: Option<String>,
+    extension: ExtensionName,
+    browser_version: Option<String>,
+    dest_dir: PathBuf,
+    profiles: Vec<Target>,
+) -> Result<Plan> {
+    let name = extension.name().to_string();
+    let ext = fetch_metadata(&client, base_url, &name, extension.version()).await?;
+    let destination = dest_dir.join(format!("{}.xpi", ext.guid));
+    let compatible = check_compatible(&name, &ext.current_version, browser_version.as_deref());
+    let mut new_version = ext.current_version.version;
+
+    let mut changes = Vec::new();
+    let mut stored_version = None;
+    if fs::metadata(&destination).await.is_ok() {
+        let old_version = manifest::from_file(&destination, None).await?.version;
+        stored_version = Some(old_version.clone());
+        if let Err(err) = compatible {
+            // the stored version is kept, see `keep_compatible`
+            warn!("{err}, keeping {old_version}");
+            new_version = old_version.clone();
+        }
+        if old_version != new_version {
+            changes.push(Change::Update {
+                name: name.clone(),
+                from: old_version,
+                to: new_version.clone(),
+                path: destination.clone(),
+            });
+        }
+    } else {
+        compatible?;
+        changes.push(Change::Download {
+            name: name.clone(),
+            version: new_version.clone(),
+            path: destination.clone(),
+        });
+    }
+
+    for p in profiles {
+        if p.install_mode == InstallMode::Policies {
+            if !enterprise::is_installed(&destination, &ext.guid, Path::new(&p.profile)).await {
+                changes.push(Change::Install {
+                    location: PathBuf::from(p.profile),
+                    source: destination.clone(),
+                    version: new_version.clone(),
+                });
+            }
+            continue;
+        }
+        if p.install_mode == InstallMode::Unpacked {
+            let dir = Path::new(&p.profile);
+            if !unpacked::is_installed(&destination, &ext.guid, &new_version, dir).await {
+                changes.push(Change::Install {
+                    location: unpacked::current_dir(&ext.guid, dir),
+                    source: destination.clone(),
+                    version: new_version.clone(),
+                });
+            }
+            continue;
+        }
+        let dst = extensions_dir(&p).join(destination.file_name().unwrap());
+        let mode = p.link_mode;
+        if !link::is_placed(&destination, &dst, mode).await {
+            changes.push(Change::Install {
+                location: dst,
+                source: destination.clone(),
+                version: new_version.clone(),
+            });
+            continue;
+        }
+        // hardlinks and copies keep the content of the replaced file
+        match &stored_version {
+            Some(from) if mode != LinkMode::Symlink && *from != new_version => {
+                changes.push(Change::Upgrade {
+                    location: dst,
+                    from: from.clone(),
+                    to: new_version.clone(),
+                })
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Plan {
+        file: Some(destination),
+        changes,
+    })
+}
+
+/// fetch_metadata queries the addons.mozilla.org API for the given extension.
+///
+/// The metadata describes the current version unless a (pinned or locked) `version` is given,
+/// which is resolved through the versions API instead.
+async fn fetch_metadata(
+    client: &ClientWithMiddleware,
+    base_url: Option<String>,
+    name: &str,
+    version: Option<&str>,
+) -> Result<Extension> {
+    let base_url: String = base_url.unwrap_or(DEFAULT_BASE_URL_MOZILLA.to_string());
+    let url = format!("{base_url}/api/v4/addons/addon/{name}/");
+    debug!("Fetching metadata from {url}");
+    let mut ext: Extension = client.get(url).send().await?.json().await?;
+    debug!("Successfully parsed metadata");
+
+    if let Some(version) = version {
+        let url = format!("{base_url}/api/v4/addons/addon/{name}/versions/{version}/");
+        debug!("Fetching metadata of pinned version from {url}");
+        let response = client.get(url).send().await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(anyhow!(
+                "{name}: version {version} does not exist on addons.mozilla.org"
+            ));
+        }
+        let metadata: Metadata = response.error_for_status()?.json().await?;
+        if metadata.version != version {
+            return Err(anyhow!(
+                "{name}: requested version {version}, got {}",
+                metadata.version
+            ));
+        }
+        ext.current_version = metadata;
+    }
+    Ok(ext)
+}
+
+/// install_file links (or copies) a stored XPI file into the given profile, replacing the installed version
+/// of the extension (if any).
+pub async fn install_file(xpi_file: &Path, guid: &str, target: &Target) -> Result<()> {
+    install_extension(xpi_file.to_path_buf(), guid.to_string(), target.clone()).await
+}
+
+async fn install_extension(xpi_file: PathBuf, guid: String, target: Target) -> Result<()> {
+    let profile_dir = target.profile.as_str();
+    if target.install_mode == InstallMode::Policies {
+        return enterprise::install(&xpi_file, &guid, Path::new(profile_dir)).await;
+    }
+    if target.install_mode == InstallMode::Unpacked {
+        return unpacked::install(&xpi_file, &guid, Path::new(profile_dir)).await;
+    }
+    let dst = extensions_dir(&target).join(format!("{guid}.xpi"));
+    let mode = target.link_mode;
+    if link::place(&xpi_file, &dst, mode).await? {
+        info!("Installing {:?} as {:?} ({mode})", xpi_file, dst);
+    }
     Ok(())
 }
 
-#[cfg(not(target_os = "windows"))]
-async fn create_symlink(src: &Path, dst: &Path) -> Result<()> {
-    fs::symlink(src, dst).await?;
+/// is_installed returns whether the given version of a stored XPI file is installed in the
+/// profile.
+pub async fn is_installed(xpi_file: &Path, guid: &str, version: &str, target: &Target) -> bool {
+    let profile_dir = target.profile.as_str();
+    if target.install_mode == InstallMode::Policies {
+        return enterprise::is_installed(xpi_file, guid, Path::new(profile_dir)).await;
+    }
+    if target.install_mode == InstallMode::Unpacked {
+        return unpacked::is_installed(xpi_file, guid, version, Path::new(profile_dir)).await;
+    }
+    let dst = extensions_dir(target).join(format!("{guid}.xpi"));
+    link::is_placed(xpi_file, &dst, target.link_mode).await
+}
+
+/// uninstall_extension removes the links to `xpi_file` and its copies from the given profile.
+pub async fn uninstall_extension(xpi_file: &Path, target: &Target) -> Result<()> {
+    let profile_dir = target.profile.as_str();
+    if target.install_mode == InstallMode::Policies {
+        return enterprise::uninstall(xpi_file, Path::new(profile_dir)).await;
+    }
+    if target.install_mode == InstallMode::Unpacked {
+        return unpacked::uninstall(xpi_file, Path::new(profile_dir)).await;
+    }
+    let ext_dir = extensions_dir(target);
+    let mut read_dir = match fs::read_dir(&ext_dir).await {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = read_dir.next_entry().await? {
+        if link::refers_to(&entry.path(), xpi_file).await {
+            debug!("Removing {:?}", entry.path());
+            fs::remove_file(entry.path()).await?;
+        }
+    }
     Ok(())
 }
 
+/// installed_extensions returns the targets of all extension symlinks in the given profile.
+/// Hardlinks and copies don't reference a stored file and are not returned.
+pub async fn installed_extensions(target: &Target) -> Result<Vec<PathBuf>> {
+    let profile_dir = target.profile.as_str();
+    if target.install_mode == InstallMode::Policies {
+        return enterprise::installed_extensions(Path::new(profile_dir)).await;
+    }
+    if target.install_mode == InstallMode::Unpacked {
+        return unpacked::installed_extensions(Path::new(profile_dir)).await;
+    }
+    let ext_dir = extensions_dir(target);
+    let mut result = Vec::new();
+    let mut read_dir = match fs::read_dir(&ext_dir).await {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(result),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = read_dir.next_entry().await? {
+        if let Ok(target) = fs::read_link(entry.path()).await {
+            result.push(target);
+        }
+    }
+    Ok(result)
+}
+
+/// extensions_dir returns the directory the extensions of a target are linked into: the system
+/// extension directory itself or the `extensions` directory of a profile.
+fn extensions_dir(target: &Target) -> PathBuf {
+    if target.install_mode == InstallMode::System {
+        return PathBuf::from(&target.profile);
+    }
+    PathBuf::from(&target.profile).join("extensions")
+}
+
 fn compute_hash(algo: &str, content: &[u8]) -> Option<String> {
     match algo {
         "sha256" => {
@@ -167,19 +538,130 @@ fn compute_hash(algo: &str, content: &[u8]) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lockfile::LockedExtension;
     use reqwest_middleware::ClientBuilder;
     use temp_dir::TempDir;
 
+    async fn test_anchor() -> TrustAnchor {
+        crate::xpi::load_trust_anchor("tests/fixtures/test-root-ca.pem")
+            .await
+            .unwrap()
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[tokio::test]
+    async fn test_uninstall_extension() {
+        let dir = TempDir::new().unwrap();
+        let xpi_file = dir.path().join("storage").join("foo.xpi");
+        let other_file = dir.path().join("storage").join("bar.xpi");
+        let profile = Target::from(dir.path().join("profile").to_str().unwrap());
+        install_extension(xpi_file.clone(), "foo".to_string(), profile.clone())
+            .await
+            .unwrap();
+        install_extension(other_file.clone(), "bar".to_string(), profile.clone())
+            .await
+            .unwrap();
+
+        uninstall_extension(&xpi_file, &profile).await.unwrap();
+        assert_eq!(
+            installed_extensions(&profile).await.unwrap(),
+            std::slice::from_ref(&other_file)
+        );
+
+        // an older version replaces the installed one
+        let older = dir.path().join("versions").join("bar").join("0.9.xpi");
+        install_file(&older, "bar", &profile).await.unwrap();
+        assert_eq!(installed_extensions(&profile).await.unwrap(), [older]);
+
+        // uninstalling from a missing profile is a no-op
+        uninstall_extension(&xpi_file, &Target::from("/nonexistent"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_install_extension_copy() {
+        let dir = TempDir::new().unwrap();
+        let xpi_file = dir.path().join("storage").join("foo.xpi");
+        fs::create_dir_all(xpi_file.parent().unwrap())
+            .await
+            .unwrap();
+        fs::write(&xpi_file, b"1.0").await.unwrap();
+        let profile = Target {
+            link_mode: LinkMode::Copy,
+            ..Target::from(dir.path().join("profile").to_str().unwrap())
+        };
+
+        install_file(&xpi_file, "foo", &profile).await.unwrap();
+        let dst = Path::new(&profile.profile)
+            .join("extensions")
+            .join("foo.xpi");
+        assert!(fs::symlink_metadata(&dst).await.unwrap().is_file());
+        assert_eq!(fs::read(&dst).await.unwrap(), b"1.0");
+        // copies don't reference the stored file
+        assert!(installed_extensions(&profile).await.unwrap().is_empty());
+
+        uninstall_extension(&xpi_file, &profile).await.unwrap();
+        assert!(fs::metadata(&dst).await.is_err());
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[tokio::test]
+    async fn test_install_file_policies() {
+        let dir = TempDir::new().unwrap();
+        let xpi_file = dir.path().join("storage").join("foo.xpi");
+        // the install mode decides, not the file name
+        let target = Target {
+            install_mode: InstallMode::Policies,
+            ..Target::from(dir.path().join("firefox.json").to_str().unwrap())
+        };
+
+        install_file(&xpi_file, "foo", &target).await.unwrap();
+        assert!(is_installed(&xpi_file, "foo", "1.0", &target).await);
+        assert_eq!(
+            installed_extensions(&target).await.unwrap(),
+            std::slice::from_ref(&xpi_file)
+        );
+        assert!(!dir.path().join("extensions").exists());
+
+        uninstall_extension(&xpi_file, &target).await.unwrap();
+        assert!(installed_extensions(&target).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_install_file_unpacked() {
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path().join("unpacked").to_string_lossy().into_owned();
+        let target = Target {
+            install_mode: InstallMode::Unpacked,
+            ..Target::from(dir.as_str())
+        };
+        let xpi_file = Path::new("tests/fixtures/vimium_ff-2.1.2.xpi");
+        let guid = "{d7742d87-e61d-4b78-b8a1-b469842139fa}";
+        install_file(xpi_file, guid, &target).await.unwrap();
+
+        // the XPI file is extracted to <dir>/<guid>/<version>
+        let current = unpacked::current_dir(guid, Path::new(&dir));
+        assert!(current.join("manifest.json").exists());
+        assert_eq!(fs::read_link(&current).await.unwrap(), Path::new("2.1.2"));
+        assert!(is_installed(xpi_file, guid, "2.1.2", &target).await);
+        assert_eq!(installed_extensions(&target).await.unwrap(), [xpi_file]);
+
+        uninstall_extension(xpi_file, &target).await.unwrap();
+        assert!(installed_extensions(&target).await.unwrap().is_empty());
+    }
+
     #[tokio::test]
     async fn test_download_extension_already_exists_same_version() {
         let extension = Extension {
-            guid: "123-456-789-0".to_string(),
+            guid: "{d7742d87-e61d-4b78-b8a1-b469842139fa}".to_string(),
             current_version: Metadata {
                 version: "2.1.2".to_string(),
                 files: vec![Src {
                     url: "http://".to_string(),
                     hash: "sha256:dummy".to_string(),
                 }],
+                compatibility: HashMap::new(),
             },
         };
         let body = serde_json::to_string(&extension).unwrap();
@@ -206,6 +688,9 @@ mod tests {
             client,
             Some(server.url()),
             "dummy".to_string(),
+            None,
+            &test_anchor().await,
+            &Checks::default(),
             dest_dir.path(),
         )
         .await
@@ -219,14 +704,15 @@ mod tests {
         let mut server = mockito::Server::new_async().await;
 
         let extension = Extension {
-            guid: "123-456-789-0".to_string(),
+            guid: "{d7742d87-e61d-4b78-b8a1-b469842139fa}".to_string(),
             current_version: Metadata {
                 version: "2.1.0".to_string(), // different from version in xpi file
                 files: vec![Src {
                     url: format!("{}/vimium_ff-2.1.2.xpi", server.url()),
-                    hash: "sha256:3b9d43ee277ff374e3b1153f97dc20cb06e654116a833674c79b43b8887820e1"
+                    hash: "sha256:e6d90392951ac10fcff45d8f36573dda8de5889c067563d9799a9bcde59fde7d"
                         .to_string(),
                 }],
+                compatibility: HashMap::new(),
             },
         };
         let body = serde_json::to_string(&extension).unwrap();
@@ -241,7 +727,7 @@ mod tests {
         let m2 = server
             .mock("GET", "/vimium_ff-2.1.2.xpi")
             .with_header("content-type", "application/x-xpinstall")
-            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2.xpi")
+            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2-test-signed.xpi")
             .with_status(200)
             .create_async()
             .await;
@@ -259,6 +745,9 @@ mod tests {
             client,
             Some(server.url()),
             "dummy".to_string(),
+            None,
+            &test_anchor().await,
+            &Checks::default(),
             dest_dir.path(),
         )
         .await
@@ -273,7 +762,7 @@ mod tests {
         let mut server = mockito::Server::new_async().await;
 
         let extension = Extension {
-            guid: "123-456-789-0".to_string(),
+            guid: "{d7742d87-e61d-4b78-b8a1-b469842139fa}".to_string(),
             current_version: Metadata {
                 version: "2.1.0".to_string(), // different from version in xpi file
                 files: vec![Src {
@@ -281,6 +770,7 @@ mod tests {
                     hash: "sha256:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"
                         .to_string(),
                 }],
+                compatibility: HashMap::new(),
             },
         };
         let body = serde_json::to_string(&extension).unwrap();
@@ -295,7 +785,7 @@ mod tests {
         let m2 = server
             .mock("GET", "/vimium_ff-2.1.2.xpi")
             .with_header("content-type", "application/x-xpinstall")
-            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2.xpi")
+            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2-test-signed.xpi")
             .with_status(200)
             .create_async()
             .await;
@@ -308,11 +798,14 @@ mod tests {
             client,
             Some(server.url()),
             "dummy".to_string(),
+            None,
+            &test_anchor().await,
+            &Checks::default(),
             dest_dir.path(),
         )
         .await;
         assert!(result.is_err());
-        assert_eq!(result.err().unwrap().to_string(), "Hash mismatch! Expected aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa, found 3b9d43ee277ff374e3b1153f97dc20cb06e654116a833674c79b43b8887820e1");
+        assert_eq!(result.err().unwrap().to_string(), "Hash mismatch! Expected aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa, found e6d90392951ac10fcff45d8f36573dda8de5889c067563d9799a9bcde59fde7d");
 
         m1.assert_async().await;
         m2.assert_async().await;
@@ -323,13 +816,14 @@ mod tests {
         let mut server = mockito::Server::new_async().await;
 
         let extension = Extension {
-            guid: "123-456-789-0".to_string(),
+            guid: "{d7742d87-e61d-4b78-b8a1-b469842139fa}".to_string(),
             current_version: Metadata {
                 version: "2.1.0".to_string(), // different from version in xpi file
                 files: vec![Src {
                     url: format!("{}/vimium_ff-2.1.2.xpi", server.url()),
                     hash: "custom:xyz".to_string(),
                 }],
+                compatibility: HashMap::new(),
             },
         };
         let body = serde_json::to_string(&extension).unwrap();
@@ -344,7 +838,7 @@ mod tests {
         let m2 = server
             .mock("GET", "/vimium_ff-2.1.2.xpi")
             .with_header("content-type", "application/x-xpinstall")
-            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2.xpi")
+            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2-test-signed.xpi")
             .with_status(200)
             .create_async()
             .await;
@@ -357,6 +851,9 @@ mod tests {
             client,
             Some(server.url()),
             "dummy".to_string(),
+            None,
+            &test_anchor().await,
+            &Checks::default(),
             dest_dir.path(),
         )
         .await;
@@ -366,6 +863,422 @@ mod tests {
         m2.assert_async().await;
     }
 
+    #[tokio::test]
+    async fn test_download_extension_locked() {
+        let mut server = mockito::Server::new_async().await;
+
+        let metadata = Metadata {
+            version: "2.1.2".to_string(),
+            files: vec![Src {
+                url: format!("{}/vimium_ff-2.1.2.xpi", server.url()),
+                hash: "sha256:e6d90392951ac10fcff45d8f36573dda8de5889c067563d9799a9bcde59fde7d"
+                    .to_string(),
+            }],
+            compatibility: HashMap::new(),
+        };
+        // upstream moved on since the lockfile was written
+        let extension = Extension {
+            guid: "{d7742d87-e61d-4b78-b8a1-b469842139fa}".to_string(),
+            current_version: Metadata {
+                version: "2.2.0".to_string(),
+                files: vec![Src {
+                    url: format!("{}/vimium_ff-2.2.0.xpi", server.url()),
+                    hash: "sha256:0000".to_string(),
+                }],
+                compatibility: HashMap::new(),
+            },
+        };
+        let body = serde_json::to_string(&extension).unwrap();
+
+        let m1 = server
+            .mock("GET", "/api/v4/addons/addon/dummy/")
+            .with_header("content-type", "application/json")
+            .with_body(&body)
+            .with_status(200)
+            .expect(2)
+            .create_async()
+            .await;
+        let m2 = server
+            .mock("GET", "/vimium_ff-2.1.2.xpi")
+            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2-test-signed.xpi")
+            .with_status(200)
+            .create_async()
+            .await;
+        let m3 = server
+            .mock("GET", "/api/v4/addons/addon/dummy/versions/2.1.2/")
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::to_string(&metadata).unwrap())
+            .with_status(200)
+            .create_async()
+            .await;
+        let m4 = server
+            .mock("GET", "/api/v4/addons/addon/dummy/versions/2.1.0/")
+            .with_status(404)
+            .create_async()
+            .await;
+
+        let client = ClientBuilder::new(reqwest::Client::new()).build();
+        let dest_dir = TempDir::new().unwrap();
+
+        let mut locked = LockedExtension {
+            browser: crate::config::BrowserKind::Firefox,
+            name: "dummy".to_string(),
+            id: extension.guid.clone(),
+            version: "2.1.2".to_string(),
+            url: metadata.files[0].url.clone(),
+            sha256: "e6d90392951ac10fcff45d8f36573dda8de5889c067563d9799a9bcde59fde7d".to_string(),
+        };
+        let checks = Checks {
+            locked: Some(locked.clone()),
+            ..Default::default()
+        };
+        let (artifact, _) = download_extension(
+            client.clone(),
+            Some(server.url()),
+            "dummy".to_string(),
+            None,
+            &test_anchor().await,
+            &checks,
+            dest_dir.path(),
+        )
+        .await
+        .unwrap();
+        // the locked version is downloaded instead of the current one
+        assert_eq!(artifact.version, "2.1.2");
+        assert_eq!(artifact.sha256, locked.sha256);
+
+        // upstream no longer offers the locked version
+        locked.version = "2.1.0".to_string();
+        let checks = Checks {
+            locked: Some(locked),
+            ..Default::default()
+        };
+        let result = download_extension(
+            client,
+            Some(server.url()),
+            "dummy".to_string(),
+            None,
+            &test_anchor().await,
+            &checks,
+            dest_dir.path(),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "dummy: version 2.1.0 does not exist on addons.mozilla.org"
+        );
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+        m3.assert_async().await;
+        m4.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_download_extension_held() {
+        let mut server = mockito::Server::new_async().await;
+        let extension = Extension {
+            guid: "{d7742d87-e61d-4b78-b8a1-b469842139fa}".to_string(),
+            current_version: Metadata {
+                version: "2.1.2".to_string(),
+                files: vec![Src {
+                    url: format!("{}/vimium_ff-2.1.2.xpi", server.url()),
+                    hash: "sha256:e6d90392951ac10fcff45d8f36573dda8de5889c067563d9799a9bcde59fde7d"
+                        .to_string(),
+                }],
+                compatibility: HashMap::new(),
+            },
+        };
+        let m1 = server
+            .mock("GET", "/api/v4/addons/addon/dummy/")
+            .with_body(serde_json::to_string(&extension).unwrap())
+            .with_status(200)
+            .expect(2)
+            .create_async()
+            .await;
+        let m2 = server
+            .mock("GET", "/vimium_ff-2.1.2.xpi")
+            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2-test-signed.xpi")
+            .with_status(200)
+            .expect(2)
+            .create_async()
+            .await;
+
+        // the stored version only requests the storage permission
+        let dest_dir = TempDir::new().unwrap();
+        let destination = dest_dir.path().join(format!("{}.xpi", extension.guid));
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        writer
+            .start_file("manifest.json", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(
+            &mut writer,
+            br#"{"name": "Vimium", "version": "2.1.1", "permissions": ["storage"]}"#,
+        )
+        .unwrap();
+        let old = writer.finish().unwrap().into_inner();
+        fs::write(&destination, &old).await.unwrap();
+
+        let client = ClientBuilder::new(reqwest::Client::new()).build();
+        let mut checks = Checks {
+            hold_new_permissions: true,
+            ..Default::default()
+        };
+        let err = download_extension(
+            client.clone(),
+            Some(server.url()),
+            "dummy".to_string(),
+            None,
+            &test_anchor().await,
+            &checks,
+            dest_dir.path(),
+        )
+        .await
+        .unwrap_err();
+        let held = err.downcast_ref::<crate::checks::HeldUpdate>().unwrap();
+        assert_eq!(held.to, "2.1.2");
+        assert!(held.permissions.contains(&"<all_urls>".to_string()));
+        assert_eq!(fs::read(&destination).await.unwrap(), old);
+
+        // the approved version is installed
+        checks.approved_version = Some("2.1.2".to_string());
+        let (artifact, _) = download_extension(
+            client,
+            Some(server.url()),
+            "dummy".to_string(),
+            None,
+            &test_anchor().await,
+            &checks,
+            dest_dir.path(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(artifact.version, "2.1.2");
+        assert_ne!(fs::read(&destination).await.unwrap(), old);
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_download_extension_pinned() {
+        let mut server = mockito::Server::new_async().await;
+
+        let extension = Extension {
+            guid: "{d7742d87-e61d-4b78-b8a1-b469842139fa}".to_string(),
+            current_version: Metadata {
+                version: "2.1.3".to_string(),
+                files: vec![Src {
+                    url: format!("{}/vimium_ff-2.1.3.xpi", server.url()),
+                    hash: "sha256:dummy".to_string(),
+                }],
+                compatibility: HashMap::new(),
+            },
+        };
+        let pinned = Metadata {
+            version: "2.1.2".to_string(),
+            files: vec![Src {
+                url: format!("{}/vimium_ff-2.1.2.xpi", server.url()),
+                hash: "sha256:e6d90392951ac10fcff45d8f36573dda8de5889c067563d9799a9bcde59fde7d"
+                    .to_string(),
+            }],
+            compatibility: HashMap::new(),
+        };
+
+        let m1 = server
+            .mock("GET", "/api/v4/addons/addon/dummy/")
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::to_string(&extension).unwrap())
+            .with_status(200)
+            .expect(2)
+            .create_async()
+            .await;
+        let m2 = server
+            .mock("GET", "/api/v4/addons/addon/dummy/versions/2.1.2/")
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::to_string(&pinned).unwrap())
+            .with_status(200)
+            .create_async()
+            .await;
+        let m3 = server
+            .mock("GET", "/api/v4/addons/addon/dummy/versions/0.1/")
+            .with_status(404)
+            .create_async()
+            .await;
+        let m4 = server
+            .mock("GET", "/vimium_ff-2.1.2.xpi")
+            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2-test-signed.xpi")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let client = ClientBuilder::new(reqwest::Client::new()).build();
+        let dest_dir = TempDir::new().unwrap();
+
+        let (artifact, _) = download_extension(
+            client.clone(),
+            Some(server.url()),
+            "dummy".to_string(),
+            Some("2.1.2"),
+            &test_anchor().await,
+            &Checks::default(),
+            dest_dir.path(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(artifact.version, "2.1.2");
+
+        let result = download_extension(
+            client,
+            Some(server.url()),
+            "dummy".to_string(),
+            Some("0.1"),
+            &test_anchor().await,
+            &Checks::default(),
+            dest_dir.path(),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "dummy: version 0.1 does not exist on addons.mozilla.org"
+        );
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+        m3.assert_async().await;
+        m4.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_download_extension_incompatible() {
+        let mut server = mockito::Server::new_async().await;
+        let extension = Extension {
+            guid: "{d7742d87-e61d-4b78-b8a1-b469842139fa}".to_string(),
+            current_version: Metadata {
+                version: "2.1.3".to_string(),
+                files: vec![Src {
+                    url: format!("{}/vimium_ff-2.1.3.xpi", server.url()),
+                    hash: "sha256:dummy".to_string(),
+                }],
+                compatibility: serde_json::from_str(r#"{"firefox": {"min": "128.0", "max": "*"}}"#)
+                    .unwrap(),
+            },
+        };
+        let m1 = server
+            .mock("GET", "/api/v4/addons/addon/dummy/")
+            .with_body(serde_json::to_string(&extension).unwrap())
+            .with_status(200)
+            .expect(3)
+            .create_async()
+            .await;
+
+        let client = ClientBuilder::new(reqwest::Client::new()).build();
+        let dest_dir = TempDir::new().unwrap();
+        let mut checks = Checks {
+            browser_version: Some("115.12.0".to_string()),
+            ..Default::default()
+        };
+
+        // nothing is installed if no compatible version is stored
+        let result = download_extension(
+            client.clone(),
+            Some(server.url()),
+            "dummy".to_string(),
+            None,
+            &test_anchor().await,
+            &checks,
+            dest_dir.path(),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "dummy: 2.1.3 requires Firefox 128.0 or newer, but Firefox 115.12.0 loads it"
+        );
+
+        // the stored version is kept
+        let to = dest_dir.path().join(format!("{}.xpi", extension.guid));
+        fs::copy("tests/fixtures/vimium_ff-2.1.2.xpi", to)
+            .await
+            .unwrap();
+        let (artifact, _) = download_extension(
+            client.clone(),
+            Some(server.url()),
+            "dummy".to_string(),
+            None,
+            &test_anchor().await,
+            &checks,
+            dest_dir.path(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(artifact.version, "2.1.2");
+
+        // unless the browser can't load it either
+        checks.browser_version = Some("102.0".to_string());
+        let result = download_extension(
+            client,
+            Some(server.url()),
+            "dummy".to_string(),
+            None,
+            &test_anchor().await,
+            &checks,
+            dest_dir.path(),
+        )
+        .await;
+        assert!(result.is_err());
+
+        m1.assert_async().await;
+    }
+
+    #[test]
+    fn test_check_compatible() {
+        let metadata: Metadata = serde_json::from_str(
+            r#"{"version": "1.0", "files": [], "compatibility": {"firefox": {"min": "91.0", "max": "128.*"}}}"#,
+        )
+        .unwrap();
+        check_compatible("foo", &metadata, None).unwrap();
+        check_compatible("foo", &metadata, Some("115.0")).unwrap();
+        check_compatible("foo", &metadata, Some("128.0.3")).unwrap();
+        assert_eq!(
+            check_compatible("foo", &metadata, Some("129.0"))
+                .unwrap_err()
+                .to_string(),
+            "foo: 1.0 requires Firefox 128.* or older, but Firefox 129.0 loads it"
+        );
+
+        let android: Metadata = serde_json::from_str(
+            r#"{"version": "1.0", "files": [], "compatibility": {"android": {"min": "120.0", "max": "*"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            check_compatible("foo", &android, Some("128.0"))
+                .unwrap_err()
+                .to_string(),
+            "foo: 1.0 does not support Firefox"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_gecko_id() {
+        let manifest = manifest::from_file("tests/fixtures/vimium_ff-2.1.2.xpi", None)
+            .await
+            .unwrap();
+        check_gecko_id(
+            "vimium-ff",
+            &manifest,
+            "{d7742d87-e61d-4b78-b8a1-b469842139fa}",
+        )
+        .unwrap();
+        assert_eq!(
+            check_gecko_id("vimium-ff", &manifest, "vimium@example.com")
+                .unwrap_err()
+                .to_string(),
+            "vimium-ff: manifest declares add-on ID {d7742d87-e61d-4b78-b8a1-b469842139fa}, but addons.mozilla.org reports vimium@example.com"
+        );
+        // the ID is optional for signed extensions
+        check_gecko_id("foo", &Manifest::default(), "foo@example.com").unwrap();
+    }
+
     #[test]
     fn test_compute_hash_sha256() {
         let value = compute_hash("sha256", b"hello world").unwrap();
@@ -0,0 +1,136 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use tokio::fs;
+use tracing::debug;
+
+use crate::artifact;
+use crate::config::LinkMode;
+
+/// place puts the stored file `src` at `dst` using the given mode, replacing whatever is at
+/// `dst`. Nothing is changed if `dst` is already up-to-date, e.g. a copy with the same content.
+///
+/// Returns whether `dst` was replaced.
+pub async fn place(src: &Path, dst: &Path, mode: LinkMode) -> Result<bool> {
+    if is_placed(src, dst, mode).await {
+        debug!("{:?} is already placed at {:?}", src, dst);
+        return Ok(false);
+    }
+    let mut tmp = dst.as_os_str().to_owned();
+    tmp.push(".bak");
+    let tmp = Path::new(&tmp);
+    if let Some(dir) = dst.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    _ = fs::remove_file(tmp).await;
+    match mode {
+        LinkMode::Symlink => create_symlink(src, tmp).await?,
+        LinkMode::Hardlink => fs::hard_link(src, tmp).await.map_err(|err| {
+            anyhow!(
+                "Failed to hardlink {:?} to {:?} (both must be on the same file system): {}",
+                src,
+                dst,
+                err
+            )
+        })?,
+        LinkMode::Copy => {
+            fs::copy(src, tmp).await?;
+        }
+    }
+    fs::rename(tmp, dst).await?;
+    Ok(true)
+}
+
+/// is_placed returns whether `dst` is an up-to-date symlink, hardlink or copy of `src`.
+pub async fn is_placed(src: &Path, dst: &Path, mode: LinkMode) -> bool {
+    match mode {
+        LinkMode::Symlink => fs::read_link(dst).await.is_ok_and(|link| link == src),
+        LinkMode::Hardlink => is_same_file(src, dst).await,
+        LinkMode::Copy => is_copy(src, dst).await,
+    }
+}
+
+/// refers_to returns whether `dst` is a symlink to `src` or a regular file with the same
+/// content, i.e. a hardlink or copy.
+pub async fn refers_to(dst: &Path, src: &Path) -> bool {
+    match fs::read_link(dst).await {
+        Ok(link) => link == src,
+        Err(_) => is_copy(src, dst).await,
+    }
+}
+
+/// is_copy returns whether `dst` is a regular file with the same content as `src`.
+async fn is_copy(src: &Path, dst: &Path) -> bool {
+    let Ok(metadata) = fs::symlink_metadata(dst).await else {
+        return false;
+    };
+    if !metadata.is_file() {
+        return false;
+    }
+    match (artifact::sha256(src).await, artifact::sha256(dst).await) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(unix)]
+async fn is_same_file(src: &Path, dst: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(src).await, fs::symlink_metadata(dst).await) {
+        (Ok(a), Ok(b)) => b.is_file() && a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+async fn is_same_file(src: &Path, dst: &Path) -> bool {
+    is_copy(src, dst).await
+}
+
+#[cfg(target_os = "windows")]
+async fn create_symlink(src: &Path, dst: &Path) -> Result<()> {
+    fs::symlink_file(src, dst).await?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+async fn create_symlink(src: &Path, dst: &Path) -> Result<()> {
+    fs::symlink(src, dst).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    #[cfg(not(target_os = "windows"))]
+    #[tokio::test]
+    async fn test_place() {
+        let dir = TempDir::new().unwrap();
+        let src = dir.path().join("storage").join("foo.xpi");
+        fs::create_dir_all(src.parent().unwrap()).await.unwrap();
+        fs::write(&src, b"1.0").await.unwrap();
+
+        for mode in [LinkMode::Symlink, LinkMode::Hardlink, LinkMode::Copy] {
+            let dst = dir.path().join(format!("{mode:?}")).join("foo.xpi");
+            assert!(!is_placed(&src, &dst, mode).await);
+            assert!(place(&src, &dst, mode).await.unwrap());
+            assert!(is_placed(&src, &dst, mode).await);
+            assert!(refers_to(&dst, &src).await);
+            assert!(!place(&src, &dst, mode).await.unwrap());
+        }
+
+        // a copy is stale once the stored file changes
+        let copy = dir.path().join("Copy").join("foo.xpi");
+        fs::remove_file(&src).await.unwrap();
+        fs::write(&src, b"1.1").await.unwrap();
+        assert!(!is_placed(&src, &copy, LinkMode::Copy).await);
+        assert!(place(&src, &copy, LinkMode::Copy).await.unwrap());
+        assert_eq!(fs::read(&copy).await.unwrap(), b"1.1");
+
+        // switching modes replaces the file
+        assert!(place(&src, &copy, LinkMode::Symlink).await.unwrap());
+        assert_eq!(fs::read_link(&copy).await.unwrap(), src);
+    }
+}
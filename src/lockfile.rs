@@ -0,0 +1,172 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::debug;
+
+use crate::artifact::Artifact;
+use crate::config::BrowserKind;
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+/// The lockfile records the exact versions of all installed extensions.
+pub struct Lockfile {
+    #[serde(default, rename = "extension")]
+    pub extensions: Vec<LockedExtension>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+/// A single extension of the lockfile.
+pub struct LockedExtension {
+    pub browser: BrowserKind,
+    /// The name of the extension as used in the config.
+    pub name: String,
+    /// The Firefox add-on guid or the Chromium extension ID.
+    pub id: String,
+    pub version: String,
+    pub url: String,
+    /// The SHA-256 of the stored `.xpi` or `.crx` file.
+    pub sha256: String,
+}
+
+impl LockedExtension {
+    pub fn new(browser: BrowserKind, name: String, artifact: &Artifact) -> Self {
+        LockedExtension {
+            browser,
+            name,
+            id: artifact.id.clone(),
+            version: artifact.version.clone(),
+            url: artifact.url.clone(),
+            sha256: artifact.sha256.clone(),
+        }
+    }
+
+    /// check_version fails if the upstream version differs from the locked one.
+    pub fn check_version(&self, version: &str) -> Result<()> {
+        if self.version != version {
+            return Err(anyhow!(
+                "{}: upstream version {version} does not match locked version {}",
+                self.name,
+                self.version
+            ));
+        }
+        Ok(())
+    }
+
+    /// check_artifact fails if the stored file differs from the locked one.
+    pub fn check_artifact(&self, artifact: &Artifact) -> Result<()> {
+        self.check_version(&artifact.version)?;
+        if self.id != artifact.id {
+            return Err(anyhow!(
+                "{}: ID {} does not match locked ID {}",
+                self.name,
+                artifact.id,
+                self.id
+            ));
+        }
+        if self.sha256 != artifact.sha256 {
+            return Err(anyhow!(
+                "{}: SHA-256 {} does not match locked SHA-256 {}",
+                self.name,
+                artifact.sha256,
+                self.sha256
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Lockfile {
+    /// get returns the locked extension with the given name.
+    pub fn get(&self, browser: BrowserKind, name: &str) -> Option<&LockedExtension> {
+        self.extensions
+            .iter()
+            .find(|ext| ext.browser == browser && ext.name == name)
+    }
+}
+
+/// path returns the path of the lockfile which belongs to the given config file.
+pub fn path(cfg_path: &Path) -> PathBuf {
+    cfg_path.with_extension("lock")
+}
+
+pub async fn from_file(path: &Path) -> Result<Lockfile> {
+    debug!("Loading lockfile {:?}", path);
+    let contents = fs::read_to_string(path).await?;
+    Ok(toml::from_str(&contents)?)
+}
+
+/// write writes the lockfile with its extensions in a stable order. Extensions stored for all
+/// users and for the profiles are locked once.
+pub async fn write(path: &Path, mut lockfile: Lockfile) -> Result<()> {
+    debug!("Writing lockfile {:?}", path);
+    lockfile.extensions.sort();
+    lockfile.extensions.dedup();
+    let contents = format!(
+        "# This file is generated by extension-downloader. Do not edit it manually.\n{}",
+        toml::to_string(&lockfile)?
+    );
+    let tmp = path.with_extension("lock.tmp");
+    fs::write(&tmp, contents).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+
+    fn artifact() -> Artifact {
+        Artifact {
+            path: PathBuf::from("/storage/firefox/vimium.xpi"),
+            id: "{d7742d87-e61d-4b78-b8a1-b469842139fa}".to_string(),
+            version: "2.1.2".to_string(),
+            url: "https://example.com/vimium.xpi".to_string(),
+            sha256: "abc".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_and_read() {
+        let dir = TempDir::new().unwrap();
+        let cfg_path = dir.path().join("config.toml");
+        let lock_path = path(&cfg_path);
+        assert_eq!(lock_path, dir.path().join("config.lock"));
+
+        let lockfile = Lockfile {
+            extensions: vec![LockedExtension::new(
+                BrowserKind::Firefox,
+                "vimium-ff".to_string(),
+                &artifact(),
+            )],
+        };
+        write(&lock_path, lockfile).await.unwrap();
+
+        let lockfile = from_file(&lock_path).await.unwrap();
+        let locked = lockfile.get(BrowserKind::Firefox, "vimium-ff").unwrap();
+        assert_eq!(locked.version, "2.1.2");
+        assert_eq!(locked.id, "{d7742d87-e61d-4b78-b8a1-b469842139fa}");
+        assert!(lockfile.get(BrowserKind::Chromium, "vimium-ff").is_none());
+    }
+
+    #[test]
+    fn test_check_artifact() {
+        let locked =
+            LockedExtension::new(BrowserKind::Firefox, "vimium-ff".to_string(), &artifact());
+        locked.check_artifact(&artifact()).unwrap();
+
+        let mut other = artifact();
+        other.version = "2.1.3".to_string();
+        assert_eq!(
+            locked.check_artifact(&other).unwrap_err().to_string(),
+            "vimium-ff: upstream version 2.1.3 does not match locked version 2.1.2"
+        );
+
+        let mut other = artifact();
+        other.sha256 = "def".to_string();
+        assert_eq!(
+            locked.check_artifact(&other).unwrap_err().to_string(),
+            "vimium-ff: SHA-256 def does not match locked SHA-256 abc"
+        );
+    }
+}
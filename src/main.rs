@@ -1,27 +1,103 @@
+mod artifact;
+mod checks;
 mod chromium;
+mod cli;
 mod config;
+mod enterprise;
 mod firefox;
+mod link;
+mod lockfile;
 mod manifest;
+mod plan;
+mod policy;
+mod profiles;
+mod source;
+mod state;
+mod unpacked;
+mod xpi;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use clap::Parser;
 use dirs::{config_dir, data_dir, home_dir};
-use reqwest_middleware::ClientBuilder;
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
 use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
+use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::env;
 use std::path::Path;
 use std::process::ExitCode;
+use std::sync::Arc;
 use std::{collections::HashMap, path::PathBuf};
 use tokio::task::JoinSet;
 use tokio::time::Instant;
-use tracing::{error, info, Level};
+use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::{fmt::Subscriber as FmtSubscriber, EnvFilter};
 use walkdir::WalkDir;
 
-use config::Config;
+use artifact::Artifact;
+use checks::{Checks, HeldUpdate};
+use chromium::update::UpdateInfo;
+use cli::{Cli, Command};
+use config::{Config, ExtensionName, Target};
+use lockfile::{LockedExtension, Lockfile};
+use manifest::{compare_versions, Manifest};
+use state::State;
+
+/// The number of versions of each extension kept for rollbacks unless configured otherwise.
+const DEFAULT_KEEP_VERSIONS: usize = 3;
+
+/// The store of the extensions installed for all users on Linux.
+const SYSTEM_EXTENSIONS_DIR: &str = "/var/lib/extension-downloader";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Where the downloaded files of an extension are stored.
+enum Store {
+    /// The extensions directory, which also contains the state file.
+    User,
+    /// The files of the extensions installed for all users, which the browsers of all users read.
+    System,
+}
+
+impl Store {
+    /// of returns the store of the files installed into the given target.
+    fn of(target: &Target) -> Store {
+        if target.install_mode.is_system_wide() {
+            Store::System
+        } else {
+            Store::User
+        }
+    }
+
+    /// dir returns the directory of the store.
+    fn dir(self, cfg: &Config) -> PathBuf {
+        match (self, &cfg.extensions_dir) {
+            (Store::User, _) => get_extensions_dir(cfg),
+            (Store::System, Some(dir)) => dir.join("system"),
+            (Store::System, None) if cfg!(target_os = "linux") => {
+                PathBuf::from(SYSTEM_EXTENSIONS_DIR)
+            }
+            (Store::System, None) => get_extensions_dir(cfg).join("system"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How `sync` treats the lockfile.
+enum SyncMode {
+    /// Install the latest versions and record them in the lockfile.
+    Update,
+    /// Install exactly the versions recorded in the lockfile.
+    Frozen,
+    /// Download the latest versions and record them in the lockfile without installing them.
+    Lock,
+}
+
+fn main() -> ExitCode {
+    execute(Cli::parse())
+}
 
 #[tokio::main]
-async fn main() -> ExitCode {
+async fn execute(cli: Cli) -> ExitCode {
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(Level::INFO.as_str())); // default to "info" if RUST_LOG is not set
 
@@ -30,9 +106,28 @@ async fn main() -> ExitCode {
         .finish();
     tracing::subscriber::set_global_default(subscriber).expect("Failed to set global subscriber");
 
-    let cfg_path = get_config_dir().join("config.toml");
+    let cfg_path = cli
+        .config
+        .unwrap_or_else(|| get_config_dir().join("config.toml"));
+    let lock_path = lockfile::path(&cfg_path);
     let start = Instant::now();
-    let result = run(&cfg_path).await;
+    let result = match load_config(&cfg_path, cli.extensions_dir).await {
+        Ok(cfg) => match cli.command.unwrap_or_default() {
+            Command::Sync(args) if args.frozen => sync(&cfg, &lock_path, SyncMode::Frozen).await,
+            Command::Sync(_) => sync(&cfg, &lock_path, SyncMode::Update).await,
+            Command::Lock => sync(&cfg, &lock_path, SyncMode::Lock).await,
+            Command::Plan => plan(&cfg).await,
+            Command::List => list(&cfg).await,
+            Command::Profiles => list_profiles(&cfg).await,
+            Command::Status => status(&cfg).await,
+            Command::Prune => prune(&cfg).await,
+            Command::Rollback(args) => rollback(&cfg, &args.name, args.to.as_deref()).await,
+            Command::Release(args) => release(&cfg, &args.name).await,
+            Command::Approve(args) => approve(&cfg, &args.name).await,
+            Command::Flags => flags(&cfg).await,
+        },
+        Err(err) => Err(err),
+    };
     let duration = start.elapsed();
     info!("Finished in {:?}", duration);
     match result {
@@ -48,311 +143,2370 @@ async fn main() -> ExitCode {
     }
 }
 
-async fn run<P: AsRef<Path>>(cfg_path: P) -> Result<u32> {
+/// load_config loads the config file and applies overrides from the command line.
+async fn load_config<P: AsRef<Path>>(
+    cfg_path: P,
+    extensions_dir: Option<PathBuf>,
+) -> Result<Config> {
     if !cfg_path.as_ref().exists() {
         return Err(anyhow::anyhow!(
             "Config file {:?} does not exist",
             cfg_path.as_ref()
         ));
     }
-    let cfg = config::from_file(cfg_path.as_ref()).await?;
+    let mut cfg = config::from_file(cfg_path.as_ref()).await?;
+    if extensions_dir.is_some() {
+        cfg.extensions_dir = extensions_dir;
+    }
+    Ok(cfg)
+}
 
-    // Retry up to 3 times with increasing intervals between attempts.
-    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
-    let client = ClientBuilder::new(reqwest::Client::new())
-        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-        .build();
+/// resolve_profiles resolves the profiles of the configured entries when a command runs, so an
+/// entry which can't be resolved only fails its own extensions. The failures are logged and
+/// the extensions of the failed entries returned.
+async fn resolve_profiles(cfg: &Config) -> (Config, Vec<config::Extension>) {
+    let (resolved, unresolved) = config::resolve(cfg).await;
+    let mut failed = Vec::with_capacity(unresolved.len());
+    for (ext, err) in unresolved {
+        error!("{}", err);
+        failed.push(ext);
+    }
+    (resolved, failed)
+}
+
+/// detect_browser_versions detects the browser versions which are not configured from the
+/// resolved profiles. Only `sync` and `plan` need them, so other commands don't read the profiles.
+async fn detect_browser_versions(cfg: &mut Config) {
+    for ext in cfg
+        .extensions
+        .iter_mut()
+        .filter(|ext| ext.browser_version.is_none())
+    {
+        ext.browser_version = profiles::browser_version(ext.browser, Path::new(&ext.profile)).await;
+    }
+}
+
+/// unresolved_extensions returns the extensions of the entries which couldn't be resolved.
+fn unresolved_extensions(failed: &[config::Extension]) -> HashSet<(config::BrowserKind, String)> {
+    failed
+        .iter()
+        .flat_map(|ext| {
+            ext.names
+                .iter()
+                .map(|name| (ext.browser, name.name().to_string()))
+        })
+        .collect()
+}
+
+/// The configured extensions mapped to the profiles they are installed into.
+type ExtensionProfiles = HashMap<(ExtensionName, config::BrowserKind, Store), Vec<Target>>;
 
-    let mut ext_to_profiles: HashMap<(String, config::BrowserKind), Vec<String>> =
-        HashMap::with_capacity(128);
-    // deduplicate extensions
+/// extensions_by_name deduplicates the configured extensions and maps them to their profiles.
+/// Extensions installed both for all users and into profiles are stored once per store.
+fn extensions_by_name(cfg: &Config) -> ExtensionProfiles {
+    let mut ext_to_profiles: ExtensionProfiles = HashMap::with_capacity(128);
     for ext in &cfg.extensions {
+        let target = ext.target();
         for name in &ext.names {
             ext_to_profiles
-                .entry((name.to_string(), ext.browser))
+                .entry((name.clone(), ext.browser, Store::of(&target)))
                 .or_default()
-                .push(ext.profile.clone());
+                .push(target.clone());
         }
     }
+    ext_to_profiles
+}
+
+/// browser_version returns the oldest browser version which loads the extensions installed into
+/// the given profiles, if known.
+fn browser_version(cfg: &Config, kind: config::BrowserKind, profiles: &[Target]) -> Option<String> {
+    cfg.extensions
+        .iter()
+        .filter(|ext| ext.browser == kind && profiles.contains(&ext.target()))
+        .filter_map(|ext| ext.browser_version.clone())
+        .min_by(|a, b| compare_versions(a, b))
+}
+
+/// check_chromium_updates checks all configured Chromium extensions for updates using one batch
+/// per browser version.
+async fn check_chromium_updates(
+    client: &ClientWithMiddleware,
+    cfg: &Config,
+    ext_to_profiles: &ExtensionProfiles,
+) -> HashMap<String, UpdateInfo> {
+    let mut batches: HashMap<Option<String>, Vec<String>> = HashMap::new();
+    for ((name, kind, _), profiles) in ext_to_profiles {
+        if *kind == config::BrowserKind::Chromium
+            && name.is_store()
+            && !chromium::managed::is_policy_only(profiles)
+        {
+            batches
+                .entry(browser_version(cfg, *kind, profiles))
+                .or_default()
+                .push(name.name().to_string());
+        }
+    }
+    let mut updates = HashMap::new();
+    for (browser_version, mut extension_ids) in batches {
+        extension_ids.sort();
+        extension_ids.dedup();
+        updates.extend(
+            chromium::check_updates(
+                client,
+                cfg.base_url_google.clone(),
+                extension_ids,
+                browser_version.as_deref(),
+            )
+            .await,
+        );
+    }
+    updates
+}
+
+/// sync downloads, updates and installs all configured extensions and purges old ones.
+///
+/// * `lock_path` - The lockfile which is read or written depending on `mode`.
+async fn sync(cfg: &Config, lock_path: &Path, mode: SyncMode) -> Result<u32> {
+    let (mut cfg, unresolved) = resolve_profiles(cfg).await;
+    detect_browser_versions(&mut cfg).await;
+    let cfg = &cfg;
+    // Retry up to 3 times with increasing intervals between attempts.
+    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
+    let client = ClientBuilder::new(reqwest::Client::new())
+        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
+        .build();
+
+    let mut ext_to_profiles = extensions_by_name(cfg);
+
+    let updates = check_chromium_updates(&client, cfg, &ext_to_profiles).await;
+    let anchor = Arc::new(match &cfg.mozilla_root_certificate {
+        Some(path) => xpi::load_trust_anchor(path).await?,
+        None => xpi::TrustAnchor::default(),
+    });
+    let lockfile = match mode {
+        SyncMode::Frozen => lockfile::from_file(lock_path)
+            .await
+            .map_err(|err| anyhow!("Failed to load lockfile {:?}: {}", lock_path, err))?,
+        _ => Lockfile::default(),
+    };
+    // the stored files which are still locked are reused by `lock`
+    let previous_lockfile = match mode {
+        SyncMode::Lock => lockfile::from_file(lock_path).await.unwrap_or_default(),
+        _ => Lockfile::default(),
+    };
 
-    let extensions_dir: PathBuf = get_extensions_dir(&cfg);
-    let dest_dir_chromium = extensions_dir.join("chromium");
-    let dest_dir_firefox = extensions_dir.join("firefox");
+    let state_path = state::path(&get_extensions_dir(cfg));
+    let mut state = load_state(cfg).await?;
+    let keep_versions = cfg.keep_versions.unwrap_or(DEFAULT_KEEP_VERSIONS);
 
     let mut set = JoinSet::new();
 
-    let mut err_count = 0;
-    for ((name, kind), profiles) in ext_to_profiles.drain() {
+    let mut err_count = unresolved.len() as u32;
+    let mut failed = unresolved_extensions(&unresolved);
+    let mut lock_dirs = HashSet::new();
+    for ((extension, kind, store), profiles) in ext_to_profiles.drain() {
+        let name = extension.name().to_string();
+        // the browser downloads extensions installed using policies itself, so there is nothing
+        // to lock
+        let policy_only =
+            kind == config::BrowserKind::Chromium && chromium::managed::is_policy_only(&profiles);
+        if mode == SyncMode::Lock && policy_only {
+            continue;
+        }
+        let dest_dir = match mode {
+            // the stored files stay in use, so the latest versions are only downloaded to check
+            // and lock them
+            SyncMode::Lock => {
+                let lock_dir = lock_dir(&store.dir(cfg));
+                lock_dirs.insert(lock_dir.clone());
+                lock_dir
+            }
+            _ => store.dir(cfg),
+        }
+        .join(kind.to_string());
+        if let Some(locked) = previous_lockfile.get(kind, &name) {
+            reuse_stored(locked, &store.dir(cfg).join(kind.to_string()), &dest_dir).await;
+        }
+        let browser_version = browser_version(cfg, kind, &profiles);
+        let profiles = match mode {
+            SyncMode::Lock => Vec::new(),
+            _ => profiles,
+        };
+        if let Some(pinned) = state.pinned(kind, &name) {
+            info!("{kind} {name}: rolled back to {}", pinned.version);
+            let install = install_stored(kind, stored_artifact(pinned), profiles.clone());
+            set.spawn(async move { (kind, name, store, profiles, install.await) });
+            continue;
+        }
+        let locked = lockfile.get(kind, &name).cloned();
+        // Chromium extensions on Windows are installed from the store and can't be locked
+        let lockable =
+            !(cfg!(target_os = "windows") && kind == config::BrowserKind::Chromium || policy_only);
+        if mode == SyncMode::Frozen && lockable && locked.is_none() {
+            error!("{kind} {name}: missing in lockfile {:?}", lock_path);
+            err_count += 1;
+            failed.insert((kind, name));
+            continue;
+        }
+        let checks = Checks {
+            locked,
+            hold_new_permissions: cfg.hold_new_permissions,
+            approved_version: state.approved_version(kind, &name),
+            policy: cfg.policy.clone(),
+            browser_version,
+            locale: cfg.locale.clone(),
+        };
+        let task_profiles = profiles.clone();
+        if !extension.is_store() {
+            let fetch = source::fetch(
+                client.clone(),
+                extension,
+                kind,
+                anchor.clone(),
+                checks,
+                dest_dir,
+            );
+            set.spawn(async move {
+                let install = match fetch.await {
+                    Ok(artifact) => install_stored(kind, artifact, profiles).await,
+                    Err(err) => Err(err),
+                };
+                (kind, name, store, task_profiles, install)
+            });
+            continue;
+        }
         match kind {
             config::BrowserKind::Chromium => {
-                set.spawn(chromium::install(
+                let install = chromium::install(
                     client.clone(),
                     cfg.base_url_google.clone(),
-                    name,
-                    dest_dir_chromium.clone(),
+                    cfg.chromium_update_url.clone(),
+                    extension,
+                    updates.get(&name).cloned(),
+                    checks,
+                    dest_dir,
                     profiles,
-                ));
+                );
+                set.spawn(async move { (kind, name, store, task_profiles, install.await) });
             }
             config::BrowserKind::Firefox => {
-                set.spawn(firefox::install(
+                let install = firefox::install(
                     client.clone(),
                     cfg.base_url_mozilla.clone(),
-                    name,
-                    dest_dir_firefox.clone(),
+                    extension,
+                    anchor.clone(),
+                    checks,
+                    dest_dir,
                     profiles,
-                ));
+                );
+                set.spawn(async move { (kind, name, store, task_profiles, install.await) });
             }
         }
     }
 
     let mut known_files = HashSet::new();
+    let mut installations = HashSet::new();
+    let mut locked = Vec::new();
+    let mut held = HashSet::new();
+    let mut policy_installations = HashSet::new();
     while let Some(result) = set.join_next().await {
         match result.unwrap() {
-            Ok(Some(path)) => {
-                known_files.insert(path);
+            (kind, name, _, _, Ok(Some(artifact))) if mode == SyncMode::Lock => {
+                locked.push(LockedExtension::new(kind, name, &artifact));
             }
-            Err(err) => {
+            (kind, name, store, profiles, Ok(Some(artifact))) => {
+                state.release_pending(kind, &name);
+                let (policies, profiles) = split_policies(kind, profiles);
+                record_policies(&mut state, &name, policies, &mut policy_installations);
+                let entry = state.record(kind, &name, &artifact);
+                for profile in profiles {
+                    entry.add_profile(&profile);
+                    installations.insert((profile.profile, artifact.path.clone()));
+                }
+                if entry.pinned {
+                    // keep the newer version until the rollback is released
+                    known_files.extend(
+                        state
+                            .artifacts
+                            .iter()
+                            .filter(|a| a.browser == kind && a.name == name)
+                            .map(|a| a.path.clone()),
+                    );
+                }
+                known_files.insert(artifact.path.clone());
+                let archived = archive(
+                    &mut state,
+                    &store.dir(cfg),
+                    kind,
+                    &name,
+                    &artifact,
+                    keep_versions,
+                )
+                .await;
+                if let Err(err) = archived {
+                    error!(
+                        "{kind} {name}: failed to keep version {}: {}",
+                        artifact.version, err
+                    );
+                    err_count += 1;
+                }
+                locked.push(LockedExtension::new(kind, name, &artifact));
+            }
+            (kind, name, _, profiles, Ok(None)) if mode != SyncMode::Lock => {
+                let (policies, _) = split_policies(kind, profiles);
+                record_policies(&mut state, &name, policies, &mut policy_installations);
+            }
+            (kind, name, _, _, Err(err)) if err.is::<HeldUpdate>() => {
+                let update = err.downcast_ref::<HeldUpdate>().unwrap();
+                warn!("{}", update);
+                info!("Run `extension-downloader approve {name}` to install it");
+                state.hold(kind, update);
+                // the installed version is kept
+                held.insert((kind, name.clone()));
+                failed.insert((kind, name));
+            }
+            (kind, name, _, _, Err(err)) => {
                 error!("{}", err);
                 err_count += 1;
+                failed.insert((kind, name));
             }
             _ => {}
         }
     }
 
-    if err_count == 0 {
-        for dir in &[dest_dir_chromium, dest_dir_firefox] {
-            if !dir.exists() {
+    if !held.is_empty() {
+        let previous = lockfile::from_file(lock_path).await.unwrap_or_default();
+        locked.extend(locked_installed(&state, &previous, &held));
+    }
+    for dir in lock_dirs {
+        if let Err(err) = tokio::fs::remove_dir_all(&dir).await {
+            error!("Failed to remove {:?}: {}", dir, err);
+        }
+    }
+
+    // locking leaves the profiles and the stored files alone
+    if mode != SyncMode::Lock {
+        keep_previous(&state, &failed, &mut known_files, &mut installations);
+        for (kind, profile, file) in stale_installations(&state, &installations) {
+            let id = file.file_stem().unwrap_or_default().to_string_lossy();
+            if policy_installations.contains(&(profile.profile.clone(), id.to_string())) {
+                // installed using policies only, the stored file is no longer needed
+                if let Some(entry) = state.get_mut(&file) {
+                    entry.remove_profile(&profile.profile);
+                }
                 continue;
             }
-            for file in WalkDir::new(dir).into_iter().filter_map(|file| file.ok()) {
-                if file.metadata().unwrap().is_file() && !known_files.contains(file.path()) {
-                    info!("Purging old extension: {:?}", file.path());
-                    tokio::fs::remove_file(file.path()).await?;
+            info!(
+                "Removing {} from {profile}",
+                display_name(&file, cfg.locale.as_deref()).await
+            );
+            let result = match kind {
+                config::BrowserKind::Chromium => {
+                    chromium::uninstall_extension(&file, &profile).await
                 }
+                config::BrowserKind::Firefox => firefox::uninstall_extension(&file, &profile).await,
+            };
+            if let Err(err) = result {
+                error!("Failed to remove {:?} from {}: {}", file, profile, err);
+                err_count += 1;
+                // the file is still installed
+                known_files.insert(file);
+                continue;
+            }
+            if let Some(entry) = state.get_mut(&file) {
+                entry.remove_profile(&profile.profile);
+            }
+        }
+        for installation in stale_policies(&state, &policy_installations, &failed) {
+            info!(
+                "Removing {} from {}",
+                installation.name, installation.target
+            );
+            let policy_file = Path::new(&installation.target.profile);
+            if let Err(err) = chromium::managed::remove(&installation.name, policy_file).await {
+                error!(
+                    "Failed to remove {} from {}: {}",
+                    installation.name, installation.target, err
+                );
+                err_count += 1;
+                continue;
             }
+            state.policies.retain(|p| *p != installation);
         }
+        purge(&mut state, &known_files, grace_period(cfg)).await?;
+    }
+    if err_count == 0 && mode != SyncMode::Frozen {
+        lockfile::write(lock_path, Lockfile { extensions: locked }).await?;
     }
+    state::write(&state_path, &mut state).await?;
     Ok(err_count)
 }
 
-fn get_extensions_dir(cfg: &Config) -> PathBuf {
-    match &cfg.extensions_dir {
-        Some(dir) => dir.clone(),
-        None => data_dir()
-            .unwrap_or(home_dir().unwrap().join(".local").join("share"))
-            .join("extension-downloader"),
+/// plan prints the changes a sync would perform. Only metadata is fetched, nothing is written.
+async fn plan(cfg: &Config) -> Result<u32> {
+    let (mut cfg, unresolved) = resolve_profiles(cfg).await;
+    detect_browser_versions(&mut cfg).await;
+    let cfg = &cfg;
+    let client = ClientBuilder::new(reqwest::Client::new()).build();
+
+    let ext_to_profiles = extensions_by_name(cfg);
+    let updates = check_chromium_updates(&client, cfg, &ext_to_profiles).await;
+    let anchor = Arc::new(match &cfg.mozilla_root_certificate {
+        Some(path) => xpi::load_trust_anchor(path).await?,
+        None => xpi::TrustAnchor::default(),
+    });
+
+    let state = load_state(cfg).await?;
+    let mut known_files = HashSet::new();
+    let mut installations = HashSet::new();
+
+    let mut set = JoinSet::new();
+    for ((extension, kind, store), profiles) in ext_to_profiles {
+        if let Some(pinned) = state.pinned(kind, extension.name()) {
+            // rolled back extensions are not updated, see `sync`
+            for profile in profiles {
+                installations.insert((profile.profile, pinned.path.clone()));
+            }
+            known_files.extend(
+                state
+                    .artifacts
+                    .iter()
+                    .filter(|a| a.browser == kind && a.name == extension.name())
+                    .map(|a| a.path.clone()),
+            );
+            continue;
+        }
+        let name = extension.name().to_string();
+        let dest_dir = store.dir(cfg).join(kind.to_string());
+        let task_profiles = profiles.clone();
+        if !extension.is_store() {
+            let plan = source::plan(
+                client.clone(),
+                extension,
+                kind,
+                anchor.clone(),
+                dest_dir,
+                profiles,
+            );
+            set.spawn(async move { (kind, name, task_profiles, plan.await) });
+            continue;
+        }
+        match kind {
+            config::BrowserKind::Chromium => {
+                let update = updates.get(&name).cloned();
+                let plan = chromium::plan(
+                    client.clone(),
+                    cfg.base_url_google.clone(),
+                    cfg.chromium_update_url.clone(),
+                    extension,
+                    update,
+                    browser_version(cfg, kind, &profiles),
+                    dest_dir,
+                    profiles,
+                );
+                set.spawn(async move { (kind, name, task_profiles, plan.await) });
+            }
+            config::BrowserKind::Firefox => {
+                let plan = firefox::plan(
+                    client.clone(),
+                    cfg.base_url_mozilla.clone(),
+                    extension,
+                    browser_version(cfg, kind, &profiles),
+                    dest_dir,
+                    profiles,
+                );
+                set.spawn(async move { (kind, name, task_profiles, plan.await) });
+            }
+        }
+    }
+
+    let mut err_count = unresolved.len() as u32;
+    let mut failed = unresolved_extensions(&unresolved);
+    let mut changes = Vec::new();
+    while let Some(result) = set.join_next().await {
+        match result.unwrap() {
+            (_, _, profiles, Ok(plan)) => {
+                if let Some(file) = &plan.file {
+                    for profile in profiles {
+                        installations.insert((profile.profile, file.clone()));
+                    }
+                }
+                known_files.extend(plan.file);
+                changes.extend(plan.changes);
+            }
+            (kind, name, _, Err(err)) => {
+                error!("{}", err);
+                err_count += 1;
+                failed.insert((kind, name));
+            }
+        }
+    }
+
+    keep_previous(&state, &failed, &mut known_files, &mut installations);
+    for (_, profile, file) in stale_installations(&state, &installations) {
+        changes.push(plan::Change::Uninstall {
+            name: display_name(&file, cfg.locale.as_deref()).await,
+            profile: profile.profile,
+        });
+    }
+    for path in purgeable_files(&state, &known_files, grace_period(cfg), state::now()) {
+        changes.push(plan::Change::Delete { path });
+    }
+
+    changes.sort();
+    if changes.is_empty() {
+        println!("Nothing to do, everything is up-to-date.");
+    }
+    for change in changes {
+        println!("{change}");
+    }
+    Ok(err_count)
+}
+
+/// list prints the configured extensions and the profiles they are installed into.
+async fn list(cfg: &Config) -> Result<u32> {
+    let (cfg, unresolved) = resolve_profiles(cfg).await;
+    let mut extensions: Vec<_> = extensions_by_name(&cfg).into_iter().collect();
+    extensions.sort();
+    let mut previous = None;
+    for ((name, kind, _), profiles) in extensions {
+        // extensions in both stores are listed once
+        if previous.as_ref() != Some(&(kind, name.clone())) {
+            println!("{kind} {name}");
+        }
+        previous = Some((kind, name));
+        for profile in profiles {
+            println!("  {profile}");
+        }
+    }
+    Ok(unresolved.len() as u32)
+}
+
+/// flags prints the command-line flags which make Chromium load the extensions extracted by
+/// `install_mode = "unpacked"`, one line per directory.
+async fn flags(cfg: &Config) -> Result<u32> {
+    let unpacked = Config {
+        extensions: cfg
+            .extensions
+            .iter()
+            .filter(|ext| {
+                ext.browser == config::BrowserKind::Chromium
+                    && ext.install_mode == config::InstallMode::Unpacked
+            })
+            .cloned()
+            .collect(),
+        ..cfg.clone()
+    };
+    let (unpacked, unresolved) = resolve_profiles(&unpacked).await;
+    let mut dirs: Vec<&str> = unpacked
+        .extensions
+        .iter()
+        .map(|ext| ext.profile.as_str())
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        if let Some(flags) = unpacked::flags(Path::new(dir)).await? {
+            println!("{flags}");
+        }
+    }
+    Ok(unresolved.len() as u32)
+}
+
+/// list_profiles prints the browser profiles found in the configured user data directories.
+async fn list_profiles(cfg: &Config) -> Result<u32> {
+    let mut dirs: Vec<(config::BrowserKind, PathBuf)> = cfg
+        .extensions
+        .iter()
+        .filter_map(|ext| profiles::user_data_dir(ext).map(|dir| (ext.browser, dir)))
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+
+    let mut err_count = 0;
+    for (kind, dir) in dirs {
+        match profiles::list(kind, &dir).await {
+            Ok(found) => {
+                println!("{kind} {}", dir.display());
+                for profile in found {
+                    let default = if profile.is_default { " [default]" } else { "" };
+                    println!("  {}{default} ({})", profile.name, profile.path.display());
+                }
+            }
+            Err(err) => {
+                error!("{}", err);
+                err_count += 1;
+            }
+        }
+    }
+    Ok(err_count)
+}
+
+/// status prints the managed extensions and the profiles they are installed in.
+async fn status(cfg: &Config) -> Result<u32> {
+    let state = load_state(cfg).await?;
+    let mut err_count = 0;
+    for entry in &state.artifacts {
+        println!(
+            "{} {} v{} ({})",
+            entry.browser,
+            entry.name,
+            entry.version,
+            entry.path.display()
+        );
+        println!(
+            "  installed {}, updated {}",
+            state::format_timestamp(entry.installed_at),
+            state::format_timestamp(entry.updated_at)
+        );
+        for profile in &entry.profiles {
+            println!("  installed in {profile}");
+        }
+        if entry.profiles.is_empty() {
+            println!("  not installed in any profile");
+        }
+        for pending in state
+            .pending
+            .iter()
+            .filter(|p| p.browser == entry.browser && p.name == entry.name && !entry.archived)
+        {
+            let approved = if pending.approved { " (approved)" } else { "" };
+            println!(
+                "  update to v{} adds permissions {} and is held for approval{approved}",
+                pending.version,
+                pending.permissions.join(", ")
+            );
+        }
+        match artifact::sha256(&entry.path).await {
+            Ok(sha256) if sha256 == entry.sha256 => {}
+            Ok(sha256) => {
+                error!(
+                    "{:?}: SHA-256 {sha256} does not match recorded SHA-256 {}",
+                    entry.path, entry.sha256
+                );
+                err_count += 1;
+            }
+            Err(err) => {
+                error!("Failed to read {:?}: {}", entry.path, err);
+                err_count += 1;
+            }
+        }
+    }
+    for installation in &state.policies {
+        println!(
+            "{} {} (downloaded by the browser)",
+            config::BrowserKind::Chromium,
+            installation.name
+        );
+        println!("  installed in {}", installation.target);
+    }
+    Ok(err_count)
+}
+
+/// prune removes managed extensions which are not installed in any profile.
+async fn prune(cfg: &Config) -> Result<u32> {
+    let mut state = load_state(cfg).await?;
+    let known_files: HashSet<PathBuf> = state
+        .artifacts
+        .iter()
+        .filter(|entry| !entry.profiles.is_empty())
+        .map(|entry| entry.path.clone())
+        .collect();
+    purge(&mut state, &known_files, 0).await?;
+    state::write(&state::path(&get_extensions_dir(cfg)), &mut state).await?;
+    Ok(0)
+}
+
+/// rollback installs an earlier kept version of an extension into the profiles it is installed in
+/// and pins it until it is released.
+///
+/// * `to` - The version to roll back to (default: the newest kept version older than the
+///   installed one).
+async fn rollback(cfg: &Config, name: &str, to: Option<&str>) -> Result<u32> {
+    let mut state = load_state(cfg).await?;
+    let mut kinds: Vec<config::BrowserKind> = state
+        .artifacts
+        .iter()
+        .filter(|a| a.name == name)
+        .map(|a| a.browser)
+        .collect();
+    kinds.sort();
+    kinds.dedup();
+    if kinds.is_empty() {
+        return Err(anyhow!("{name} is not managed by extension-downloader"));
+    }
+
+    for kind in kinds {
+        let entries: Vec<_> = state
+            .artifacts
+            .iter()
+            .filter(|a| a.browser == kind && a.name == name)
+            .collect();
+        let current = entries
+            .iter()
+            .find(|a| a.pinned)
+            .or_else(|| entries.iter().find(|a| !a.archived))
+            .map(|a| a.version.clone());
+        let mut kept: Vec<_> = entries.iter().filter(|a| a.archived).collect();
+        kept.sort_by(|a, b| compare_versions(&b.version, &a.version));
+        let target = match to {
+            Some(version) => kept.iter().find(|a| a.version == version).ok_or_else(|| {
+                let available: Vec<_> = kept.iter().map(|a| a.version.as_str()).collect();
+                anyhow!(
+                    "{kind} {name}: version {version} is not available (available: {})",
+                    available.join(", ")
+                )
+            })?,
+            None => kept
+                .iter()
+                .find(|a| {
+                    current.as_deref().is_none_or(|current| {
+                        compare_versions(&a.version, current) == Ordering::Less
+                    })
+                })
+                .ok_or_else(|| {
+                    anyhow!(
+                        "{kind} {name}: no version older than {} is available",
+                        current.as_deref().unwrap_or_default()
+                    )
+                })?,
+        };
+        let target = stored_artifact(target);
+        let mut profiles: Vec<Target> = entries
+            .iter()
+            .flat_map(|a| a.profiles.iter().cloned())
+            .collect();
+        profiles.sort();
+        profiles.dedup();
+
+        install_stored(kind, target.clone(), profiles.clone()).await?;
+        for entry in state
+            .artifacts
+            .iter_mut()
+            .filter(|a| a.browser == kind && a.name == name)
+        {
+            entry.pinned = entry.path == target.path;
+            entry.profiles.clear();
+        }
+        if let Some(entry) = state.get_mut(&target.path) {
+            for profile in &profiles {
+                entry.add_profile(profile);
+            }
+        }
+        info!("{kind} {name}: rolled back to {}", target.version);
+    }
+    state::write(&state::path(&get_extensions_dir(cfg)), &mut state).await?;
+    Ok(0)
+}
+
+/// release removes the pin of a rolled back extension so the next sync updates it again.
+async fn release(cfg: &Config, name: &str) -> Result<u32> {
+    let mut state = load_state(cfg).await?;
+    let mut released = false;
+    for entry in state
+        .artifacts
+        .iter_mut()
+        .filter(|a| a.name == name && a.pinned)
+    {
+        info!(
+            "{} {name}: released version {}",
+            entry.browser, entry.version
+        );
+        entry.pinned = false;
+        released = true;
+    }
+    if !released {
+        return Err(anyhow!("{name} is not rolled back"));
+    }
+    state::write(&state::path(&get_extensions_dir(cfg)), &mut state).await?;
+    Ok(0)
+}
+
+/// approve allows the next sync to install the held update of an extension.
+async fn approve(cfg: &Config, name: &str) -> Result<u32> {
+    let mut state = load_state(cfg).await?;
+    let mut approved = false;
+    for pending in state.pending.iter_mut().filter(|p| p.name == name) {
+        info!(
+            "{} {name}: approved version {} with permissions {}",
+            pending.browser,
+            pending.version,
+            pending.permissions.join(", ")
+        );
+        pending.approved = true;
+        approved = true;
+    }
+    if !approved {
+        return Err(anyhow!("{name} has no held update"));
+    }
+    state::write(&state::path(&get_extensions_dir(cfg)), &mut state).await?;
+    Ok(0)
+}
+
+/// locked_installed returns the lockfile entries of the installed versions of the given
+/// extensions, e.g. because their updates are held. The entries of the previous lockfile are
+/// kept if they match.
+fn locked_installed(
+    state: &State,
+    previous: &Lockfile,
+    extensions: &HashSet<(config::BrowserKind, String)>,
+) -> Vec<LockedExtension> {
+    state
+        .artifacts
+        .iter()
+        .filter(|a| !a.archived && extensions.contains(&(a.browser, a.name.clone())))
+        .map(|a| match previous.get(a.browser, &a.name) {
+            Some(locked) if locked.sha256 == a.sha256 => locked.clone(),
+            _ => LockedExtension::new(a.browser, a.name.clone(), &stored_artifact(a)),
+        })
+        .collect()
+}
+
+/// stored_artifact returns the artifact of a managed file.
+fn stored_artifact(entry: &state::ManagedArtifact) -> Artifact {
+    Artifact {
+        path: entry.path.clone(),
+        id: entry.id.clone(),
+        version: entry.version.clone(),
+        url: String::new(),
+        sha256: entry.sha256.clone(),
+    }
+}
+
+/// install_stored installs an already stored file into all given profiles.
+async fn install_stored(
+    kind: config::BrowserKind,
+    artifact: Artifact,
+    profiles: Vec<Target>,
+) -> Result<Option<Artifact>> {
+    for profile in profiles {
+        match kind {
+            config::BrowserKind::Chromium => {
+                chromium::install_file(&artifact.path, &artifact.id, &artifact.version, &profile)
+                    .await?
+            }
+            config::BrowserKind::Firefox => {
+                firefox::install_file(&artifact.path, &artifact.id, &profile).await?
+            }
+        }
+    }
+    Ok(Some(artifact))
+}
+
+/// archive keeps a copy of a stored file in the versioned store for rollbacks and removes the
+/// oldest copies which exceed `keep_versions` unless they are installed.
+async fn archive(
+    state: &mut State,
+    extensions_dir: &Path,
+    kind: config::BrowserKind,
+    name: &str,
+    artifact: &Artifact,
+    keep_versions: usize,
+) -> Result<()> {
+    if keep_versions == 0 {
+        return Ok(());
+    }
+    let dir = versions_dir(extensions_dir)
+        .join(kind.to_string())
+        .join(&artifact.id);
+    let ext = artifact
+        .path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let path = dir.join(format!("{}.{ext}", artifact.version));
+    if !path.exists() {
+        artifact::create_dir(&dir).await?;
+        tokio::fs::copy(&artifact.path, &path).await?;
+    }
+    state
+        .record(
+            kind,
+            name,
+            &Artifact {
+                path,
+                ..artifact.clone()
+            },
+        )
+        .archived = true;
+
+    let mut kept: Vec<_> = state
+        .artifacts
+        .iter()
+        .filter(|a| a.archived && a.browser == kind && a.id == artifact.id)
+        .collect();
+    kept.sort_by(|a, b| compare_versions(&b.version, &a.version));
+    let expired: Vec<PathBuf> = kept
+        .into_iter()
+        .skip(keep_versions)
+        .filter(|a| a.profiles.is_empty() && !a.pinned)
+        .map(|a| a.path.clone())
+        .collect();
+    for path in expired {
+        info!("Removing kept version {:?}", path);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        state.remove(&path);
+    }
+    Ok(())
+}
+
+/// lock_dir returns the temporary directory `lock` downloads the extensions of a store to.
+fn lock_dir(store_dir: &Path) -> PathBuf {
+    store_dir.join("lock.tmp")
+}
+
+/// reuse_stored puts the stored file of a locked extension into the lock directory if it still
+/// matches the lockfile, so `lock` only downloads the extensions which changed upstream.
+async fn reuse_stored(locked: &LockedExtension, store_dir: &Path, lock_dir: &Path) {
+    let file_name = match locked.browser {
+        config::BrowserKind::Chromium => format!("{}.crx", locked.id),
+        config::BrowserKind::Firefox => format!("{}.xpi", locked.id),
+    };
+    let stored = store_dir.join(&file_name);
+    if !artifact::sha256(&stored)
+        .await
+        .is_ok_and(|sha256| sha256 == locked.sha256)
+    {
+        return;
+    }
+    let dst = lock_dir.join(file_name);
+    // the lock directory is next to the stored files, i.e. on the same file system
+    let placed = match link::place(&stored, &dst, config::LinkMode::Hardlink).await {
+        Ok(placed) => Ok(placed),
+        Err(_) => link::place(&stored, &dst, config::LinkMode::Copy).await,
+    };
+    match placed {
+        Ok(_) => debug!("Reusing {:?} for {}", stored, locked.name),
+        Err(err) => warn!("Failed to reuse {:?}: {}", stored, err),
+    }
+}
+
+/// versions_dir returns the directory containing the versions kept for rollbacks.
+fn versions_dir(extensions_dir: &Path) -> PathBuf {
+    extensions_dir.join("versions")
+}
+
+/// load_state loads the state file of the extensions directory. Without a state file the stored
+/// files named like the tool's own (see `is_managed_name`) or installed in a configured profile
+/// are adopted, so installations made before the state file existed are still managed.
+async fn load_state(cfg: &Config) -> Result<State> {
+    let path = state::path(&get_extensions_dir(cfg));
+    if path.exists() {
+        return state::from_file(&path).await;
+    }
+    let (resolved, _) = config::resolve(cfg).await;
+    let installed = installed_files(&resolved).await?;
+    let mut stores: Vec<Store> = cfg
+        .extensions
+        .iter()
+        .map(|ext| Store::of(&ext.target()))
+        .chain([Store::User])
+        .collect();
+    stores.sort();
+    stores.dedup();
+    let mut state = State::default();
+    for (store, kind) in stores.into_iter().flat_map(|store| {
+        [config::BrowserKind::Chromium, config::BrowserKind::Firefox].map(|kind| (store, kind))
+    }) {
+        for path in stored_files(&store.dir(cfg).join(kind.to_string())) {
+            if !is_managed_name(kind, &path) && !installed.contains_key(&path) {
+                debug!("Ignoring unmanaged file {:?}", path);
+                continue;
+            }
+            let id = path.file_stem().unwrap_or_default().to_string_lossy();
+            let artifact = Artifact {
+                id: id.to_string(),
+                version: stored_manifest(&path, cfg.locale.as_deref())
+                    .await
+                    .map(|manifest| manifest.version)
+                    .unwrap_or_default(),
+                url: String::new(),
+                sha256: artifact::sha256(&path).await?,
+                path: path.clone(),
+            };
+            let entry = state.record(kind, &id, &artifact);
+            for profile in installed.get(&path).into_iter().flatten() {
+                entry.add_profile(profile);
+            }
+        }
+    }
+    Ok(state)
+}
+
+/// is_managed_name returns whether a stored file is named like the files the tool stores:
+/// `<id>.crx` with a Chromium extension ID (32 characters `a` to `p`) or `<guid>.xpi` with a
+/// Firefox add-on ID (`{<uuid>}` or `<name>@<domain>`).
+fn is_managed_name(kind: config::BrowserKind, path: &Path) -> bool {
+    let (Some(stem), Some(ext)) = (
+        path.file_stem().and_then(|stem| stem.to_str()),
+        path.extension(),
+    ) else {
+        return false;
+    };
+    match kind {
+        config::BrowserKind::Chromium => {
+            ext == "crx" && stem.len() == 32 && stem.bytes().all(|b| (b'a'..=b'p').contains(&b))
+        }
+        config::BrowserKind::Firefox => {
+            let is_uuid = stem
+                .strip_prefix('{')
+                .and_then(|uuid| uuid.strip_suffix('}'))
+                .is_some_and(|uuid| {
+                    uuid.split('-').map(str::len).eq([8, 4, 4, 4, 12])
+                        && uuid.chars().all(|c| c == '-' || c.is_ascii_hexdigit())
+                });
+            let is_email = stem
+                .split_once('@')
+                .is_some_and(|(_, domain)| !domain.is_empty());
+            ext == "xpi" && (is_uuid || is_email)
+        }
+    }
+}
+
+/// installed_files maps the stored extension files to the configured profiles they are installed in.
+async fn installed_files(cfg: &Config) -> Result<HashMap<PathBuf, Vec<Target>>> {
+    let mut result: HashMap<PathBuf, Vec<Target>> = HashMap::new();
+    for ext in &cfg.extensions {
+        let target = ext.target();
+        let files = match ext.browser {
+            config::BrowserKind::Chromium => chromium::installed_extensions(&target).await?,
+            config::BrowserKind::Firefox => firefox::installed_extensions(&target).await?,
+        };
+        for file in files {
+            let profiles = result.entry(file).or_default();
+            if !profiles.contains(&target) {
+                profiles.push(target.clone());
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// stale_installations returns the managed extensions which are installed in a profile although
+/// they are no longer configured for it. Extensions installed by other means are ignored.
+///
+/// * `installations` - The configured (profile, stored file) pairs. Installations are compared
+///   by profile only, so a changed link mode replaces the installation instead of removing it.
+fn stale_installations(
+    state: &State,
+    installations: &HashSet<(String, PathBuf)>,
+) -> Vec<(config::BrowserKind, Target, PathBuf)> {
+    state
+        .artifacts
+        .iter()
+        .flat_map(|entry| {
+            entry
+                .profiles
+                .iter()
+                .filter(|target| {
+                    !installations.contains(&(target.profile.clone(), entry.path.clone()))
+                })
+                .map(|profile| (entry.browser, profile.clone(), entry.path.clone()))
+        })
+        .collect()
+}
+
+/// display_name returns the name and ID of a stored extension for log messages.
+async fn display_name(path: &Path, locale: Option<&str>) -> String {
+    let id = path.file_stem().unwrap_or_default().to_string_lossy();
+    match stored_manifest(path, locale).await {
+        Ok(manifest) => format!("{} ({id})", manifest.name),
+        Err(_) => id.into_owned(),
+    }
+}
+
+/// stored_files returns all files in the given directory (recursively) in a stable order.
+fn stored_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|file| file.ok())
+        .filter(|file| file.file_type().is_file())
+        .map(|file| file.into_path())
+        .collect();
+    files.sort();
+    files
+}
+
+/// stored_manifest reads the manifest of a stored `.crx` or `.xpi` file.
+async fn stored_manifest(path: &Path, locale: Option<&str>) -> Result<Manifest> {
+    if path.extension().is_some_and(|ext| ext == "crx") {
+        let crx_file = chromium::crx3::parse_file(path).await?;
+        manifest::from_bytes(&crx_file.zip_archive, locale)
+    } else {
+        manifest::from_file(path, locale).await
     }
 }
 
-fn get_config_dir() -> PathBuf {
-    // prefer env EXTENSION_DOWNLOADER_CONFIG_DIR if set
-    env::var("EXTENSION_DOWNLOADER_CONFIG_DIR")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| config_dir().unwrap().join("extension-downloader"))
-}
+/// split_policies separates the policy files an extension is installed into from the other
+/// targets. The browser downloads extensions installed using policies itself, so only the other
+/// targets refer to the stored file.
+fn split_policies(kind: config::BrowserKind, profiles: Vec<Target>) -> (Vec<Target>, Vec<Target>) {
+    profiles.into_iter().partition(|target| {
+        kind == config::BrowserKind::Chromium
+            && target.install_mode == config::InstallMode::Policies
+    })
+}
+
+/// record_policies records the installations of a Chromium extension using policy files.
+fn record_policies(
+    state: &mut State,
+    name: &str,
+    policies: Vec<Target>,
+    policy_installations: &mut HashSet<(String, String)>,
+) {
+    for target in policies {
+        state.record_policy(name, &target);
+        policy_installations.insert((target.profile, name.to_string()));
+    }
+}
+
+/// stale_policies returns the recorded installations using policy files which are no longer
+/// configured. The installations of extensions which failed are kept.
+fn stale_policies(
+    state: &State,
+    policy_installations: &HashSet<(String, String)>,
+    failed: &HashSet<(config::BrowserKind, String)>,
+) -> Vec<state::PolicyInstallation> {
+    state
+        .policies
+        .iter()
+        .filter(|p| {
+            !policy_installations.contains(&(p.target.profile.clone(), p.name.clone()))
+                && !failed.contains(&(config::BrowserKind::Chromium, p.name.clone()))
+        })
+        .cloned()
+        .collect()
+}
+
+/// keep_previous keeps the stored files and installations of the given extensions, e.g. because
+/// updating them failed.
+fn keep_previous(
+    state: &State,
+    extensions: &HashSet<(config::BrowserKind, String)>,
+    known_files: &mut HashSet<PathBuf>,
+    installations: &mut HashSet<(String, PathBuf)>,
+) {
+    for entry in &state.artifacts {
+        if !extensions.contains(&(entry.browser, entry.name.clone())) {
+            continue;
+        }
+        known_files.insert(entry.path.clone());
+        for profile in &entry.profiles {
+            installations.insert((profile.profile.clone(), entry.path.clone()));
+        }
+    }
+}
+
+/// grace_period returns how long unused files are kept before they are purged (in seconds).
+fn grace_period(cfg: &Config) -> u64 {
+    cfg.purge_grace_days.unwrap_or_default() * 24 * 60 * 60
+}
+
+/// purgeable_files returns the managed files which are not contained in `known_files` and have
+/// been unused for at least `grace_period` seconds. Versions kept for rollbacks are removed by
+/// `archive` instead.
+fn purgeable_files(
+    state: &State,
+    known_files: &HashSet<PathBuf>,
+    grace_period: u64,
+    now: u64,
+) -> Vec<PathBuf> {
+    state
+        .artifacts
+        .iter()
+        .filter(|entry| !entry.archived && !known_files.contains(&entry.path))
+        .filter(|entry| {
+            grace_period == 0
+                || entry
+                    .unused_since
+                    .is_some_and(|since| now.saturating_sub(since) >= grace_period)
+        })
+        .map(|entry| entry.path.clone())
+        .collect()
+}
+
+/// purge deletes the managed files which are not contained in `known_files` once they have been
+/// unused for `grace_period` seconds and forgets them.
+async fn purge(state: &mut State, known_files: &HashSet<PathBuf>, grace_period: u64) -> Result<()> {
+    let now = state::now();
+    for entry in state.artifacts.iter_mut().filter(|entry| !entry.archived) {
+        if known_files.contains(&entry.path) {
+            entry.unused_since = None;
+        } else if entry.unused_since.is_none() {
+            entry.unused_since = Some(now);
+        }
+    }
+    for file in purgeable_files(state, known_files, grace_period, now) {
+        info!("Purging old extension: {:?}", file);
+        match tokio::fs::remove_file(&file).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        state.remove(&file);
+    }
+    Ok(())
+}
+
+fn get_extensions_dir(cfg: &Config) -> PathBuf {
+    match &cfg.extensions_dir {
+        Some(dir) => dir.clone(),
+        None => data_dir()
+            .unwrap_or(home_dir().unwrap().join(".local").join("share"))
+            .join("extension-downloader"),
+    }
+}
+
+fn get_config_dir() -> PathBuf {
+    // prefer env EXTENSION_DOWNLOADER_CONFIG_DIR if set
+    env::var("EXTENSION_DOWNLOADER_CONFIG_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| config_dir().unwrap().join("extension-downloader"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, path::Component};
+    use temp_dir::TempDir;
+    use tokio::{
+        fs::{self},
+        io::AsyncReadExt,
+    };
+
+    #[cfg(not(target_os = "windows"))]
+    #[tokio::test]
+    async fn test_chromium() {
+        let mut server = mockito::Server::new_async().await;
+
+        let extension_id = "dbepggeogbaibhgnhhndojpepiihcmeb";
+
+        let url = format!("/service/update2/crx?response=updatecheck&prodversion=119.0.6045.199&acceptformat=crx2,crx3&x=id%3D{extension_id}%26installsource%3Dondemand%26uc");
+        let url_str: &str = &url;
+        let m1 = server
+            .mock("GET", url_str)
+            .with_header("content-type", "text/xml")
+            .with_body(format!("<gupdate protocol=\"2.0\"><app appid=\"{extension_id}\" status=\"ok\"><updatecheck codebase=\"{}/vimium.crx\" status=\"ok\" version=\"2.1.2\"/></app></gupdate>", server.url()))
+            .with_status(200)
+            .create_async()
+            .await;
+        let m2 = server
+            .mock("GET", "/vimium.crx")
+            .with_header("content-type", "application/x-chrome-extension")
+            .with_body_from_file("tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let tmp_dir = TempDir::new().unwrap();
+        let extensions_dir = tmp_dir.path().join("storage");
+        let chromium_profile = tmp_dir.path().join("profile/chromium");
+        let cfg = Config {
+            base_url_google: Some(server.url()),
+            extensions_dir: Some(extensions_dir.clone()),
+            extensions: vec![config::Extension {
+                names: vec![extension_id.into()],
+                browser: config::BrowserKind::Chromium,
+                user_data_dir: None,
+                browser_version: None,
+                install_mode: config::InstallMode::Profile,
+                link_mode: config::LinkMode::Symlink,
+                profile: chromium_profile.to_string_lossy().to_string(),
+            }],
+            ..Default::default()
+        };
+        let cfg_path = tmp_dir.path().join("config.toml");
+        fs::write(&cfg_path, toml::to_string(&cfg).unwrap())
+            .await
+            .unwrap();
+
+        let chromium_extensions_dir = extensions_dir.join("chromium");
+        // create stale extension
+        fs::create_dir_all(&chromium_extensions_dir).await.unwrap();
+        let stale_path = chromium_extensions_dir.join("aaaabbbbccccddddeeeeffffgggghhhh.crx");
+        fs::File::create(&stale_path).await.unwrap();
+        // and a file which wasn't stored by extension-downloader
+        let unmanaged_path = chromium_extensions_dir.join("test.crx");
+        fs::File::create(&unmanaged_path).await.unwrap();
+
+        let lock_path = lockfile::path(&cfg_path);
+        _ = sync(
+            &load_config(&cfg_path, None).await.unwrap(),
+            &lock_path,
+            SyncMode::Update,
+        )
+        .await;
+
+        // check that stale file was removed, but the unmanaged one was kept
+        assert_eq!(
+            fs::metadata(stale_path).await.unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert!(fs::metadata(unmanaged_path).await.unwrap().is_file());
+
+        // check extension was downloaded
+        let crx_file = chromium_extensions_dir.join(format!("{extension_id}.crx"));
+        assert!(fs::metadata(&crx_file).await.unwrap().is_file());
+
+        // check that a symlink was created
+        let mut f = fs::File::open(
+            chromium_profile
+                .join("External Extensions")
+                .join(format!("{extension_id}.json")),
+        )
+        .await
+        .unwrap();
+        let mut content = String::with_capacity(4096);
+        f.read_to_string(&mut content).await.unwrap();
+        assert!(content.contains(crx_file.to_str().unwrap()));
+        assert!(content.contains("2.1.2"));
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_firefox() {
+        let mut server = mockito::Server::new_async().await;
+
+        let mut f = fs::File::open("tests/fixtures/vimium-ff.body.json")
+            .await
+            .unwrap();
+        let mut contents = String::new();
+        f.read_to_string(&mut contents).await.unwrap();
+        // adjust download url to use our mock server
+        let contents = contents.replace(
+            "https://addons.mozilla.org/firefox/downloads",
+            &format!("{}/firefox/downloads", server.url()),
+        );
+
+        let m1 = server
+            .mock("GET", "/api/v4/addons/addon/vimium-ff/")
+            .with_header("content-type", "application/json")
+            .with_body(&contents)
+            .with_status(200)
+            .create_async()
+            .await;
+        let m2 = server
+            .mock("GET", "/firefox/downloads/file/4259790/vimium_ff-2.1.2.xpi")
+            .with_header("content-type", "application/x-xpinstall")
+            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2-test-signed.xpi")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let tmp_dir = TempDir::new().unwrap();
+        let extensions_dir = tmp_dir.path().join("storage");
+        let firefox_profile = tmp_dir.path().join("profile/firefox");
+        let cfg = Config {
+            base_url_mozilla: Some(server.url()),
+            extensions_dir: Some(extensions_dir.clone()),
+            extensions: vec![config::Extension {
+                names: vec!["vimium-ff".into()],
+                browser: config::BrowserKind::Firefox,
+                user_data_dir: None,
+                browser_version: None,
+                install_mode: config::InstallMode::Profile,
+                link_mode: config::LinkMode::Symlink,
+                profile: firefox_profile.to_string_lossy().to_string(),
+            }],
+            mozilla_root_certificate: Some("tests/fixtures/test-root-ca.pem".into()),
+            ..Default::default()
+        };
+        let cfg_path = tmp_dir.path().join("config.toml");
+        fs::write(&cfg_path, toml::to_string(&cfg).unwrap())
+            .await
+            .unwrap();
+
+        // create stale extension
+        let ff_extensions_dir = extensions_dir.join("firefox");
+        fs::create_dir_all(&ff_extensions_dir).await.unwrap();
+        let stale_path = ff_extensions_dir.join("stale@example.com.xpi");
+        fs::File::create(&stale_path).await.unwrap();
+        // and a file which wasn't stored by extension-downloader
+        let unmanaged_path = ff_extensions_dir.join("test.crx");
+        fs::File::create(&unmanaged_path).await.unwrap();
+        // which is still installed in the profile
+        let stale_link = firefox_profile
+            .join("extensions")
+            .join("stale@example.com.xpi");
+        #[cfg(not(target_os = "windows"))]
+        {
+            fs::create_dir_all(stale_link.parent().unwrap())
+                .await
+                .unwrap();
+            fs::symlink(&stale_path, &stale_link).await.unwrap();
+        }
+
+        let lock_path = lockfile::path(&cfg_path);
+        _ = sync(
+            &load_config(&cfg_path, None).await.unwrap(),
+            &lock_path,
+            SyncMode::Update,
+        )
+        .await;
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+
+        // check that stale file was removed, but the unmanaged one was kept
+        assert_eq!(
+            fs::metadata(stale_path).await.unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert!(fs::symlink_metadata(stale_link).await.is_err());
+        assert!(fs::metadata(&unmanaged_path).await.unwrap().is_file());
+
+        // check that the file was downloaded
+        let mut count: i64 = 0;
+        let mut fnames: Vec<PathBuf> = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(ff_extensions_dir).await.unwrap();
+        while let Some(entry) = read_dir.next_entry().await.unwrap() {
+            if entry.path() != unmanaged_path {
+                fnames.push(entry.path());
+                count += 1;
+            }
+        }
+        assert_eq!(count, 1);
+        assert_eq!(
+            fnames
+                .first()
+                .unwrap()
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            "{d7742d87-e61d-4b78-b8a1-b469842139fa}.xpi"
+        );
+
+        // check that a symlink was created
+        let metadata = fs::symlink_metadata(
+            firefox_profile
+                .join("extensions")
+                .join("{d7742d87-e61d-4b78-b8a1-b469842139fa}.xpi"),
+        )
+        .await
+        .unwrap();
+        assert!(metadata.file_type().is_symlink());
+    }
+
+    #[tokio::test]
+    async fn test_download_error() {
+        let mut server = mockito::Server::new_async().await;
+
+        let mut f = fs::File::open("tests/fixtures/vimium-ff.body.json")
+            .await
+            .unwrap();
+        let mut contents = String::new();
+        f.read_to_string(&mut contents).await.unwrap();
+        // adjust download url to use our mock server
+        let contents = contents.replace(
+            "https://addons.mozilla.org/firefox/downloads",
+            &format!("{}/firefox/downloads", server.url()),
+        );
+
+        let m1 = server
+            .mock("GET", "/api/v4/addons/addon/vimium-ff/")
+            .with_header("content-type", "application/json")
+            .with_body(&contents)
+            .with_status(200)
+            .create_async()
+            .await;
+        let m2 = server
+            .mock("GET", "/firefox/downloads/file/4259790/vimium_ff-2.1.2.xpi")
+            .with_header("content-type", "application/x-xpinstall")
+            .with_body("Forbidden")
+            .with_status(403)
+            .create_async()
+            .await;
+
+        let tmp_dir = TempDir::new().unwrap();
+        let extensions_dir = tmp_dir.path().join("storage");
+        let firefox_profile = tmp_dir.path().join("profile/firefox");
+        let cfg = Config {
+            base_url_mozilla: Some(server.url()),
+            extensions_dir: Some(extensions_dir.clone()),
+            extensions: vec![config::Extension {
+                names: vec!["vimium-ff".into()],
+                browser: config::BrowserKind::Firefox,
+                user_data_dir: None,
+                browser_version: None,
+                install_mode: config::InstallMode::Profile,
+                link_mode: config::LinkMode::Symlink,
+                profile: firefox_profile.to_string_lossy().to_string(),
+            }],
+            mozilla_root_certificate: Some("tests/fixtures/test-root-ca.pem".into()),
+            ..Default::default()
+        };
+        let cfg_path = tmp_dir.path().join("config.toml");
+        fs::write(&cfg_path, toml::to_string(&cfg).unwrap())
+            .await
+            .unwrap();
+
+        let result = sync(
+            &load_config(&cfg_path, None).await.unwrap(),
+            &lockfile::path(&cfg_path),
+            SyncMode::Update,
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), 1);
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+    }
+
+    #[test]
+    fn test_get_extensions_dir() {
+        let cfg = Config {
+            ..Default::default()
+        };
+        let dir = get_extensions_dir(&cfg);
+        let components: Vec<Component> = dir.components().collect();
+        assert!(!components.is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_is_managed_name() {
+        use config::BrowserKind::{Chromium, Firefox};
+        let crx = Path::new("dbepggeogbaibhgnhhndojpepiihcmeb.crx");
+        assert!(is_managed_name(Chromium, crx));
+        assert!(!is_managed_name(Firefox, crx));
+        assert!(!is_managed_name(Chromium, Path::new("test.crx")));
+        assert!(!is_managed_name(
+            Chromium,
+            Path::new("dbepggeogbaibhgnhhndojpepiihcmez.crx")
+        ));
+        assert!(is_managed_name(
+            Firefox,
+            Path::new("{d7742d87-e61d-4b78-b8a1-b469842139fa}.xpi")
+        ));
+        assert!(is_managed_name(
+            Firefox,
+            Path::new("uBlock0@raymondhill.net.xpi")
+        ));
+        assert!(!is_managed_name(Firefox, Path::new("{d7742d87}.xpi")));
+        assert!(!is_managed_name(Firefox, Path::new("test.xpi")));
+        assert!(!is_managed_name(Firefox, Path::new("foo@.xpi")));
+    }
+
+    #[test]
+    fn test_store_dir() {
+        let mut cfg = Config {
+            extensions: vec![config::Extension {
+                browser: config::BrowserKind::Chromium,
+                user_data_dir: None,
+                browser_version: None,
+                install_mode: config::InstallMode::System,
+                link_mode: config::LinkMode::Symlink,
+                profile: "/usr/share/chromium/extensions".to_string(),
+                names: vec![],
+            }],
+            ..Default::default()
+        };
+        assert_eq!(Store::of(&cfg.extensions[0].target()), Store::System);
+        // extensions installed for all users don't move the extensions directory
+        assert_ne!(get_extensions_dir(&cfg), Path::new(SYSTEM_EXTENSIONS_DIR));
+        if cfg!(target_os = "linux") {
+            assert_eq!(Store::System.dir(&cfg), Path::new(SYSTEM_EXTENSIONS_DIR));
+        }
+
+        cfg.extensions_dir = Some(PathBuf::from("/srv/extensions"));
+        assert_eq!(Store::User.dir(&cfg), Path::new("/srv/extensions"));
+        assert_eq!(Store::System.dir(&cfg), Path::new("/srv/extensions/system"));
+
+        cfg.extensions[0].install_mode = config::InstallMode::Profile;
+        assert_eq!(Store::of(&cfg.extensions[0].target()), Store::User);
+    }
+
+    #[test]
+    fn test_main() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config_dir = tmp_dir.path().join("extension-downloader");
+        std::env::set_var(
+            "EXTENSION_DOWNLOADER_CONFIG_DIR",
+            config_dir.to_str().unwrap(),
+        );
+        let cfg = Config {
+            extensions_dir: Some(std::env::temp_dir()),
+            ..Default::default()
+        };
+        let content = toml::to_string(&cfg).unwrap();
+        let config_path = config_dir.join("config.toml");
+        {
+            std::fs::create_dir_all(config_path.parent().unwrap()).unwrap();
+            let mut f = std::fs::File::create(config_path).unwrap();
+            f.write_all(content.as_bytes()).unwrap();
+            f.flush().unwrap();
+        }
+        _ = execute(Cli::default());
+    }
+
+    #[tokio::test]
+    async fn test_load_config_not_found() {
+        let err = load_config("/does/not/exist.toml", None).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Config file \"/does/not/exist.toml\" does not exist"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_config_extensions_dir_override() {
+        let tmp_dir = TempDir::new().unwrap();
+        let cfg_path = tmp_dir.path().join("config.toml");
+        fs::write(&cfg_path, "extensions_dir = \"/tmp\"\nextensions = []\n")
+            .await
+            .unwrap();
+
+        let cfg = load_config(&cfg_path, None).await.unwrap();
+        assert_eq!(cfg.extensions_dir, Some(PathBuf::from("/tmp")));
+
+        let cfg = load_config(&cfg_path, Some(tmp_dir.path().join("override")))
+            .await
+            .unwrap();
+        assert_eq!(cfg.extensions_dir, Some(tmp_dir.path().join("override")));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[tokio::test]
+    async fn test_prune() {
+        let tmp_dir = TempDir::new().unwrap();
+        let extensions_dir = tmp_dir.path().join("storage");
+        let firefox_profile = tmp_dir.path().join("profile/firefox");
+        let ff_extensions_dir = extensions_dir.join("firefox");
+        fs::create_dir_all(&ff_extensions_dir).await.unwrap();
+        fs::create_dir_all(firefox_profile.join("extensions"))
+            .await
+            .unwrap();
+
+        let installed = ff_extensions_dir.join("installed.xpi");
+        fs::copy("tests/fixtures/vimium_ff-2.1.2.xpi", &installed)
+            .await
+            .unwrap();
+        fs::symlink(
+            &installed,
+            firefox_profile.join("extensions").join("installed.xpi"),
+        )
+        .await
+        .unwrap();
+        let orphan = ff_extensions_dir.join("orphan@example.com.xpi");
+        fs::File::create(&orphan).await.unwrap();
+
+        let cfg = Config {
+            extensions_dir: Some(extensions_dir.clone()),
+            extensions: vec![config::Extension {
+                names: vec!["vimium-ff".into()],
+                browser: config::BrowserKind::Firefox,
+                user_data_dir: None,
+                browser_version: None,
+                install_mode: config::InstallMode::Profile,
+                link_mode: config::LinkMode::Symlink,
+                profile: firefox_profile.to_string_lossy().to_string(),
+            }],
+            mozilla_root_certificate: Some("tests/fixtures/test-root-ca.pem".into()),
+            ..Default::default()
+        };
+
+        assert_eq!(status(&cfg).await.unwrap(), 0);
+        assert_eq!(list(&cfg).await.unwrap(), 0);
+        assert_eq!(prune(&cfg).await.unwrap(), 0);
+
+        assert!(fs::metadata(&installed).await.unwrap().is_file());
+        assert_eq!(
+            fs::metadata(orphan).await.unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+
+        // the state file records the remaining installation
+        let state = state::from_file(&state::path(&extensions_dir))
+            .await
+            .unwrap();
+        assert_eq!(state.artifacts.len(), 1);
+        assert_eq!(state.artifacts[0].path, installed);
+        assert_eq!(state.artifacts[0].version, "2.1.2");
+        assert_eq!(
+            state.artifacts[0].profiles,
+            [Target::from(firefox_profile.to_str().unwrap())]
+        );
+
+        // files which are not managed are left alone
+        let unmanaged = ff_extensions_dir.join("unmanaged.xpi");
+        fs::File::create(&unmanaged).await.unwrap();
+        assert_eq!(prune(&cfg).await.unwrap(), 0);
+        assert!(fs::metadata(&unmanaged).await.is_ok());
+
+        // modified files are reported
+        fs::write(&installed, "modified").await.unwrap();
+        assert_eq!(status(&cfg).await.unwrap(), 1);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[tokio::test]
+    async fn test_plan() {
+        let mut server = mockito::Server::new_async().await;
+
+        let extension_id = "dbepggeogbaibhgnhhndojpepiihcmeb";
+        let url = format!("/service/update2/crx?response=updatecheck&prodversion=119.0.6045.199&acceptformat=crx2,crx3&x=id%3D{extension_id}%26installsource%3Dondemand%26uc");
+        let m1 = server
+            .mock("GET", url.as_str())
+            .with_header("content-type", "text/xml")
+            .with_body(format!("<gupdate protocol=\"2.0\"><app appid=\"{extension_id}\" status=\"ok\"><updatecheck codebase=\"{}/vimium.crx\" status=\"ok\" version=\"2.1.2\"/></app></gupdate>", server.url()))
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let contents = std::fs::read_to_string("tests/fixtures/vimium-ff.body.json").unwrap();
+        let m2 = server
+            .mock("GET", "/api/v4/addons/addon/vimium-ff/")
+            .with_header("content-type", "application/json")
+            .with_body(&contents)
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let tmp_dir = TempDir::new().unwrap();
+        let extensions_dir = tmp_dir.path().join("storage");
+        let stale_path = extensions_dir.join("firefox").join("test.xpi");
+        fs::create_dir_all(stale_path.parent().unwrap())
+            .await
+            .unwrap();
+        fs::File::create(&stale_path).await.unwrap();
+
+        let cfg = Config {
+            base_url_mozilla: Some(server.url()),
+            base_url_google: Some(server.url()),
+            extensions_dir: Some(extensions_dir.clone()),
+            extensions: vec![
+                config::Extension {
+                    names: vec![extension_id.into()],
+                    browser: config::BrowserKind::Chromium,
+                    user_data_dir: None,
+                    browser_version: None,
+                    install_mode: config::InstallMode::Profile,
+                    link_mode: config::LinkMode::Symlink,
+                    profile: tmp_dir
+                        .path()
+                        .join("chromium")
+                        .to_string_lossy()
+                        .to_string(),
+                },
+                config::Extension {
+                    names: vec!["vimium-ff".into()],
+                    browser: config::BrowserKind::Firefox,
+                    user_data_dir: None,
+                    browser_version: None,
+                    install_mode: config::InstallMode::Profile,
+                    link_mode: config::LinkMode::Symlink,
+                    profile: tmp_dir.path().join("firefox").to_string_lossy().to_string(),
+                },
+            ],
+            mozilla_root_certificate: Some("tests/fixtures/test-root-ca.pem".into()),
+            ..Default::default()
+        };
+
+        assert_eq!(plan(&cfg).await.unwrap(), 0);
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+
+        // nothing was written or deleted
+        assert!(fs::metadata(&stale_path).await.is_ok());
+        assert!(fs::metadata(extensions_dir.join("chromium")).await.is_err());
+        assert!(fs::metadata(tmp_dir.path().join("chromium")).await.is_err());
+        assert!(fs::metadata(tmp_dir.path().join("firefox")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_lock_and_frozen() {
+        let mut server = mockito::Server::new_async().await;
+
+        let contents = std::fs::read_to_string("tests/fixtures/vimium-ff.body.json")
+            .unwrap()
+            .replace(
+                "https://addons.mozilla.org/firefox/downloads",
+                &format!("{}/firefox/downloads", server.url()),
+            );
+        let m1 = server
+            .mock("GET", "/api/v4/addons/addon/vimium-ff/")
+            .with_header("content-type", "application/json")
+            .with_body(&contents)
+            .with_status(200)
+            .expect(4)
+            .create_async()
+            .await;
+        let m2 = server
+            .mock("GET", "/firefox/downloads/file/4259790/vimium_ff-2.1.2.xpi")
+            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2-test-signed.xpi")
+            .with_status(200)
+            .expect(2)
+            .create_async()
+            .await;
+        let metadata: serde_json::Value = serde_json::from_str(&contents).unwrap();
+        let m3 = server
+            .mock("GET", "/api/v4/addons/addon/vimium-ff/versions/2.1.2/")
+            .with_header("content-type", "application/json")
+            .with_body(metadata["current_version"].to_string())
+            .with_status(200)
+            .create_async()
+            .await;
+        let m4 = server
+            .mock("GET", "/api/v4/addons/addon/vimium-ff/versions/2.1.1/")
+            .with_status(404)
+            .create_async()
+            .await;
+
+        let tmp_dir = TempDir::new().unwrap();
+        let firefox_profile = tmp_dir.path().join("profile/firefox");
+        let cfg = Config {
+            base_url_mozilla: Some(server.url()),
+            extensions_dir: Some(tmp_dir.path().join("storage")),
+            extensions: vec![config::Extension {
+                names: vec!["vimium-ff".into()],
+                browser: config::BrowserKind::Firefox,
+                user_data_dir: None,
+                browser_version: None,
+                install_mode: config::InstallMode::Profile,
+                link_mode: config::LinkMode::Symlink,
+                profile: firefox_profile.to_string_lossy().to_string(),
+            }],
+            mozilla_root_certificate: Some("tests/fixtures/test-root-ca.pem".into()),
+            ..Default::default()
+        };
+        let lock_path = tmp_dir.path().join("config.lock");
+
+        // frozen mode requires a lockfile
+        assert!(sync(&cfg, &lock_path, SyncMode::Frozen).await.is_err());
+
+        // locking downloads the extension but neither stores nor installs it
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Lock).await.unwrap(), 0);
+        assert!(fs::metadata(&firefox_profile).await.is_err());
+        let extensions_dir = tmp_dir.path().join("storage");
+        assert!(stored_files(&extensions_dir.join("firefox")).is_empty());
+        assert!(fs::metadata(lock_dir(&extensions_dir)).await.is_err());
+        let lockfile = lockfile::from_file(&lock_path).await.unwrap();
+        let locked = lockfile
+            .get(config::BrowserKind::Firefox, "vimium-ff")
+            .unwrap();
+        assert_eq!(locked.id, "{d7742d87-e61d-4b78-b8a1-b469842139fa}");
+        assert_eq!(locked.version, "2.1.2");
+        assert_eq!(
+            locked.url,
+            format!(
+                "{}/firefox/downloads/file/4259790/vimium_ff-2.1.2.xpi",
+                server.url()
+            )
+        );
+        assert_eq!(
+            locked.sha256,
+            "e6d90392951ac10fcff45d8f36573dda8de5889c067563d9799a9bcde59fde7d"
+        );
+
+        // frozen mode installs the locked version
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Frozen).await.unwrap(), 0);
+        assert_eq!(
+            firefox::installed_extensions(&cfg.extensions[0].target())
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // locking again reuses the stored file instead of downloading it
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Lock).await.unwrap(), 0);
+        assert_eq!(lockfile::from_file(&lock_path).await.unwrap(), lockfile);
+        assert_eq!(stored_files(&extensions_dir.join("firefox")).len(), 1);
+        assert!(fs::metadata(lock_dir(&extensions_dir)).await.is_err());
+
+        // and fails if upstream no longer offers the locked version
+        let mut lockfile = lockfile::from_file(&lock_path).await.unwrap();
+        lockfile.extensions[0].version = "2.1.1".to_string();
+        lockfile::write(&lock_path, lockfile).await.unwrap();
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Frozen).await.unwrap(), 1);
+
+        m1.assert_async().await;
+        m2.assert_async().await;
+        m3.assert_async().await;
+        m4.assert_async().await;
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[tokio::test]
+    async fn test_file_source() {
+        let tmp_dir = TempDir::new().unwrap();
+        let firefox_profile = tmp_dir.path().join("profile/firefox");
+        let xpi_path = "tests/fixtures/vimium_ff-2.1.2-test-signed.xpi";
+        let extension = config::Extension {
+            names: vec![ExtensionName::File {
+                path: xpi_path.to_string(),
+                sha256: None,
+            }],
+            browser: config::BrowserKind::Firefox,
+            user_data_dir: None,
+            browser_version: None,
+            install_mode: config::InstallMode::Profile,
+            link_mode: config::LinkMode::Symlink,
+            profile: firefox_profile.to_string_lossy().to_string(),
+        };
+        let mut cfg = Config {
+            extensions_dir: Some(tmp_dir.path().join("storage")),
+            // the same file in two entries is stored once
+            extensions: vec![extension.clone(), extension],
+            mozilla_root_certificate: Some("tests/fixtures/test-root-ca.pem".into()),
+            ..Default::default()
+        };
+        let lock_path = tmp_dir.path().join("config.lock");
+
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 0);
+        let stored = tmp_dir
+            .path()
+            .join("storage/firefox/{d7742d87-e61d-4b78-b8a1-b469842139fa}.xpi");
+        assert_eq!(
+            firefox::installed_extensions(&cfg.extensions[0].target())
+                .await
+                .unwrap(),
+            std::slice::from_ref(&stored)
+        );
+        let lockfile = lockfile::from_file(&lock_path).await.unwrap();
+        assert!(lockfile
+            .get(config::BrowserKind::Firefox, xpi_path)
+            .is_some());
+
+        // removing the entry uninstalls and purges the stored file
+        cfg.extensions.clear();
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 0);
+        assert!(
+            firefox::installed_extensions(&Target::from(firefox_profile.to_str().unwrap()))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(fs::metadata(&stored).await.is_err());
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[tokio::test]
+    async fn test_uninstall_error() {
+        let tmp_dir = TempDir::new().unwrap();
+        let extension = config::Extension {
+            names: vec![ExtensionName::File {
+                path: "tests/fixtures/vimium_ff-2.1.2-test-signed.xpi".to_string(),
+                sha256: None,
+            }],
+            browser: config::BrowserKind::Firefox,
+            user_data_dir: None,
+            browser_version: None,
+            install_mode: config::InstallMode::Profile,
+            link_mode: config::LinkMode::Symlink,
+            profile: tmp_dir
+                .path()
+                .join("profile/a")
+                .to_string_lossy()
+                .to_string(),
+        };
+        let broken = config::Extension {
+            profile: tmp_dir
+                .path()
+                .join("profile/b")
+                .to_string_lossy()
+                .to_string(),
+            ..extension.clone()
+        };
+        let mut cfg = Config {
+            extensions_dir: Some(tmp_dir.path().join("storage")),
+            extensions: vec![extension.clone(), broken.clone()],
+            mozilla_root_certificate: Some("tests/fixtures/test-root-ca.pem".into()),
+            ..Default::default()
+        };
+        let lock_path = tmp_dir.path().join("config.lock");
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 0);
+        let stored = tmp_dir
+            .path()
+            .join("storage/firefox/{d7742d87-e61d-4b78-b8a1-b469842139fa}.xpi");
+
+        // the failed removal is counted, the other one still happens
+        let broken_dir = Path::new(&broken.profile).join("extensions");
+        fs::remove_dir_all(&broken_dir).await.unwrap();
+        fs::write(&broken_dir, "").await.unwrap();
+        cfg.extensions.clear();
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 1);
+        assert!(firefox::installed_extensions(&extension.target())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(fs::metadata(&stored).await.is_ok());
+        let state = state::from_file(&state::path(&tmp_dir.path().join("storage")))
+            .await
+            .unwrap();
+        assert_eq!(state.artifacts[0].profiles, [broken.target()]);
+
+        // and retried by the next sync
+        fs::remove_file(&broken_dir).await.unwrap();
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 0);
+        assert!(fs::metadata(&stored).await.is_err());
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[tokio::test]
+    async fn test_unresolved_profile() {
+        let tmp_dir = TempDir::new().unwrap();
+        let firefox_profile = tmp_dir.path().join("profile/firefox");
+        let extension = config::Extension {
+            names: vec![ExtensionName::File {
+                path: "tests/fixtures/vimium_ff-2.1.2-test-signed.xpi".to_string(),
+                sha256: None,
+            }],
+            browser: config::BrowserKind::Firefox,
+            user_data_dir: None,
+            browser_version: None,
+            install_mode: config::InstallMode::Profile,
+            link_mode: config::LinkMode::Symlink,
+            profile: firefox_profile.to_string_lossy().to_string(),
+        };
+        // a profile name which can't be looked up
+        let unresolved = config::Extension {
+            user_data_dir: Some(tmp_dir.path().join("missing").to_string_lossy().to_string()),
+            profile: "work".to_string(),
+            ..extension.clone()
+        };
+        let mut cfg = Config {
+            extensions_dir: Some(tmp_dir.path().join("storage")),
+            extensions: vec![extension.clone(), unresolved.clone()],
+            mozilla_root_certificate: Some("tests/fixtures/test-root-ca.pem".into()),
+            ..Default::default()
+        };
+        let lock_path = tmp_dir.path().join("config.lock");
+
+        // only the unresolved entry fails
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 1);
+        let installed = firefox::installed_extensions(&extension.target())
+            .await
+            .unwrap();
+        assert_eq!(installed.len(), 1);
+
+        // the installations of an entry which can't be resolved are kept
+        cfg.extensions = vec![unresolved];
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 1);
+        assert_eq!(
+            firefox::installed_extensions(&extension.target())
+                .await
+                .unwrap(),
+            installed
+        );
+        assert!(fs::metadata(&installed[0]).await.is_ok());
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[tokio::test]
+    async fn test_held_update() {
+        let tmp_dir = TempDir::new().unwrap();
+        let extensions_dir = tmp_dir.path().join("storage");
+        let firefox_profile = tmp_dir.path().join("profile/firefox");
+        let xpi_path = "tests/fixtures/vimium_ff-2.1.2-test-signed.xpi";
+        let cfg = Config {
+            extensions_dir: Some(extensions_dir.clone()),
+            extensions: vec![config::Extension {
+                names: vec![ExtensionName::File {
+                    path: xpi_path.to_string(),
+                    sha256: None,
+                }],
+                browser: config::BrowserKind::Firefox,
+                user_data_dir: None,
+                browser_version: None,
+                install_mode: config::InstallMode::Profile,
+                link_mode: config::LinkMode::Symlink,
+                profile: firefox_profile.to_string_lossy().to_string(),
+            }],
+            mozilla_root_certificate: Some("tests/fixtures/test-root-ca.pem".into()),
+            hold_new_permissions: true,
+            ..Default::default()
+        };
+
+        // the installed version only requests the storage permission
+        let guid = "{d7742d87-e61d-4b78-b8a1-b469842139fa}";
+        let stored = extensions_dir.join(format!("firefox/{guid}.xpi"));
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        writer
+            .start_file("manifest.json", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(
+            &mut writer,
+            br#"{"name": "Vimium", "version": "2.1.1", "permissions": ["storage"]}"#,
+        )
+        .unwrap();
+        artifact::store(&stored, &writer.finish().unwrap().into_inner())
+            .await
+            .unwrap();
+        let target = cfg.extensions[0].target();
+        firefox::install_file(&stored, guid, &target).await.unwrap();
+        let installed = Artifact {
+            path: stored.clone(),
+            id: guid.to_string(),
+            version: "2.1.1".to_string(),
+            url: String::new(),
+            sha256: artifact::sha256(&stored).await.unwrap(),
+        };
+        let mut state = State::default();
+        state
+            .record(config::BrowserKind::Firefox, xpi_path, &installed)
+            .add_profile(&target);
+        state::write(&state::path(&extensions_dir), &mut state)
+            .await
+            .unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::{io::Write, path::Component};
-    use temp_dir::TempDir;
-    use tokio::{
-        fs::{self},
-        io::AsyncReadExt,
-    };
+        // the held update is no error and the installed version stays installed and locked
+        let lock_path = tmp_dir.path().join("config.lock");
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 0);
+        assert_eq!(
+            firefox::installed_extensions(&target).await.unwrap(),
+            std::slice::from_ref(&stored)
+        );
+        assert_eq!(artifact::sha256(&stored).await.unwrap(), installed.sha256);
+        let state = state::from_file(&state::path(&extensions_dir))
+            .await
+            .unwrap();
+        assert_eq!(state.pending.len(), 1);
+        assert_eq!(state.pending[0].version, "2.1.2");
+        let lockfile = lockfile::from_file(&lock_path).await.unwrap();
+        let locked = lockfile
+            .get(config::BrowserKind::Firefox, xpi_path)
+            .unwrap();
+        assert_eq!(locked.version, "2.1.1");
+        assert_eq!(locked.sha256, installed.sha256);
+    }
 
     #[cfg(not(target_os = "windows"))]
     #[tokio::test]
-    async fn test_chromium() {
+    async fn test_rollback() {
         let mut server = mockito::Server::new_async().await;
 
-        let extension_id = "dbepggeogbaibhgnhhndojpepiihcmeb";
-
-        let url = format!("/service/update2/crx?response=redirect&prodversion=119.0.6045.199&acceptformat=crx2,crx3&x=id%3D{extension_id}%26installsource%3Dondemand%26uc");
-        let url_str: &str = &url;
+        let contents = std::fs::read_to_string("tests/fixtures/vimium-ff.body.json")
+            .unwrap()
+            .replace(
+                "https://addons.mozilla.org/firefox/downloads",
+                &format!("{}/firefox/downloads", server.url()),
+            );
         let m1 = server
-            .mock("GET", url_str)
+            .mock("GET", "/api/v4/addons/addon/vimium-ff/")
             .with_header("content-type", "application/json")
-            .with_body_from_file("tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx")
+            .with_body(&contents)
+            .with_status(200)
+            .expect(2)
+            .create_async()
+            .await;
+        let m2 = server
+            .mock("GET", "/firefox/downloads/file/4259790/vimium_ff-2.1.2.xpi")
+            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2-test-signed.xpi")
             .with_status(200)
             .create_async()
             .await;
 
         let tmp_dir = TempDir::new().unwrap();
         let extensions_dir = tmp_dir.path().join("storage");
-        let chromium_profile = tmp_dir.path().join("profile/chromium");
+        let firefox_profile = tmp_dir.path().join("profile/firefox");
         let cfg = Config {
-            base_url_mozilla: None,
-            base_url_google: Some(server.url()),
+            base_url_mozilla: Some(server.url()),
             extensions_dir: Some(extensions_dir.clone()),
             extensions: vec![config::Extension {
-                names: vec![extension_id.to_owned()],
-                browser: config::BrowserKind::Chromium,
-                profile: chromium_profile.to_string_lossy().to_string(),
+                names: vec!["vimium-ff".into()],
+                browser: config::BrowserKind::Firefox,
+                user_data_dir: None,
+                browser_version: None,
+                install_mode: config::InstallMode::Profile,
+                link_mode: config::LinkMode::Symlink,
+                profile: firefox_profile.to_string_lossy().to_string(),
             }],
+            mozilla_root_certificate: Some("tests/fixtures/test-root-ca.pem".into()),
+            ..Default::default()
         };
-        let cfg_path = tmp_dir.path().join("config.toml");
-        fs::write(&cfg_path, toml::to_string(&cfg).unwrap())
-            .await
-            .unwrap();
+        let lock_path = tmp_dir.path().join("config.lock");
+        let guid = "{d7742d87-e61d-4b78-b8a1-b469842139fa}";
+        let current = extensions_dir.join("firefox").join(format!("{guid}.xpi"));
+        let link = firefox_profile
+            .join("extensions")
+            .join(format!("{guid}.xpi"));
 
-        let chromium_extensions_dir = extensions_dir.join("chromium");
-        // create stale extension
-        fs::create_dir_all(&chromium_extensions_dir).await.unwrap();
-        let stale_path = chromium_extensions_dir.join("test.crx");
-        fs::File::create(&stale_path).await.unwrap();
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 0);
+        let kept_dir = versions_dir(&extensions_dir).join("firefox").join(guid);
+        assert!(fs::metadata(kept_dir.join("2.1.2.xpi")).await.is_ok());
 
-        _ = run(&cfg_path).await;
+        // nothing older is kept yet
+        assert!(rollback(&cfg, "vimium-ff", None).await.is_err());
+        assert!(rollback(&cfg, "vimium-ff", Some("2.1.0")).await.is_err());
 
-        // check that stale file was removed
-        assert_eq!(
-            fs::metadata(stale_path).await.unwrap_err().kind(),
-            std::io::ErrorKind::NotFound
-        );
+        // pretend an older version was kept by a previous sync
+        let older = kept_dir.join("2.1.1.xpi");
+        fs::copy(&current, &older).await.unwrap();
+        let state_path = state::path(&extensions_dir);
+        let mut state = state::from_file(&state_path).await.unwrap();
+        let mut kept = state.artifacts[0].clone();
+        kept.path = older.clone();
+        kept.version = "2.1.1".to_string();
+        kept.archived = true;
+        kept.profiles.clear();
+        state.artifacts.push(kept);
+        state::write(&state_path, &mut state).await.unwrap();
 
-        // check extension was downloaded
-        let crx_file = chromium_extensions_dir.join(format!("{extension_id}.crx"));
-        assert!(fs::metadata(&crx_file).await.unwrap().is_file());
+        assert_eq!(rollback(&cfg, "vimium-ff", None).await.unwrap(), 0);
+        assert_eq!(fs::read_link(&link).await.unwrap(), older);
 
-        // check that a symlink was created
-        let mut f = fs::File::open(
-            chromium_profile
-                .join("External Extensions")
-                .join(format!("{extension_id}.json")),
-        )
-        .await
-        .unwrap();
-        let mut content = String::with_capacity(4096);
-        f.read_to_string(&mut content).await.unwrap();
-        assert!(content.contains(crx_file.to_str().unwrap()));
-        assert!(content.contains("2.1.2"));
+        // the rolled back version is kept by sync
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 0);
+        assert_eq!(fs::read_link(&link).await.unwrap(), older);
+        assert!(fs::metadata(&current).await.is_ok());
+
+        // until it is released
+        assert_eq!(release(&cfg, "vimium-ff").await.unwrap(), 0);
+        assert!(release(&cfg, "vimium-ff").await.is_err());
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 0);
+        assert_eq!(fs::read_link(&link).await.unwrap(), current);
+        let state = state::from_file(&state_path).await.unwrap();
+        let kept = state.artifacts.iter().find(|a| a.path == older).unwrap();
+        assert!(kept.profiles.is_empty() && !kept.pinned);
 
         m1.assert_async().await;
+        m2.assert_async().await;
     }
 
     #[tokio::test]
-    async fn test_firefox() {
+    async fn test_purge_after_error() {
         let mut server = mockito::Server::new_async().await;
-
-        let mut f = fs::File::open("tests/fixtures/vimium-ff.body.json")
-            .await
-            .unwrap();
-        let mut contents = String::new();
-        f.read_to_string(&mut contents).await.unwrap();
-        // adjust download url to use our mock server
-        let contents = contents.replace(
-            "https://addons.mozilla.org/firefox/downloads",
-            &format!("{}/firefox/downloads", server.url()),
-        );
-
         let m1 = server
             .mock("GET", "/api/v4/addons/addon/vimium-ff/")
-            .with_header("content-type", "application/json")
-            .with_body(&contents)
-            .with_status(200)
-            .create_async()
-            .await;
-        let m2 = server
-            .mock("GET", "/firefox/downloads/file/4259790/vimium_ff-2.1.2.xpi")
-            .with_header("content-type", "application/x-xpinstall")
-            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2.xpi")
-            .with_status(200)
+            .with_body("Not Found")
+            .with_status(404)
             .create_async()
             .await;
 
         let tmp_dir = TempDir::new().unwrap();
         let extensions_dir = tmp_dir.path().join("storage");
+        let ff_extensions_dir = extensions_dir.join("firefox");
+        fs::create_dir_all(&ff_extensions_dir).await.unwrap();
         let firefox_profile = tmp_dir.path().join("profile/firefox");
         let cfg = Config {
             base_url_mozilla: Some(server.url()),
-            base_url_google: None,
             extensions_dir: Some(extensions_dir.clone()),
             extensions: vec![config::Extension {
-                names: vec!["vimium-ff".to_string()],
+                names: vec!["vimium-ff".into()],
                 browser: config::BrowserKind::Firefox,
+                user_data_dir: None,
+                browser_version: None,
+                install_mode: config::InstallMode::Profile,
+                link_mode: config::LinkMode::Symlink,
                 profile: firefox_profile.to_string_lossy().to_string(),
             }],
+            mozilla_root_certificate: Some("tests/fixtures/test-root-ca.pem".into()),
+            ..Default::default()
         };
-        let cfg_path = tmp_dir.path().join("config.toml");
-        fs::write(&cfg_path, toml::to_string(&cfg).unwrap())
+
+        // the previous version of the failing extension, an extension which is no longer
+        // configured and a file which is not managed at all
+        let previous = ff_extensions_dir.join("{d7742d87-e61d-4b78-b8a1-b469842139fa}.xpi");
+        let removed = ff_extensions_dir.join("removed.xpi");
+        let unmanaged = ff_extensions_dir.join("unmanaged.xpi");
+        let mut state = State::default();
+        for (name, path) in [("vimium-ff", &previous), ("removed", &removed)] {
+            fs::copy("tests/fixtures/vimium_ff-2.1.2.xpi", path)
+                .await
+                .unwrap();
+            let artifact = Artifact {
+                path: path.clone(),
+                id: name.to_string(),
+                version: "2.1.1".to_string(),
+                url: String::new(),
+                sha256: String::new(),
+            };
+            state
+                .record(config::BrowserKind::Firefox, name, &artifact)
+                .add_profile(&cfg.extensions[0].target());
+        }
+        state::write(&state::path(&extensions_dir), &mut state)
             .await
             .unwrap();
+        fs::File::create(&unmanaged).await.unwrap();
 
-        // create stale extension
-        let ff_extensions_dir = extensions_dir.join("firefox");
-        fs::create_dir_all(&ff_extensions_dir).await.unwrap();
-        let stale_path = ff_extensions_dir.join("test.crx");
-        fs::File::create(&stale_path).await.unwrap();
+        let lock_path = tmp_dir.path().join("config.lock");
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 1);
 
-        _ = run(&cfg_path).await;
+        assert!(fs::metadata(&previous).await.is_ok());
+        assert!(fs::metadata(&removed).await.is_err());
+        assert!(fs::metadata(&unmanaged).await.is_ok());
+        assert!(fs::metadata(&lock_path).await.is_err());
+        let state = state::from_file(&state::path(&extensions_dir))
+            .await
+            .unwrap();
+        assert_eq!(state.artifacts.len(), 1);
+        assert_eq!(state.artifacts[0].profiles, [cfg.extensions[0].target()]);
 
         m1.assert_async().await;
-        m2.assert_async().await;
+    }
 
-        // check that stale file was removed
-        assert_eq!(
-            fs::metadata(stale_path).await.unwrap_err().kind(),
-            std::io::ErrorKind::NotFound
+    #[tokio::test]
+    async fn test_purge_grace_period() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("foo.xpi");
+        fs::File::create(&path).await.unwrap();
+        let mut state = State::default();
+        state.record(
+            config::BrowserKind::Firefox,
+            "foo",
+            &Artifact {
+                path: path.clone(),
+                id: "foo".to_string(),
+                version: "1.0".to_string(),
+                url: String::new(),
+                sha256: String::new(),
+            },
         );
+        let known_files = HashSet::new();
+        let day = 24 * 60 * 60;
 
-        // check that the file was downloaded
-        let mut count: i64 = 0;
-        let mut fnames: Vec<PathBuf> = Vec::new();
-        let mut read_dir = tokio::fs::read_dir(ff_extensions_dir).await.unwrap();
-        while let Some(entry) = read_dir.next_entry().await.unwrap() {
-            fnames.push(entry.path());
-            count += 1;
-        }
-        assert_eq!(count, 1);
+        // unused files are kept during the grace period
+        purge(&mut state, &known_files, day).await.unwrap();
+        assert!(fs::metadata(&path).await.is_ok());
+        let since = state.artifacts[0].unused_since.unwrap();
+        assert!(purgeable_files(&state, &known_files, day, since + day - 1).is_empty());
         assert_eq!(
-            fnames
-                .first()
-                .unwrap()
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap(),
-            "{d7742d87-e61d-4b78-b8a1-b469842139fa}.xpi"
+            purgeable_files(&state, &known_files, day, since + day),
+            std::slice::from_ref(&path)
         );
 
-        // check that a symlink was created
-        let metadata = fs::symlink_metadata(
-            firefox_profile
-                .join("extensions")
-                .join("{d7742d87-e61d-4b78-b8a1-b469842139fa}.xpi"),
-        )
-        .await
-        .unwrap();
-        assert!(metadata.file_type().is_symlink());
+        // using a file again restarts the grace period
+        purge(&mut state, &HashSet::from([path.clone()]), day)
+            .await
+            .unwrap();
+        assert!(state.artifacts[0].unused_since.is_none());
+
+        state.artifacts[0].unused_since = Some(since - day);
+        purge(&mut state, &known_files, day).await.unwrap();
+        assert!(fs::metadata(&path).await.is_err());
+        assert!(state.artifacts.is_empty());
     }
 
     #[tokio::test]
-    async fn test_download_error() {
+    async fn test_policy() {
         let mut server = mockito::Server::new_async().await;
-
-        let mut f = fs::File::open("tests/fixtures/vimium-ff.body.json")
-            .await
-            .unwrap();
-        let mut contents = String::new();
-        f.read_to_string(&mut contents).await.unwrap();
-        // adjust download url to use our mock server
-        let contents = contents.replace(
-            "https://addons.mozilla.org/firefox/downloads",
-            &format!("{}/firefox/downloads", server.url()),
-        );
-
+        let contents = std::fs::read_to_string("tests/fixtures/vimium-ff.body.json")
+            .unwrap()
+            .replace(
+                "https://addons.mozilla.org/firefox/downloads",
+                &format!("{}/firefox/downloads", server.url()),
+            );
         let m1 = server
             .mock("GET", "/api/v4/addons/addon/vimium-ff/")
-            .with_header("content-type", "application/json")
             .with_body(&contents)
             .with_status(200)
             .create_async()
             .await;
         let m2 = server
             .mock("GET", "/firefox/downloads/file/4259790/vimium_ff-2.1.2.xpi")
-            .with_header("content-type", "application/x-xpinstall")
-            .with_body("Forbidden")
-            .with_status(403)
+            .with_body_from_file("tests/fixtures/vimium_ff-2.1.2-test-signed.xpi")
+            .with_status(200)
             .create_async()
             .await;
 
@@ -361,71 +2515,183 @@ mod tests {
         let firefox_profile = tmp_dir.path().join("profile/firefox");
         let cfg = Config {
             base_url_mozilla: Some(server.url()),
-            base_url_google: None,
             extensions_dir: Some(extensions_dir.clone()),
             extensions: vec![config::Extension {
-                names: vec!["vimium-ff".to_string()],
+                names: vec!["vimium-ff".into()],
                 browser: config::BrowserKind::Firefox,
+                user_data_dir: None,
+                browser_version: None,
+                install_mode: config::InstallMode::Profile,
+                link_mode: config::LinkMode::Symlink,
                 profile: firefox_profile.to_string_lossy().to_string(),
             }],
+            policy: toml::from_str("[[deny]]\npermissions = [\"nativeMessaging\", \"tabs\"]")
+                .unwrap(),
+            mozilla_root_certificate: Some("tests/fixtures/test-root-ca.pem".into()),
+            ..Default::default()
         };
-        let cfg_path = tmp_dir.path().join("config.toml");
-        fs::write(&cfg_path, toml::to_string(&cfg).unwrap())
-            .await
-            .unwrap();
 
-        let result = run(&cfg_path).await;
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), 1);
+        let lock_path = tmp_dir.path().join("config.lock");
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 1);
+        assert!(stored_files(&extensions_dir.join("firefox")).is_empty());
+        assert!(fs::metadata(&firefox_profile).await.is_err());
 
         m1.assert_async().await;
         m2.assert_async().await;
     }
 
-    #[test]
-    fn test_get_extensions_dir() {
-        let cfg = Config {
-            base_url_mozilla: None,
-            base_url_google: None,
-            extensions_dir: None,
-            extensions: vec![],
+    #[tokio::test]
+    async fn test_chromium_policies() {
+        let mut server = mockito::Server::new_async().await;
+        let m1 = server
+            .mock("GET", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+
+        let extension_id = "dbepggeogbaibhgnhhndojpepiihcmeb";
+        let tmp_dir = TempDir::new().unwrap();
+        let extensions_dir = tmp_dir.path().join("storage");
+        let policy_file = tmp_dir.path().join("policies/managed/extensions.json");
+        let mut cfg = Config {
+            base_url_google: Some(server.url()),
+            extensions_dir: Some(extensions_dir.clone()),
+            extensions: vec![config::Extension {
+                names: vec![extension_id.into()],
+                browser: config::BrowserKind::Chromium,
+                user_data_dir: None,
+                browser_version: None,
+                install_mode: config::InstallMode::Policies,
+                link_mode: config::LinkMode::Symlink,
+                profile: policy_file.to_string_lossy().to_string(),
+            }],
+            ..Default::default()
         };
-        let dir = get_extensions_dir(&cfg);
-        let components: Vec<Component> = dir.components().collect();
-        assert!(!components.is_empty());
+
+        let lock_path = tmp_dir.path().join("config.lock");
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 0);
+        let policies = fs::read_to_string(&policy_file).await.unwrap();
+        assert!(policies.contains(extension_id));
+        assert!(stored_files(&extensions_dir.join("system/chromium")).is_empty());
+        let state = load_state(&cfg).await.unwrap();
+        assert!(state.artifacts.is_empty());
+        assert_eq!(state.policies.len(), 1);
+        assert_eq!(state.policies[0].name, extension_id);
+
+        // nothing to lock
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Lock).await.unwrap(), 0);
+        assert!(lockfile::from_file(&lock_path)
+            .await
+            .unwrap()
+            .extensions
+            .is_empty());
+
+        cfg.extensions[0].names.clear();
+        assert_eq!(sync(&cfg, &lock_path, SyncMode::Update).await.unwrap(), 0);
+        let policies = fs::read_to_string(&policy_file).await.unwrap();
+        assert!(!policies.contains(extension_id));
+        assert!(load_state(&cfg).await.unwrap().policies.is_empty());
+
+        m1.assert_async().await;
     }
 
-    #[test]
-    fn test_main() {
+    #[tokio::test]
+    async fn test_list_profiles() {
         let tmp_dir = TempDir::new().unwrap();
-        let config_dir = tmp_dir.path().join("extension-downloader");
-        std::env::set_var(
-            "EXTENSION_DOWNLOADER_CONFIG_DIR",
-            config_dir.to_str().unwrap(),
-        );
+        let user_data_dir = tmp_dir.path().join("brave");
+        fs::create_dir_all(&user_data_dir).await.unwrap();
+        fs::write(
+            user_data_dir.join("Local State"),
+            r#"{"profile": {"info_cache": {"Default": {"name": "Person 1"}}}}"#,
+        )
+        .await
+        .unwrap();
+
+        let mut cfg = Config {
+            extensions: vec![config::Extension {
+                browser: config::BrowserKind::Chromium,
+                user_data_dir: Some(user_data_dir.to_string_lossy().into_owned()),
+                browser_version: None,
+                install_mode: config::InstallMode::Profile,
+                link_mode: config::LinkMode::Symlink,
+                profile: user_data_dir.to_string_lossy().into_owned(),
+                names: vec![],
+            }],
+            ..Default::default()
+        };
+        assert_eq!(list_profiles(&cfg).await.unwrap(), 0);
+
+        cfg.extensions[0].user_data_dir = Some(tmp_dir.path().to_string_lossy().into_owned());
+        assert_eq!(list_profiles(&cfg).await.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_browser_version() {
+        let extension = |profile: &str, browser_version: Option<&str>| config::Extension {
+            browser: config::BrowserKind::Firefox,
+            user_data_dir: None,
+            browser_version: browser_version.map(str::to_string),
+            install_mode: config::InstallMode::Profile,
+            link_mode: config::LinkMode::Symlink,
+            profile: profile.to_string(),
+            names: vec!["vimium-ff".into()],
+        };
         let cfg = Config {
-            base_url_mozilla: None,
-            base_url_google: None,
-            extensions_dir: Some(std::env::temp_dir()),
-            extensions: vec![],
+            extensions: vec![
+                extension("/a", Some("128.0")),
+                extension("/b", Some("115.12.0")),
+                extension("/c", None),
+            ],
+            ..Default::default()
         };
-        let content = toml::to_string(&cfg).unwrap();
-        let config_path = config_dir.join("config.toml");
-        {
-            std::fs::create_dir_all(config_path.parent().unwrap()).unwrap();
-            let mut f = std::fs::File::create(config_path).unwrap();
-            f.write_all(content.as_bytes()).unwrap();
-            f.flush().unwrap();
-        }
-        _ = main();
+        let profiles = |p: &[&str]| p.iter().map(|p| Target::from(*p)).collect::<Vec<_>>();
+        assert_eq!(
+            browser_version(&cfg, config::BrowserKind::Firefox, &profiles(&["/a", "/b"])),
+            Some("115.12.0".to_string())
+        );
+        assert_eq!(
+            browser_version(&cfg, config::BrowserKind::Firefox, &profiles(&["/a", "/c"])),
+            Some("128.0".to_string())
+        );
+        assert_eq!(
+            browser_version(&cfg, config::BrowserKind::Firefox, &profiles(&["/c"])),
+            None
+        );
+        assert_eq!(
+            browser_version(&cfg, config::BrowserKind::Chromium, &profiles(&["/a"])),
+            None
+        );
     }
 
     #[tokio::test]
-    async fn test_run_config_not_found() {
-        let err = run("/does/not/exist.toml").await.unwrap_err();
+    async fn test_detect_browser_versions() {
+        let tmp_dir = TempDir::new().unwrap();
+        fs::write(
+            tmp_dir.path().join("compatibility.ini"),
+            "[Compatibility]\nLastVersion=128.0_20240704121409/20240704121409\n",
+        )
+        .await
+        .unwrap();
+        let extension = |browser_version: Option<&str>| config::Extension {
+            browser: config::BrowserKind::Firefox,
+            user_data_dir: None,
+            browser_version: browser_version.map(str::to_string),
+            install_mode: config::InstallMode::Profile,
+            link_mode: config::LinkMode::Symlink,
+            profile: tmp_dir.path().to_string_lossy().into_owned(),
+            names: vec!["vimium-ff".into()],
+        };
+        let mut cfg = Config {
+            extensions: vec![extension(None), extension(Some("115.12.0"))],
+            ..Default::default()
+        };
+
+        // configured versions are kept
+        detect_browser_versions(&mut cfg).await;
+        assert_eq!(cfg.extensions[0].browser_version.as_deref(), Some("128.0"));
         assert_eq!(
-            err.to_string(),
-            "Config file \"/does/not/exist.toml\" does not exist"
+            cfg.extensions[1].browser_version.as_deref(),
+            Some("115.12.0")
         );
     }
 }
@@ -1,16 +1,139 @@
 use anyhow::Result;
 use serde::Deserialize;
-use std::{io::Cursor, path::Path};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Cursor, Read, Seek};
+use std::{cmp::Ordering, env, path::Path};
 use tokio::{fs::File, io::AsyncReadExt};
 use tracing::debug;
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
 /// A (subset of a) browser extension manifest.json.
 /// The manifest.json file is the only file that every extension using WebExtension APIs must contain.
 /// See https://developer.mozilla.org/en-US/docs/Mozilla/Add-ons/WebExtensions/manifest.json
 pub struct Manifest {
     pub name: String,
     pub version: String,
+    pub short_name: Option<String>,
+    pub description: Option<String>,
+    /// The locale `__MSG_*__` placeholders are resolved in if the preferred one isn't available.
+    pub default_locale: Option<String>,
+    #[serde(default)]
+    pub manifest_version: u32,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    #[serde(default)]
+    pub optional_permissions: Vec<String>,
+    #[serde(default)]
+    pub host_permissions: Vec<String>,
+    #[serde(default)]
+    pub content_scripts: Vec<ContentScript>,
+    pub homepage_url: Option<String>,
+    /// The settings for Firefox, called `applications` in older manifests.
+    #[serde(alias = "applications")]
+    pub browser_specific_settings: Option<BrowserSpecificSettings>,
+    /// The minimum version of Chromium the extension requires.
+    pub minimum_chrome_version: Option<String>,
+    /// The URL of the update manifest of extensions not hosted by the browser vendor.
+    pub update_url: Option<String>,
+    /// The public key of a Chromium extension, which determines its ID.
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+/// See https://developer.mozilla.org/en-US/docs/Mozilla/Add-ons/WebExtensions/manifest.json/browser_specific_settings
+pub struct BrowserSpecificSettings {
+    pub gecko: Option<GeckoSettings>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+/// The Firefox specific settings of the manifest.
+pub struct GeckoSettings {
+    /// The add-on ID.
+    pub id: Option<String>,
+    pub strict_min_version: Option<String>,
+    /// The maximum version of Firefox, e.g. `128.0` or `128.*`.
+    pub strict_max_version: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+/// A content script of the manifest, see
+/// https://developer.mozilla.org/en-US/docs/Mozilla/Add-ons/WebExtensions/manifest.json/content_scripts
+pub struct ContentScript {
+    /// The match patterns of the pages the script is injected into.
+    #[serde(default)]
+    pub matches: Vec<String>,
+}
+
+impl Manifest {
+    /// gecko returns the Firefox specific settings, if any.
+    pub fn gecko(&self) -> Option<&GeckoSettings> {
+        self.browser_specific_settings.as_ref()?.gecko.as_ref()
+    }
+
+    /// gecko_id returns the add-on ID declared by the manifest, if any.
+    pub fn gecko_id(&self) -> Option<&str> {
+        self.gecko()?.id.as_deref()
+    }
+
+    /// firefox_incompatibility returns why the extension can't be loaded by the given version of
+    /// Firefox, if it can't.
+    pub fn firefox_incompatibility(&self, firefox_version: &str) -> Option<String> {
+        let gecko = self.gecko()?;
+        if let Some(min) = &gecko.strict_min_version {
+            if compare_versions(firefox_version, min) == Ordering::Less {
+                return Some(format!("requires Firefox {min} or newer"));
+            }
+        }
+        if let Some(max) = &gecko.strict_max_version {
+            if !satisfies_max_version(firefox_version, max) {
+                return Some(format!("requires Firefox {max} or older"));
+            }
+        }
+        None
+    }
+
+    /// chromium_incompatibility returns why the extension can't be loaded by the given version
+    /// of Chromium, if it can't.
+    pub fn chromium_incompatibility(&self, chromium_version: &str) -> Option<String> {
+        let min = self.minimum_chrome_version.as_ref()?;
+        match compare_versions(chromium_version, min) {
+            Ordering::Less => Some(format!("requires Chromium {min} or newer")),
+            _ => None,
+        }
+    }
+
+    /// added_permissions returns the (optional and host) permissions requested by this manifest
+    /// but not by `old`.
+    pub fn added_permissions(&self, old: &Manifest) -> Vec<String> {
+        let old = old.all_permissions();
+        self.all_permissions()
+            .into_iter()
+            .filter(|p| !old.contains(p))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// requests_permission returns whether the manifest requests the (optional or host) permission.
+    pub fn requests_permission(&self, permission: &str) -> bool {
+        self.all_permissions().contains(permission)
+    }
+
+    /// has_all_hosts_content_scripts returns whether a content script is injected into all hosts.
+    pub fn has_all_hosts_content_scripts(&self) -> bool {
+        self.content_scripts
+            .iter()
+            .flat_map(|script| &script.matches)
+            .any(|pattern| is_all_hosts(pattern))
+    }
+
+    fn all_permissions(&self) -> BTreeSet<&str> {
+        self.permissions
+            .iter()
+            .chain(&self.optional_permissions)
+            .chain(&self.host_permissions)
+            .map(String::as_str)
+            .collect()
+    }
 }
 
 impl std::fmt::Display for Manifest {
@@ -19,39 +142,353 @@ impl std::fmt::Display for Manifest {
     }
 }
 
-pub async fn from_file<P: AsRef<Path>>(path: P) -> Result<Manifest> {
+/// replace_messages replaces the `__MSG_<key>__` placeholders in `value` with the given messages.
+fn replace_messages(value: &str, messages: &HashMap<String, String>) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("__MSG_") {
+        let after = &rest[start + "__MSG_".len()..];
+        let Some(end) = after.find("__") else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        match messages.get(&after[..end].to_lowercase()) {
+            Some(message) => result.push_str(message),
+            None => result.push_str(&rest[start..start + "__MSG_".len() + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// satisfies_max_version returns whether `version` is at most `max`, where a trailing `*` of
+/// `max` matches any remaining parts (e.g. `128.*` matches `128.0.3`).
+pub fn satisfies_max_version(version: &str, max: &str) -> bool {
+    match max.strip_suffix(".*").or(max.strip_suffix('*')) {
+        Some(prefix) => {
+            let parts = prefix.split('.').filter(|p| !p.is_empty()).count();
+            let truncated: Vec<&str> = version.split('.').take(parts).collect();
+            compare_versions(&truncated.join("."), prefix) != Ordering::Greater
+        }
+        None => compare_versions(version, max) != Ordering::Greater,
+    }
+}
+
+/// is_all_hosts returns whether the match pattern matches every host, e.g. `<all_urls>` or
+/// `*://*/*`.
+fn is_all_hosts(pattern: &str) -> bool {
+    pattern == "<all_urls>"
+        || pattern
+            .split_once("://")
+            .is_some_and(|(_, rest)| rest == "*" || rest.starts_with("*/"))
+}
+
+/// compare_versions compares two dot-separated extension versions (e.g. `1.57.2` and `1.57.10`).
+/// Each part is compared numerically by its leading digits, the remainder lexicographically
+/// where a pre-release suffix (e.g. `2.0b1`) sorts before the plain number.
+/// Missing parts are treated as `0`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn split(part: &str) -> (u64, Option<&str>) {
+        let idx = part
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(part.len());
+        let suffix = &part[idx..];
+        (
+            part[..idx].parse().unwrap_or(0),
+            (!suffix.is_empty()).then_some(suffix),
+        )
+    }
+
+    let mut a_parts = a.split('.');
+    let mut b_parts = b.split('.');
+    loop {
+        match (a_parts.next(), b_parts.next()) {
+            (None, None) => return Ordering::Equal,
+            (a_part, b_part) => {
+                let (a_num, a_suffix) = split(a_part.unwrap_or("0"));
+                let (b_num, b_suffix) = split(b_part.unwrap_or("0"));
+                let ordering = a_num.cmp(&b_num).then_with(|| match (a_suffix, b_suffix) {
+                    (Some(a), Some(b)) => a.cmp(b),
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                });
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+        }
+    }
+}
+
+pub async fn from_file<P: AsRef<Path>>(path: P, locale: Option<&str>) -> Result<Manifest> {
     debug!("Parsing manifest {:?}", path.as_ref());
     let mut file = File::open(&path).await?;
     let mut contents = Vec::new();
     file.read_to_end(&mut contents).await?;
-    from_bytes(&contents)
+    from_bytes(&contents, locale)
 }
 
-pub fn from_bytes(contents: &[u8]) -> Result<Manifest> {
+/// from_bytes parses the manifest of an extension archive and resolves the `__MSG_*__`
+/// placeholders of its name and description.
+///
+/// * `locale` - The locale (e.g. `de` or `pt_BR`) to resolve them in. Without one, the locale
+///   of the environment is used.
+pub fn from_bytes(contents: &[u8], locale: Option<&str>) -> Result<Manifest> {
+    match locale {
+        Some(locale) => from_bytes_localized(contents, Some(locale)),
+        None => from_bytes_localized(contents, environment_locale().as_deref()),
+    }
+}
+
+fn from_bytes_localized(contents: &[u8], locale: Option<&str>) -> Result<Manifest> {
     let cursor = Cursor::new(contents);
     let mut archive = zip::ZipArchive::new(cursor)?;
     let manifest_file = archive.by_name("manifest.json")?;
-    let manifest: Manifest = serde_json::from_reader(manifest_file)?;
+    let mut manifest: Manifest = serde_json::from_reader(manifest_file)?;
+    manifest.localize(&mut archive, locale);
     debug!("Parsed {:?}", manifest);
     Ok(manifest)
 }
 
+/// environment_locale returns the locale of the environment (`LC_ALL`, `LC_MESSAGES`, `LANG`).
+fn environment_locale() -> Option<String> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .map(|value| {
+            value
+                .split(['.', '@'])
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        })
+        .find(|locale| !locale.is_empty() && locale != "C" && locale != "POSIX")
+}
+
+#[derive(Deserialize)]
+/// An entry of `_locales/<locale>/messages.json`.
+struct Message {
+    message: String,
+}
+
+impl Manifest {
+    /// localize replaces the `__MSG_*__` placeholders of the name, short name and description
+    /// with the messages of the preferred locale, falling back to its language and the default
+    /// locale. Unknown placeholders are kept.
+    fn localize<R: Read + Seek>(&mut self, archive: &mut zip::ZipArchive<R>, locale: Option<&str>) {
+        let fields = [
+            Some(&mut self.name),
+            self.short_name.as_mut(),
+            self.description.as_mut(),
+        ];
+        if !fields
+            .iter()
+            .flatten()
+            .any(|field| field.contains("__MSG_"))
+        {
+            return;
+        }
+
+        let mut locales: Vec<String> = Vec::new();
+        if let Some(locale) = locale {
+            let locale = locale.replace('-', "_");
+            if let Some((language, _)) = locale.split_once('_') {
+                locales.push(locale.clone());
+                locales.push(language.to_string());
+            } else {
+                locales.push(locale);
+            }
+        }
+        locales.extend(self.default_locale.clone());
+
+        // keys are case-insensitive, the first locale providing a message wins
+        let mut messages: HashMap<String, String> = HashMap::new();
+        for locale in locales {
+            let Ok(mut file) = archive.by_name(&format!("_locales/{locale}/messages.json")) else {
+                continue;
+            };
+            let mut contents = String::new();
+            if file.read_to_string(&mut contents).is_err() {
+                continue;
+            }
+            match serde_json::from_str::<HashMap<String, Message>>(
+                contents.trim_start_matches('\u{feff}'),
+            ) {
+                Ok(entries) => {
+                    for (key, entry) in entries {
+                        messages.entry(key.to_lowercase()).or_insert(entry.message);
+                    }
+                }
+                Err(err) => debug!("Failed to parse messages of locale {locale}: {err}"),
+            }
+        }
+
+        for field in fields.into_iter().flatten() {
+            *field = replace_messages(field, &messages);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[tokio::test]
     async fn test_from_file() {
-        let manifest = from_file("tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx")
+        let manifest = from_file("tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx", None)
             .await
             .unwrap();
         assert_eq!(
             manifest,
             Manifest {
                 name: "Vimium".to_string(),
-                version: "2.1.2".to_string()
+                version: "2.1.2".to_string(),
+                description: Some("The Hacker's Browser. Vimium provides keyboard shortcuts for navigation and control in the spirit of Vim.".to_string()),
+                manifest_version: 3,
+                permissions: [
+                    "tabs",
+                    "bookmarks",
+                    "history",
+                    "storage",
+                    "sessions",
+                    "notifications",
+                    "scripting",
+                    "favicon",
+                    "webNavigation"
+                ]
+                .map(String::from)
+                .to_vec(),
+                optional_permissions: vec![],
+                host_permissions: vec!["<all_urls>".to_string()],
+                content_scripts: vec![
+                    ContentScript {
+                        matches: vec!["<all_urls>".to_string()],
+                    },
+                    ContentScript {
+                        matches: vec!["file:///".to_string(), "file:///*/".to_string()],
+                    },
+                ],
+                minimum_chrome_version: Some("105.0".to_string()),
+                update_url: Some("https://clients2.google.com/service/update2/crx".to_string()),
+                ..Default::default()
             }
         );
+        assert!(manifest.has_all_hosts_content_scripts());
+        assert!(manifest.requests_permission("<all_urls>"));
+        assert!(!manifest.requests_permission("proxy"));
+    }
+
+    fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            writer
+                .start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut writer, contents.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_localize() {
+        let contents = archive(&[
+            (
+                "manifest.json",
+                r#"{"name": "__MSG_appName__", "version": "1.0", "short_name": "__MSG_shortName__",
+                    "description": "__MSG_appDesc__ (__MSG_missing__)", "default_locale": "en"}"#,
+            ),
+            (
+                "_locales/en/messages.json",
+                r#"{"appName": {"message": "Dark Reader"}, "shortName": {"message": "Dark"},
+                    "appDesc": {"message": "Dark mode"}}"#,
+            ),
+            (
+                "_locales/de/messages.json",
+                "\u{feff}{\"APPNAME\": {\"message\": \"Dunkler Leser\", \"description\": \"name\"}}",
+            ),
+            ("_locales/pt_BR/messages.json", "not json"),
+        ]);
+
+        let manifest = from_bytes(&contents, Some("de-AT")).unwrap();
+        assert_eq!(manifest.name, "Dunkler Leser");
+        assert_eq!(manifest.short_name.as_deref(), Some("Dark"));
+        assert_eq!(
+            manifest.description.as_deref(),
+            Some("Dark mode (__MSG_missing__)")
+        );
+
+        let manifest = from_bytes(&contents, Some("pt_BR")).unwrap();
+        assert_eq!(manifest.name, "Dark Reader");
+        let manifest = from_bytes_localized(&contents, None).unwrap();
+        assert_eq!(manifest.name, "Dark Reader");
+    }
+
+    #[tokio::test]
+    async fn test_browser_specific_settings() {
+        let manifest = from_file("tests/fixtures/vimium_ff-2.1.2.xpi", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            manifest.gecko_id(),
+            Some("{d7742d87-e61d-4b78-b8a1-b469842139fa}")
+        );
+        assert_eq!(manifest.minimum_chrome_version.as_deref(), Some("105.0"));
+        assert_eq!(
+            manifest.firefox_incompatibility("111.0.1").as_deref(),
+            Some("requires Firefox 112.0 or newer")
+        );
+        assert!(manifest.firefox_incompatibility("112.0").is_none());
+        assert_eq!(
+            manifest
+                .chromium_incompatibility("104.0.5112.101")
+                .as_deref(),
+            Some("requires Chromium 105.0 or newer")
+        );
+        assert!(manifest
+            .chromium_incompatibility("119.0.6045.199")
+            .is_none());
+
+        let legacy: Manifest = serde_json::from_str(
+            r#"{"name": "foo", "version": "1.0",
+                "applications": {"gecko": {"id": "foo@example.com", "strict_max_version": "56.*"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(legacy.gecko_id(), Some("foo@example.com"));
+        assert!(legacy.firefox_incompatibility("56.0.2").is_none());
+        assert_eq!(
+            legacy.firefox_incompatibility("57.0").as_deref(),
+            Some("requires Firefox 56.* or older")
+        );
+    }
+
+    #[test]
+    fn test_is_all_hosts() {
+        assert!(is_all_hosts("<all_urls>"));
+        assert!(is_all_hosts("*://*/*"));
+        assert!(is_all_hosts("https://*/"));
+        assert!(!is_all_hosts("https://*.example.com/*"));
+        assert!(!is_all_hosts("file:///*"));
+    }
+
+    #[test]
+    fn test_added_permissions() {
+        let old = Manifest {
+            permissions: vec!["storage".to_string(), "https://a.example/*".to_string()],
+            ..Default::default()
+        };
+        let new = Manifest {
+            permissions: vec!["storage".to_string(), "nativeMessaging".to_string()],
+            optional_permissions: vec!["https://a.example/*".to_string()],
+            host_permissions: vec!["<all_urls>".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            new.added_permissions(&old),
+            ["<all_urls>", "nativeMessaging"]
+        );
+        assert!(old.added_permissions(&new).is_empty());
     }
 
     #[test]
@@ -59,7 +496,19 @@ mod tests {
         let manifest = Manifest {
             name: "Vimium".to_string(),
             version: "2.1.2".to_string(),
+            ..Default::default()
         };
         assert_eq!(format!("{manifest}"), "Vimium v2.1.2");
     }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("2.1.2", "2.1.2"), Ordering::Equal);
+        assert_eq!(compare_versions("2.1", "2.1.0"), Ordering::Equal);
+        assert_eq!(compare_versions("1.57.10", "1.57.2"), Ordering::Greater);
+        assert_eq!(compare_versions("1.9", "1.10"), Ordering::Less);
+        assert_eq!(compare_versions("2.0", "2.0b1"), Ordering::Greater);
+        assert_eq!(compare_versions("2.0a1", "2.0b1"), Ordering::Less);
+        assert_eq!(compare_versions("3", "2.99.99"), Ordering::Greater);
+    }
 }
@@ -0,0 +1,123 @@
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+/// A single change a sync would perform.
+pub enum Change {
+    /// The extension is not stored yet and would be downloaded.
+    Download {
+        name: String,
+        version: String,
+        path: PathBuf,
+    },
+    /// The stored extension would be replaced by a newer version.
+    Update {
+        name: String,
+        from: String,
+        to: String,
+        path: PathBuf,
+    },
+    /// The extension would be installed into a profile.
+    Install {
+        location: PathBuf,
+        source: PathBuf,
+        version: String,
+    },
+    /// The extension installed in a profile would be upgraded.
+    Upgrade {
+        location: PathBuf,
+        from: String,
+        to: String,
+    },
+    /// The extension would be removed from a profile it is no longer configured for.
+    Uninstall { name: String, profile: String },
+    /// A stored file would be deleted.
+    Delete { path: PathBuf },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Download {
+                name,
+                version,
+                path,
+            } => write!(f, "+ download {name} {version} to {}", path.display()),
+            Change::Update {
+                name,
+                from,
+                to,
+                path,
+            } => write!(f, "~ update {name} {from} -> {to} ({})", path.display()),
+            Change::Install {
+                location,
+                source,
+                version,
+            } => write!(
+                f,
+                "+ install {} -> {} ({version})",
+                location.display(),
+                source.display()
+            ),
+            Change::Upgrade { location, from, to } => {
+                write!(f, "~ upgrade {} {from} -> {to}", location.display())
+            }
+            Change::Uninstall { name, profile } => write!(f, "- uninstall {name} from {profile}"),
+            Change::Delete { path } => write!(f, "- delete {}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// The changes a sync would perform for a single extension.
+pub struct Plan {
+    /// The file the extension would be stored as (if any).
+    pub file: Option<PathBuf>,
+    pub changes: Vec<Change>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let changes = [
+            Change::Download {
+                name: "vimium-ff".to_string(),
+                version: "2.1.2".to_string(),
+                path: PathBuf::from("/storage/firefox/vimium.xpi"),
+            },
+            Change::Update {
+                name: "vimium-ff".to_string(),
+                from: "2.1.0".to_string(),
+                to: "2.1.2".to_string(),
+                path: PathBuf::from("/storage/firefox/vimium.xpi"),
+            },
+            Change::Install {
+                location: PathBuf::from("/profile/extensions/vimium.xpi"),
+                source: PathBuf::from("/storage/firefox/vimium.xpi"),
+                version: "2.1.2".to_string(),
+            },
+            Change::Upgrade {
+                location: PathBuf::from("/profile/External Extensions/vimium.json"),
+                from: "2.1.0".to_string(),
+                to: "2.1.2".to_string(),
+            },
+            Change::Delete {
+                path: PathBuf::from("/storage/firefox/old.xpi"),
+            },
+        ];
+        let lines: Vec<String> = changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "+ download vimium-ff 2.1.2 to /storage/firefox/vimium.xpi",
+                "~ update vimium-ff 2.1.0 -> 2.1.2 (/storage/firefox/vimium.xpi)",
+                "+ install /profile/extensions/vimium.xpi -> /storage/firefox/vimium.xpi (2.1.2)",
+                "~ upgrade /profile/External Extensions/vimium.json 2.1.0 -> 2.1.2",
+                "- delete /storage/firefox/old.xpi",
+            ]
+        );
+    }
+}
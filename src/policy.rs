@@ -0,0 +1,182 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::Manifest;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+/// The policy decides which extensions may be installed based on their manifest.
+/// An extension is rejected if it matches a deny rule and no allow rule.
+pub struct Policy {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<Rule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+/// A rule matches an extension if all of its conditions match. A rule without conditions matches
+/// every extension.
+pub struct Rule {
+    /// Matches the extensions with these names as used in the config.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub names: Vec<String>,
+    /// Matches extensions which request any of these (optional or host) permissions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub permissions: Vec<String>,
+    /// Matches extensions with this manifest version.
+    pub manifest_version: Option<u32>,
+    /// Matches extensions which do (or don't) run content scripts on all hosts.
+    pub all_hosts_content_scripts: Option<bool>,
+}
+
+impl Policy {
+    /// check fails if the extension is denied by the policy.
+    pub fn check(&self, name: &str, manifest: &Manifest) -> Result<()> {
+        if self
+            .allow
+            .iter()
+            .any(|rule| rule.matches(name, manifest).is_some())
+        {
+            return Ok(());
+        }
+        for rule in &self.deny {
+            if let Some(reasons) = rule.matches(name, manifest) {
+                return Err(anyhow!(
+                    "{name}: denied by policy ({})",
+                    match reasons.is_empty() {
+                        true => "all extensions are denied".to_string(),
+                        false => reasons.join(", "),
+                    }
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Rule {
+    /// matches returns why the rule matches the extension, if it does.
+    fn matches(&self, name: &str, manifest: &Manifest) -> Option<Vec<String>> {
+        let mut reasons = Vec::new();
+        if !self.names.is_empty() {
+            if !self.names.iter().any(|n| n == name) {
+                return None;
+            }
+            reasons.push(format!("name {name}"));
+        }
+        if !self.permissions.is_empty() {
+            let requested: Vec<&str> = self
+                .permissions
+                .iter()
+                .filter(|p| manifest.requests_permission(p))
+                .map(String::as_str)
+                .collect();
+            if requested.is_empty() {
+                return None;
+            }
+            reasons.push(format!("requests {}", requested.join(", ")));
+        }
+        if let Some(version) = self.manifest_version {
+            if manifest.manifest_version != version {
+                return None;
+            }
+            reasons.push(format!("manifest version {version}"));
+        }
+        if let Some(all_hosts) = self.all_hosts_content_scripts {
+            if manifest.has_all_hosts_content_scripts() != all_hosts {
+                return None;
+            }
+            reasons.push(match all_hosts {
+                true => "content scripts on all hosts".to_string(),
+                false => "no content scripts on all hosts".to_string(),
+            });
+        }
+        Some(reasons)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ContentScript;
+
+    #[test]
+    fn test_check() {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[allow]]
+            names = ["trusted"]
+
+            [[deny]]
+            permissions = ["debugger", "proxy", "nativeMessaging"]
+
+            [[deny]]
+            manifest_version = 2
+
+            [[deny]]
+            all_hosts_content_scripts = true
+            "#,
+        )
+        .unwrap();
+
+        let manifest = Manifest {
+            manifest_version: 3,
+            permissions: vec!["storage".to_string()],
+            content_scripts: vec![ContentScript {
+                matches: vec!["https://example.com/*".to_string()],
+            }],
+            ..Default::default()
+        };
+        policy.check("foo", &manifest).unwrap();
+
+        let denied = Manifest {
+            permissions: vec!["proxy".to_string(), "debugger".to_string()],
+            ..manifest
+        };
+        assert_eq!(
+            policy.check("foo", &denied).unwrap_err().to_string(),
+            "foo: denied by policy (requests debugger, proxy)"
+        );
+        policy.check("trusted", &denied).unwrap();
+
+        let mv2 = Manifest {
+            manifest_version: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.check("foo", &mv2).unwrap_err().to_string(),
+            "foo: denied by policy (manifest version 2)"
+        );
+
+        let all_hosts = Manifest {
+            manifest_version: 3,
+            content_scripts: vec![ContentScript {
+                matches: vec!["*://*/*".to_string()],
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.check("foo", &all_hosts).unwrap_err().to_string(),
+            "foo: denied by policy (content scripts on all hosts)"
+        );
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let policy = Policy {
+            allow: vec![Rule {
+                names: vec!["foo".to_string()],
+                ..Default::default()
+            }],
+            deny: vec![Rule::default()],
+        };
+        policy.check("foo", &Manifest::default()).unwrap();
+        assert_eq!(
+            policy
+                .check("bar", &Manifest::default())
+                .unwrap_err()
+                .to_string(),
+            "bar: denied by policy (all extensions are denied)"
+        );
+    }
+}
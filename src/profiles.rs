@@ -0,0 +1,600 @@
+use anyhow::{anyhow, Result};
+use ini::Ini;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::debug;
+
+use crate::chromium::managed;
+use crate::config::{BrowserKind, Extension, InstallMode};
+use crate::enterprise;
+
+/// The `profile` which selects all profiles found.
+pub const ALL_PROFILES: &str = "*";
+
+/// The `profile` which selects the default profile.
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A Firefox profile listed in `profiles.ini`.
+pub struct FirefoxProfile {
+    pub name: String,
+    pub path: PathBuf,
+    /// The `Path=` entry as written in `profiles.ini`.
+    raw_path: String,
+    /// Whether the profile is marked with `Default=1` (used by Firefox before version 67).
+    legacy_default: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct LocalState {
+    #[serde(default)]
+    profile: ProfileState,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ProfileState {
+    #[serde(default)]
+    info_cache: BTreeMap<String, ProfileInfo>,
+    last_used: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileInfo {
+    #[serde(default)]
+    name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A profile found by `list`.
+pub struct Profile {
+    pub name: String,
+    pub path: PathBuf,
+    pub is_default: bool,
+}
+
+/// resolve returns the profile directories the given config entry refers to.
+///
+/// Profiles can be referred to by their directory, their name, `default` or `*`. Names are
+/// looked up in the `profiles.ini` (Firefox) or `Local State` (Chromium) in `user_data_dir`.
+///
+/// Chromium reads `External Extensions` from the user data directory and offers them to all of
+/// its profiles, so Chromium profiles always resolve to their user data directory and a profile
+/// name is only accepted if the user data directory contains no other profile.
+///
+/// Entries installed using policies resolve to the policies file instead, system-wide entries to
+/// the system extension directory and unpacked entries to the directory they are extracted to.
+pub async fn resolve(ext: &Extension) -> Result<Vec<String>> {
+    if ext.install_mode == InstallMode::Policies {
+        return match ext.browser {
+            BrowserKind::Firefox => Ok(vec![enterprise::policies_path(&ext.profile)?]),
+            // the policies are read from the registry
+            BrowserKind::Chromium if cfg!(target_os = "windows") => Err(anyhow!(
+                "install_mode 'policies' is not supported for Chromium on Windows"
+            )),
+            BrowserKind::Chromium => Ok(vec![managed::policy_path(&ext.profile)?]),
+        };
+    }
+    if ext.install_mode == InstallMode::System {
+        if !Path::new(&ext.profile).is_absolute() {
+            return Err(anyhow!(
+                "install_mode 'system' requires the system extension directory as profile, got '{}'",
+                ext.profile
+            ));
+        }
+        return Ok(vec![ext.profile.clone()]);
+    }
+    if ext.install_mode == InstallMode::Unpacked {
+        if ext.browser == BrowserKind::Chromium && cfg!(target_os = "windows") {
+            return Err(anyhow!(
+                "install_mode 'unpacked' is not supported for Chromium on Windows"
+            ));
+        }
+        if !Path::new(&ext.profile).is_absolute() {
+            return Err(anyhow!(
+                "install_mode 'unpacked' requires the directory to extract the extensions to as profile, got '{}'",
+                ext.profile
+            ));
+        }
+        return Ok(vec![ext.profile.clone()]);
+    }
+    if Path::new(&ext.profile).is_absolute() {
+        return Ok(vec![ext.profile.clone()]);
+    }
+    let profiles = match ext.browser {
+        // the profile is a registry key
+        BrowserKind::Chromium if cfg!(target_os = "windows") => {
+            return Ok(vec![ext.profile.clone()])
+        }
+        BrowserKind::Chromium => {
+            let root = ext.user_data_dir.as_ref().ok_or_else(|| {
+                anyhow!(
+                    "Chromium profile '{}' requires a user_data_dir",
+                    ext.profile
+                )
+            })?;
+            vec![resolve_chromium(Path::new(root), &ext.profile).await?]
+        }
+        BrowserKind::Firefox => {
+            let root = match user_data_dir(ext) {
+                Some(root) => root,
+                None => default_firefox_dir()?,
+            };
+            resolve_firefox(&root, &ext.profile).await?
+        }
+    };
+    debug!(
+        "Resolved {} profile {:?} to {:?}",
+        ext.browser, ext.profile, profiles
+    );
+    Ok(profiles
+        .into_iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect())
+}
+
+/// resolve_firefox looks up the profile `name` in the `profiles.ini` in `root`.
+async fn resolve_firefox(root: &Path, name: &str) -> Result<Vec<PathBuf>> {
+    let profiles = firefox_profiles(root).await?;
+    if name == ALL_PROFILES {
+        return Ok(profiles.into_iter().map(|p| p.path).collect());
+    }
+    if name == DEFAULT_PROFILE {
+        let default = firefox_default_profile(root, &profiles).await?;
+        return default
+            .map(|p| vec![p.path.clone()])
+            .ok_or_else(|| anyhow!("No default Firefox profile found in {:?}", root));
+    }
+    profiles
+        .iter()
+        .find(|p| p.name == name || p.path.file_name().is_some_and(|f| f == name))
+        .map(|p| vec![p.path.clone()])
+        .ok_or_else(|| anyhow!("Firefox profile '{name}' not found in {:?}", root))
+}
+
+/// user_data_dir returns the directory the profiles of the given config entry are stored in.
+pub fn user_data_dir(ext: &Extension) -> Option<PathBuf> {
+    match (&ext.user_data_dir, ext.browser) {
+        (Some(dir), _) => Some(PathBuf::from(dir)),
+        (None, BrowserKind::Firefox) => default_firefox_dir().ok(),
+        (None, BrowserKind::Chromium) if Path::new(&ext.profile).is_absolute() => {
+            Some(PathBuf::from(&ext.profile))
+        }
+        (None, BrowserKind::Chromium) => None,
+    }
+}
+
+/// list returns all profiles found in the given user data directory.
+pub async fn list(kind: BrowserKind, root: &Path) -> Result<Vec<Profile>> {
+    match kind {
+        BrowserKind::Firefox => {
+            let profiles = firefox_profiles(root).await?;
+            let default = firefox_default_profile(root, &profiles)
+                .await?
+                .map(|p| p.path.clone());
+            Ok(profiles
+                .into_iter()
+                .map(|p| Profile {
+                    is_default: Some(&p.path) == default.as_ref(),
+                    name: p.name,
+                    path: p.path,
+                })
+                .collect())
+        }
+        BrowserKind::Chromium => {
+            let state = read_local_state(root).await?;
+            let last_used = state.profile.last_used.as_deref().unwrap_or("Default");
+            Ok(state
+                .profile
+                .info_cache
+                .into_iter()
+                .map(|(dir, info)| Profile {
+                    is_default: dir == last_used,
+                    name: info.name,
+                    path: root.join(dir),
+                })
+                .collect())
+        }
+    }
+}
+
+/// resolve_chromium checks that the profile `name` exists in the user data directory `root`.
+///
+/// Chromium offers the extensions of a user data directory to all of its profiles, so a single
+/// profile can only be referred to by name if it's the only one.
+async fn resolve_chromium(root: &Path, name: &str) -> Result<PathBuf> {
+    if name == ALL_PROFILES || name == DEFAULT_PROFILE {
+        return Ok(root.to_path_buf());
+    }
+    let profiles = list(BrowserKind::Chromium, root).await?;
+    if !profiles
+        .iter()
+        .any(|p| p.name == name || p.path.file_name().is_some_and(|f| f == name))
+    {
+        return Err(anyhow!("Chromium profile '{name}' not found in {:?}", root));
+    }
+    if profiles.len() > 1 {
+        return Err(anyhow!(
+            "Chromium profile '{name}' can't be targeted alone, the extensions of {:?} are offered to all of its profiles: use profile = \"*\" instead",
+            root
+        ));
+    }
+    Ok(root.to_path_buf())
+}
+
+async fn read_local_state(root: &Path) -> Result<LocalState> {
+    let path = root.join("Local State");
+    let contents = fs::read(&path)
+        .await
+        .map_err(|err| anyhow!("Failed to read {:?}: {}", path, err))?;
+    serde_json::from_slice(&contents).map_err(|err| anyhow!("Failed to parse {:?}: {}", path, err))
+}
+
+/// firefox_profiles returns the profiles listed in the `profiles.ini` in `root`.
+pub async fn firefox_profiles(root: &Path) -> Result<Vec<FirefoxProfile>> {
+    let ini = read_ini(&root.join("profiles.ini")).await?;
+    let mut result = Vec::new();
+    for (section, props) in ini.iter() {
+        if !section.is_some_and(|s| s.starts_with("Profile")) {
+            continue;
+        }
+        let Some(raw_path) = props.get("Path") else {
+            continue;
+        };
+        let path = match props.get("IsRelative") {
+            Some("1") => root.join(raw_path),
+            _ => PathBuf::from(raw_path),
+        };
+        result.push(FirefoxProfile {
+            name: props.get("Name").unwrap_or(raw_path).to_string(),
+            path,
+            raw_path: raw_path.to_string(),
+            legacy_default: props.get("Default") == Some("1"),
+        });
+    }
+    Ok(result)
+}
+
+/// firefox_default_profile determines the default profile like Firefox does: the default of
+/// the installation (`[Install…]` in `profiles.ini` or `installs.ini`) takes precedence over the
+/// legacy `Default=1` marker. A single profile is always the default.
+async fn firefox_default_profile<'a>(
+    root: &Path,
+    profiles: &'a [FirefoxProfile],
+) -> Result<Option<&'a FirefoxProfile>> {
+    let mut install_defaults: Vec<String> = Vec::new();
+    let profiles_ini = read_ini(&root.join("profiles.ini")).await?;
+    for (section, props) in profiles_ini.iter() {
+        if section.is_some_and(|s| s.starts_with("Install")) {
+            install_defaults.extend(props.get("Default").map(str::to_string));
+        }
+    }
+    let installs_ini = root.join("installs.ini");
+    if let Ok(true) = fs::try_exists(&installs_ini).await {
+        for (section, props) in read_ini(&installs_ini).await?.iter() {
+            if section.is_some() {
+                install_defaults.extend(props.get("Default").map(str::to_string));
+            }
+        }
+    }
+
+    let default = install_defaults
+        .iter()
+        .find_map(|default| profiles.iter().find(|p| &p.raw_path == default))
+        .or_else(|| profiles.iter().find(|p| p.legacy_default))
+        .or(match profiles {
+            [profile] => Some(profile),
+            _ => None,
+        });
+    Ok(default)
+}
+
+/// browser_version returns the version of the browser which last used the profile, if known.
+pub async fn browser_version(kind: BrowserKind, profile_dir: &Path) -> Option<String> {
+    match kind {
+        BrowserKind::Firefox => firefox_version(profile_dir).await,
+        BrowserKind::Chromium => chromium_version(profile_dir).await,
+    }
+}
+
+/// firefox_version returns the version of Firefox which last used the profile, as recorded in
+/// its `compatibility.ini` (e.g. `LastVersion=128.0_20240704121409/20240704121409`).
+pub async fn firefox_version(profile_dir: &Path) -> Option<String> {
+    let ini = read_ini(&profile_dir.join("compatibility.ini"))
+        .await
+        .ok()?;
+    let last_version = ini.get_from(Some("Compatibility"), "LastVersion")?;
+    let version = last_version.split('_').next().unwrap_or_default();
+    (!version.is_empty()).then(|| version.to_string())
+}
+
+/// chromium_version returns the version of Chromium which last used the user data directory,
+/// as recorded in its `Last Version` file.
+pub async fn chromium_version(user_data_dir: &Path) -> Option<String> {
+    let contents = fs::read_to_string(user_data_dir.join("Last Version"))
+        .await
+        .ok()?;
+    let version = contents.trim();
+    (!version.is_empty()).then(|| version.to_string())
+}
+
+async fn read_ini(path: &Path) -> Result<Ini> {
+    let contents = fs::read_to_string(path)
+        .await
+        .map_err(|err| anyhow!("Failed to read {:?}: {}", path, err))?;
+    Ini::load_from_str(&contents).map_err(|err| anyhow!("Failed to parse {:?}: {}", path, err))
+}
+
+/// default_firefox_dir returns the directory containing Firefox's `profiles.ini`.
+fn default_firefox_dir() -> Result<PathBuf> {
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("Unable to determine home directory"))?;
+    let dir = if cfg!(target_os = "windows") {
+        dirs::config_dir().map(|dir| dir.join("Mozilla").join("Firefox"))
+    } else if cfg!(target_os = "macos") {
+        dirs::config_dir().map(|dir| dir.join("Firefox"))
+    } else {
+        Some(home.join(".mozilla").join("firefox"))
+    };
+    dir.ok_or_else(|| anyhow!("Unable to determine Firefox directory"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LinkMode;
+    use temp_dir::TempDir;
+
+    const PROFILES_INI: &str = r#"
+[Profile1]
+Name=work
+IsRelative=0
+Path=/srv/firefox/work
+
+[Profile0]
+Name=default
+IsRelative=1
+Path=abcd1234.default
+Default=1
+
+[Profile2]
+Name=default-release
+IsRelative=1
+Path=efgh5678.default-release
+
+[General]
+StartWithLastProfile=1
+Version=2
+"#;
+
+    async fn create_root(profiles_ini: &str, installs_ini: Option<&str>) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("profiles.ini"), profiles_ini)
+            .await
+            .unwrap();
+        if let Some(installs_ini) = installs_ini {
+            fs::write(dir.path().join("installs.ini"), installs_ini)
+                .await
+                .unwrap();
+        }
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_firefox_profiles() {
+        let dir = create_root(PROFILES_INI, None).await;
+        let profiles = firefox_profiles(dir.path()).await.unwrap();
+        let names: Vec<_> = profiles.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["work", "default", "default-release"]);
+        assert_eq!(profiles[0].path, PathBuf::from("/srv/firefox/work"));
+        assert_eq!(profiles[1].path, dir.path().join("abcd1234.default"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_firefox() {
+        let dir = create_root(PROFILES_INI, None).await;
+        let root = dir.path();
+        assert_eq!(
+            resolve_firefox(root, "work").await.unwrap(),
+            [PathBuf::from("/srv/firefox/work")]
+        );
+        assert_eq!(
+            resolve_firefox(root, "efgh5678.default-release")
+                .await
+                .unwrap(),
+            [root.join("efgh5678.default-release")]
+        );
+        assert_eq!(resolve_firefox(root, "*").await.unwrap().len(), 3);
+        assert_eq!(
+            resolve_firefox(root, "missing")
+                .await
+                .unwrap_err()
+                .to_string(),
+            format!("Firefox profile 'missing' not found in {:?}", root)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_firefox_default() {
+        // legacy default
+        let dir = create_root(PROFILES_INI, None).await;
+        assert_eq!(
+            resolve_firefox(dir.path(), "default").await.unwrap(),
+            [dir.path().join("abcd1234.default")]
+        );
+
+        // the default of the installation takes precedence
+        let installs_ini = "[4F96D1932A9F858E]\nDefault=efgh5678.default-release\nLocked=1\n";
+        let dir = create_root(PROFILES_INI, Some(installs_ini)).await;
+        assert_eq!(
+            resolve_firefox(dir.path(), "default").await.unwrap(),
+            [dir.path().join("efgh5678.default-release")]
+        );
+
+        let profiles_ini = format!(
+            "{PROFILES_INI}\n[Install4F96D1932A9F858E]\nDefault=/srv/firefox/work\nLocked=1\n"
+        );
+        let dir = create_root(&profiles_ini, None).await;
+        assert_eq!(
+            resolve_firefox(dir.path(), "default").await.unwrap(),
+            [PathBuf::from("/srv/firefox/work")]
+        );
+    }
+
+    const LOCAL_STATE: &str = r#"{
+  "browser": {"enabled_labs_experiments": []},
+  "profile": {
+    "info_cache": {
+      "Default": {"name": "Person 1", "is_using_default_name": true},
+      "Profile 1": {"name": "Work"}
+    },
+    "last_used": "Profile 1",
+    "profiles_order": ["Default", "Profile 1"]
+  }
+}"#;
+
+    #[tokio::test]
+    async fn test_browser_version() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(firefox_version(dir.path()).await, None);
+        assert_eq!(chromium_version(dir.path()).await, None);
+
+        fs::write(
+            dir.path().join("compatibility.ini"),
+            "[Compatibility]\nLastVersion=128.0.3_20240729144208/20240729144208\nLastOSABI=Linux_x86_64-gcc3\n",
+        )
+        .await
+        .unwrap();
+        fs::write(dir.path().join("Last Version"), "126.0.6478.126")
+            .await
+            .unwrap();
+        assert_eq!(
+            firefox_version(dir.path()).await.as_deref(),
+            Some("128.0.3")
+        );
+        assert_eq!(
+            chromium_version(dir.path()).await.as_deref(),
+            Some("126.0.6478.126")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chromium_profiles() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("Local State"), LOCAL_STATE)
+            .await
+            .unwrap();
+        let root = dir.path();
+
+        assert_eq!(
+            list(BrowserKind::Chromium, root).await.unwrap(),
+            [
+                Profile {
+                    name: "Person 1".to_string(),
+                    path: root.join("Default"),
+                    is_default: false,
+                },
+                Profile {
+                    name: "Work".to_string(),
+                    path: root.join("Profile 1"),
+                    is_default: true,
+                }
+            ]
+        );
+        assert_eq!(resolve_chromium(root, "*").await.unwrap(), root);
+        assert_eq!(resolve_chromium(root, "default").await.unwrap(), root);
+        // the extensions would be offered to "Person 1" as well
+        for name in ["Work", "Profile 1"] {
+            assert_eq!(
+                resolve_chromium(root, name).await.unwrap_err().to_string(),
+                format!("Chromium profile '{name}' can't be targeted alone, the extensions of {:?} are offered to all of its profiles: use profile = \"*\" instead", root)
+            );
+        }
+        assert_eq!(
+            resolve_chromium(root, "Private")
+                .await
+                .unwrap_err()
+                .to_string(),
+            format!("Chromium profile 'Private' not found in {:?}", root)
+        );
+
+        // the only profile can be referred to by name
+        let dir = TempDir::new().unwrap();
+        fs::write(
+            dir.path().join("Local State"),
+            r#"{"profile": {"info_cache": {"Default": {"name": "Work"}}}}"#,
+        )
+        .await
+        .unwrap();
+        let root = dir.path();
+        assert_eq!(resolve_chromium(root, "Work").await.unwrap(), root);
+        assert_eq!(resolve_chromium(root, "Default").await.unwrap(), root);
+    }
+
+    #[tokio::test]
+    async fn test_list_firefox() {
+        let dir = create_root(PROFILES_INI, None).await;
+        let profiles = list(BrowserKind::Firefox, dir.path()).await.unwrap();
+        let defaults: Vec<_> = profiles.iter().map(|p| p.is_default).collect();
+        assert_eq!(defaults, [false, true, false]);
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let dir = create_root(PROFILES_INI, None).await;
+        let ext = Extension {
+            browser: BrowserKind::Firefox,
+            profile: "default-release".to_string(),
+            user_data_dir: Some(dir.path().to_string_lossy().into_owned()),
+            browser_version: None,
+            install_mode: InstallMode::Profile,
+            link_mode: LinkMode::Symlink,
+            names: vec![],
+        };
+        assert_eq!(
+            resolve(&ext).await.unwrap(),
+            [dir.path()
+                .join("efgh5678.default-release")
+                .to_string_lossy()
+                .into_owned()]
+        );
+
+        // absolute paths are used as-is
+        let ext = Extension {
+            profile: "/tmp/profile".to_string(),
+            user_data_dir: Some("/nonexistent".to_string()),
+            browser_version: None,
+            ..ext
+        };
+        assert_eq!(resolve(&ext).await.unwrap(), ["/tmp/profile"]);
+
+        // system-wide entries refer to a system extension directory
+        let ext = Extension {
+            profile: "/usr/lib/mozilla/extensions/{ec8030f7-c20a-464f-9b0e-13a3a9e97384}"
+                .to_string(),
+            install_mode: InstallMode::System,
+            ..ext
+        };
+        assert_eq!(
+            resolve(&ext).await.unwrap(),
+            std::slice::from_ref(&ext.profile)
+        );
+        let ext = Extension {
+            profile: "extensions".to_string(),
+            ..ext
+        };
+        assert!(resolve(&ext).await.is_err());
+
+        // unpacked entries refer to the directory the extensions are extracted to
+        let ext = Extension {
+            install_mode: InstallMode::Unpacked,
+            ..ext
+        };
+        assert!(resolve(&ext).await.is_err());
+        let ext = Extension {
+            profile: "/opt/kiosk/extensions".to_string(),
+            ..ext
+        };
+        assert_eq!(resolve(&ext).await.unwrap(), ["/opt/kiosk/extensions"]);
+    }
+}
@@ -0,0 +1,414 @@
+use anyhow::{anyhow, Result};
+use reqwest_middleware::ClientWithMiddleware;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tracing::{debug, info};
+
+use crate::artifact::{self, Artifact};
+use crate::checks::Checks;
+use crate::chromium::{self, crx3};
+use crate::config::{BrowserKind, ExtensionName, Target};
+use crate::firefox;
+use crate::manifest::{self, Manifest};
+use crate::plan::{Change, Plan};
+use crate::xpi::{self, TrustAnchor};
+
+/// An extension file read from a path or downloaded from a URL.
+struct Source {
+    contents: Vec<u8>,
+    /// The Firefox add-on guid or the Chromium extension ID.
+    id: String,
+    manifest: Manifest,
+    url: String,
+    sha256: String,
+}
+
+/// fetch reads or downloads an extension which is not in a store and stores it like a store
+/// download, i.e. as `<id>.xpi` or `<id>.crx` in `dest_dir`. The file must match the configured
+/// SHA-256 (if any) and is checked like a store download.
+///
+/// * `extension` - The path or URL of the extension and its expected SHA-256, if any.
+/// * `anchor` - The CA certificate the signature of a Firefox extension must chain up to.
+/// * `checks` - The checks the extension must pass.
+pub async fn fetch(
+    client: ClientWithMiddleware,
+    extension: ExtensionName,
+    kind: BrowserKind,
+    anchor: Arc<TrustAnchor>,
+    checks: Checks,
+    dest_dir: PathBuf,
+) -> Result<Artifact> {
+    let name = extension.name();
+    let locale = checks.locale.as_deref();
+    let source = read(&client, &extension, kind, &anchor, locale).await?;
+    let destination = destination(kind, &source.id, &dest_dir);
+    if let Some(locked) = &checks.locked {
+        locked.check_version(&source.manifest.version)?;
+    }
+    if let Some(browser_version) = checks.browser_version.as_deref() {
+        if let Some(reason) = incompatibility(kind, &source.manifest, browser_version) {
+            return Err(anyhow!(
+                "{name}: {} {reason}, but {kind} {browser_version} loads it",
+                source.manifest
+            ));
+        }
+    }
+
+    let stored = match fs::metadata(&destination).await {
+        Ok(_) => Some(artifact::sha256(&destination).await?),
+        Err(_) => None,
+    };
+    let old_manifest = match &stored {
+        Some(sha256) if *sha256 != source.sha256 => {
+            Some(stored_manifest(kind, &destination, locale).await?)
+        }
+        _ => None,
+    };
+    checks.check_manifest(name, old_manifest.as_ref(), &source.manifest)?;
+    let artifact = Artifact {
+        path: destination.clone(),
+        id: source.id,
+        version: source.manifest.version.clone(),
+        url: source.url,
+        sha256: source.sha256,
+    };
+    if let Some(locked) = &checks.locked {
+        locked.check_artifact(&artifact)?;
+    }
+
+    match (stored, old_manifest) {
+        (Some(_), None) => {
+            debug!("{name} {} already up-to-date", artifact.version);
+            return Ok(artifact);
+        }
+        (Some(_), Some(old)) => info!("{name}: updating {} -> {}", old.version, artifact.version),
+        (None, _) => debug!("Storing {name} {} as {:?}", artifact.version, destination),
+    }
+    artifact::store(&destination, &source.contents).await?;
+    Ok(artifact)
+}
+
+/// plan determines the changes a sync would perform for an extension which is not in a store.
+/// The file is read or downloaded and checked like in [`fetch`], but not stored.
+pub async fn plan(
+    client: ClientWithMiddleware,
+    extension: ExtensionName,
+    kind: BrowserKind,
+    anchor: Arc<TrustAnchor>,
+    dest_dir: PathBuf,
+    profiles: Vec<Target>,
+) -> Result<Plan> {
+    let name = extension.name().to_string();
+    let source = read(&client, &extension, kind, &anchor, None).await?;
+    let destination = destination(kind, &source.id, &dest_dir);
+    let new_version = source.manifest.version.clone();
+
+    let mut changes = Vec::new();
+    match fs::metadata(&destination).await {
+        Ok(_) if artifact::sha256(&destination).await? == source.sha256 => {}
+        Ok(_) => changes.push(Change::Update {
+            name,
+            from: stored_manifest(kind, &destination, None).await?.version,
+            to: new_version.clone(),
+            path: destination.clone(),
+        }),
+        Err(_) => changes.push(Change::Download {
+            name,
+            version: new_version.clone(),
+            path: destination.clone(),
+        }),
+    }
+
+    for p in profiles {
+        let installed = match kind {
+            BrowserKind::Chromium => {
+                chromium::is_installed(&destination, &source.id, &new_version, &p).await
+            }
+            BrowserKind::Firefox => {
+                firefox::is_installed(&destination, &source.id, &new_version, &p).await
+            }
+        };
+        if !installed {
+            changes.push(Change::Install {
+                location: PathBuf::from(p.profile),
+                source: destination.clone(),
+                version: new_version.clone(),
+            });
+        }
+    }
+
+    Ok(Plan {
+        file: Some(destination),
+        changes,
+    })
+}
+
+/// read reads the extension from its path or downloads it from its URL and verifies its
+/// SHA-256 and signature.
+async fn read(
+    client: &ClientWithMiddleware,
+    extension: &ExtensionName,
+    kind: BrowserKind,
+    anchor: &TrustAnchor,
+    locale: Option<&str>,
+) -> Result<Source> {
+    let name = extension.name();
+    let (contents, url) = match extension {
+        ExtensionName::File { path, .. } => {
+            debug!("Reading {kind} extension {path}");
+            let contents = fs::read(path)
+                .await
+                .map_err(|err| anyhow!("Failed to read {path}: {err}"))?;
+            (contents, String::new())
+        }
+        ExtensionName::Url { url, .. } => {
+            debug!("Downloading {kind} extension from {url}");
+            let response = client.get(url).send().await?.error_for_status()?;
+            (response.bytes().await?.to_vec(), url.clone())
+        }
+        _ => return Err(anyhow!("{name}: neither a path nor a URL")),
+    };
+
+    let sha256 = format!("{:x}", Sha256::digest(&contents));
+    if let Some(expected) = extension.sha256() {
+        if !sha256.eq_ignore_ascii_case(expected) {
+            return Err(anyhow!(
+                "{name}: hash mismatch! Expected {expected}, found {sha256}"
+            ));
+        }
+        debug!("Hash verified successfully");
+    }
+
+    let (id, manifest) = match kind {
+        BrowserKind::Firefox => {
+            let manifest = manifest::from_bytes(&contents, locale)?;
+            let guid = manifest
+                .gecko_id()
+                .ok_or_else(|| {
+                    anyhow!("{name}: manifest declares no add-on ID (browser_specific_settings.gecko.id)")
+                })?
+                .to_string();
+            xpi::verify(&contents, &guid, anchor)?;
+            (guid, manifest)
+        }
+        BrowserKind::Chromium => {
+            let crx_file = crx3::parse_bytes(contents.clone())?;
+            let extension_id = crx_file.id()?;
+            crx_file.verify(&extension_id)?;
+            (
+                extension_id,
+                manifest::from_bytes(&crx_file.zip_archive, locale)?,
+            )
+        }
+    };
+    Ok(Source {
+        contents,
+        id,
+        manifest,
+        url,
+        sha256,
+    })
+}
+
+/// destination returns where the extension is stored, which is the same as for store downloads.
+fn destination(kind: BrowserKind, id: &str, dest_dir: &Path) -> PathBuf {
+    match kind {
+        BrowserKind::Firefox => dest_dir.join(format!("{id}.xpi")),
+        BrowserKind::Chromium => dest_dir.join(format!("{id}.crx")),
+    }
+}
+
+/// stored_manifest returns the manifest of the stored file.
+async fn stored_manifest(kind: BrowserKind, path: &Path, locale: Option<&str>) -> Result<Manifest> {
+    match kind {
+        BrowserKind::Firefox => manifest::from_file(path, locale).await,
+        BrowserKind::Chromium => {
+            manifest::from_bytes(&crx3::parse_file(path).await?.zip_archive, locale)
+        }
+    }
+}
+
+/// incompatibility returns why the given browser version can't load the extension, if it can't.
+fn incompatibility(
+    kind: BrowserKind,
+    manifest: &Manifest,
+    browser_version: &str,
+) -> Option<String> {
+    match kind {
+        BrowserKind::Firefox => manifest.firefox_incompatibility(browser_version),
+        BrowserKind::Chromium => manifest.chromium_incompatibility(browser_version),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest_middleware::ClientBuilder;
+    use temp_dir::TempDir;
+
+    const CRX_FIXTURE: &str = "tests/fixtures/dbepggeogbaibhgnhhndojpepiihcmeb.crx";
+    const CRX_SHA256: &str = "0da10cd4dc8c5fc44c06f5a82153a199f63f69eeba1c235f4459f002e2d41d55";
+    const XPI_FIXTURE: &str = "tests/fixtures/vimium_ff-2.1.2-test-signed.xpi";
+    const TEST_ROOT: &str = "tests/fixtures/test-root-ca.pem";
+
+    #[tokio::test]
+    async fn test_fetch_file() {
+        let dest_dir = TempDir::new().unwrap();
+        let client = ClientBuilder::new(reqwest::Client::new()).build();
+        let extension = ExtensionName::File {
+            path: CRX_FIXTURE.to_string(),
+            sha256: Some(CRX_SHA256.to_string()),
+        };
+
+        let artifact = fetch(
+            client.clone(),
+            extension.clone(),
+            BrowserKind::Chromium,
+            Arc::default(),
+            Checks::default(),
+            dest_dir.path().to_path_buf(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(artifact.id, "dbepggeogbaibhgnhhndojpepiihcmeb");
+        assert_eq!(artifact.version, "2.1.2");
+        assert_eq!(artifact.sha256, CRX_SHA256);
+        assert_eq!(
+            artifact.path,
+            dest_dir.path().join("dbepggeogbaibhgnhhndojpepiihcmeb.crx")
+        );
+        assert_eq!(artifact::sha256(&artifact.path).await.unwrap(), CRX_SHA256);
+
+        // the stored file is up-to-date
+        let plan = plan(
+            client,
+            extension,
+            BrowserKind::Chromium,
+            Arc::default(),
+            dest_dir.path().to_path_buf(),
+            Vec::new(),
+        )
+        .await
+        .unwrap();
+        assert!(plan.changes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_file_hash_mismatch() {
+        let dest_dir = TempDir::new().unwrap();
+        let client = ClientBuilder::new(reqwest::Client::new()).build();
+        let extension = ExtensionName::File {
+            path: CRX_FIXTURE.to_string(),
+            sha256: Some("0".repeat(64)),
+        };
+
+        let err = fetch(
+            client,
+            extension,
+            BrowserKind::Chromium,
+            Arc::default(),
+            Checks::default(),
+            dest_dir.path().to_path_buf(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{CRX_FIXTURE}: hash mismatch! Expected {}, found {CRX_SHA256}",
+                "0".repeat(64)
+            )
+        );
+        assert!(fs::read_dir(dest_dir.path())
+            .await
+            .unwrap()
+            .next_entry()
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_url() {
+        let mut server = mockito::Server::new_async().await;
+        let m = server
+            .mock("GET", "/extensions/vimium.xpi")
+            .with_body_from_file(XPI_FIXTURE)
+            .with_status(200)
+            .create_async()
+            .await;
+        let dest_dir = TempDir::new().unwrap();
+        let client = ClientBuilder::new(reqwest::Client::new()).build();
+        let url = format!("{}/extensions/vimium.xpi", server.url());
+        let extension = ExtensionName::Url {
+            url: url.clone(),
+            sha256: None,
+        };
+
+        let plan = plan(
+            client.clone(),
+            extension.clone(),
+            BrowserKind::Firefox,
+            Arc::new(xpi::load_trust_anchor(TEST_ROOT).await.unwrap()),
+            dest_dir.path().to_path_buf(),
+            Vec::new(),
+        )
+        .await
+        .unwrap();
+        let guid = "{d7742d87-e61d-4b78-b8a1-b469842139fa}";
+        let destination = dest_dir.path().join(format!("{guid}.xpi"));
+        assert_eq!(
+            plan.changes,
+            [Change::Download {
+                name: url.clone(),
+                version: "2.1.2".to_string(),
+                path: destination.clone(),
+            }]
+        );
+
+        let artifact = fetch(
+            client,
+            extension,
+            BrowserKind::Firefox,
+            Arc::new(xpi::load_trust_anchor(TEST_ROOT).await.unwrap()),
+            Checks::default(),
+            dest_dir.path().to_path_buf(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(artifact.id, guid);
+        assert_eq!(artifact.url, url);
+        assert_eq!(artifact.path, destination);
+
+        m.expect(2).assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_fetch_incompatible() {
+        let dest_dir = TempDir::new().unwrap();
+        let client = ClientBuilder::new(reqwest::Client::new()).build();
+        let checks = Checks {
+            browser_version: Some("102.0".to_string()),
+            ..Default::default()
+        };
+
+        let err = fetch(
+            client,
+            ExtensionName::File {
+                path: XPI_FIXTURE.to_string(),
+                sha256: None,
+            },
+            BrowserKind::Firefox,
+            Arc::new(xpi::load_trust_anchor(TEST_ROOT).await.unwrap()),
+            checks,
+            dest_dir.path().to_path_buf(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("requires Firefox 112.0 or newer, but firefox 102.0 loads it"));
+    }
+}
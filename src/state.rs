@@ -0,0 +1,382 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tracing::debug;
+
+use crate::artifact::Artifact;
+use crate::checks::HeldUpdate;
+use crate::config::{BrowserKind, Target};
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+/// The state records the extensions managed by extension-downloader between runs.
+pub struct State {
+    #[serde(default)]
+    pub artifacts: Vec<ManagedArtifact>,
+    /// Updates which add permissions and wait for approval.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending: Vec<PendingUpdate>,
+    /// Chromium extensions installed using policies only, which are not stored.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub policies: Vec<PolicyInstallation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// A stored extension file and the profiles it is installed in.
+pub struct ManagedArtifact {
+    pub browser: BrowserKind,
+    /// The name of the extension as used in the config.
+    pub name: String,
+    /// The Firefox add-on guid or the Chromium extension ID.
+    pub id: String,
+    pub path: PathBuf,
+    pub version: String,
+    pub sha256: String,
+    /// The profiles the file is installed in.
+    #[serde(default)]
+    pub profiles: Vec<Target>,
+    /// When the file was stored (seconds since the Unix epoch).
+    pub installed_at: u64,
+    /// When the file was last replaced by another version (seconds since the Unix epoch).
+    pub updated_at: u64,
+    /// Since when the file is no longer used (seconds since the Unix epoch).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unused_since: Option<u64>,
+    /// Whether the file is a copy of a version kept for rollbacks.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub archived: bool,
+    /// Whether the extension was rolled back to this version and must not be updated.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+/// An update which was held because it adds permissions.
+pub struct PendingUpdate {
+    pub browser: BrowserKind,
+    pub name: String,
+    pub version: String,
+    /// The added permissions.
+    pub permissions: Vec<String>,
+    /// Whether the update may be installed by the next sync.
+    #[serde(default)]
+    pub approved: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+/// A Chromium extension force-installed using a policy file, which the browser downloads itself.
+pub struct PolicyInstallation {
+    /// The ID of the extension.
+    pub name: String,
+    /// The policy file.
+    pub target: Target,
+}
+
+impl ManagedArtifact {
+    /// add_profile records an installation, replacing the one in the same profile (if any) since
+    /// the install and link modes may have changed.
+    pub fn add_profile(&mut self, target: &Target) {
+        if !self.profiles.contains(target) {
+            self.remove_profile(&target.profile);
+            self.profiles.push(target.clone());
+            self.profiles.sort();
+        }
+    }
+
+    pub fn remove_profile(&mut self, profile: &str) {
+        self.profiles.retain(|p| p.profile != profile);
+    }
+}
+
+impl State {
+    pub fn get_mut(&mut self, path: &Path) -> Option<&mut ManagedArtifact> {
+        self.artifacts.iter_mut().find(|a| a.path == path)
+    }
+
+    /// pinned returns the version the extension was rolled back to, if any.
+    pub fn pinned(&self, browser: BrowserKind, name: &str) -> Option<&ManagedArtifact> {
+        self.artifacts
+            .iter()
+            .find(|a| a.browser == browser && a.name == name && a.pinned)
+    }
+
+    /// approved_version returns the held version of the extension which was approved, if any.
+    pub fn approved_version(&self, browser: BrowserKind, name: &str) -> Option<String> {
+        self.pending
+            .iter()
+            .find(|p| p.browser == browser && p.name == name && p.approved)
+            .map(|p| p.version.clone())
+    }
+
+    /// hold records a held update, replacing a previously held version of the extension.
+    pub fn hold(&mut self, browser: BrowserKind, update: &HeldUpdate) {
+        if self
+            .pending
+            .iter()
+            .any(|p| p.browser == browser && p.name == update.name && p.version == update.to)
+        {
+            return;
+        }
+        self.release_pending(browser, &update.name);
+        self.pending.push(PendingUpdate {
+            browser,
+            name: update.name.clone(),
+            version: update.to.clone(),
+            permissions: update.permissions.clone(),
+            approved: false,
+        });
+    }
+
+    /// release_pending forgets the held updates of the extension, e.g. once it was updated.
+    pub fn release_pending(&mut self, browser: BrowserKind, name: &str) {
+        self.pending
+            .retain(|p| !(p.browser == browser && p.name == name));
+    }
+
+    /// record adds a stored artifact or updates its entry if the file has changed.
+    pub fn record(
+        &mut self,
+        browser: BrowserKind,
+        name: &str,
+        artifact: &Artifact,
+    ) -> &mut ManagedArtifact {
+        let now = now();
+        let index = match self.artifacts.iter().position(|a| a.path == artifact.path) {
+            Some(index) => index,
+            None => {
+                self.artifacts.push(ManagedArtifact {
+                    browser,
+                    name: name.to_string(),
+                    id: artifact.id.clone(),
+                    path: artifact.path.clone(),
+                    version: artifact.version.clone(),
+                    sha256: artifact.sha256.clone(),
+                    profiles: Vec::new(),
+                    installed_at: now,
+                    updated_at: now,
+                    unused_since: None,
+                    archived: false,
+                    pinned: false,
+                });
+                self.artifacts.len() - 1
+            }
+        };
+        let entry = &mut self.artifacts[index];
+        if entry.sha256 != artifact.sha256 {
+            entry.version = artifact.version.clone();
+            entry.sha256 = artifact.sha256.clone();
+            entry.updated_at = now;
+        }
+        entry.name = name.to_string();
+        entry.id = artifact.id.clone();
+        entry
+    }
+
+    /// record_policy adds an installation using a policy file.
+    pub fn record_policy(&mut self, name: &str, target: &Target) {
+        let installation = PolicyInstallation {
+            name: name.to_string(),
+            target: target.clone(),
+        };
+        if !self.policies.contains(&installation) {
+            self.policies.push(installation);
+            self.policies.sort();
+        }
+    }
+
+    /// remove forgets the artifact stored at `path`.
+    pub fn remove(&mut self, path: &Path) -> Option<ManagedArtifact> {
+        let index = self.artifacts.iter().position(|a| a.path == path)?;
+        Some(self.artifacts.remove(index))
+    }
+}
+
+/// path returns the path of the state file in the given extensions directory.
+pub fn path(extensions_dir: &Path) -> PathBuf {
+    extensions_dir.join("state.json")
+}
+
+pub async fn from_file(path: &Path) -> Result<State> {
+    debug!("Loading state {:?}", path);
+    let contents = fs::read(path).await?;
+    serde_json::from_slice(&contents).map_err(|err| anyhow!("Failed to parse {:?}: {}", path, err))
+}
+
+/// write writes the state with its artifacts in a stable order.
+pub async fn write(path: &Path, state: &mut State) -> Result<()> {
+    debug!("Writing state {:?}", path);
+    state.artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(state)?).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// now returns the current time in seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// format_timestamp formats seconds since the Unix epoch as UTC date and time.
+pub fn format_timestamp(secs: u64) -> String {
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let rem = secs % 86400;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{InstallMode, LinkMode};
+    use temp_dir::TempDir;
+
+    fn artifact(version: &str, sha256: &str) -> Artifact {
+        Artifact {
+            path: PathBuf::from("/storage/chromium/foo.crx"),
+            id: "foo".to_string(),
+            version: version.to_string(),
+            url: String::new(),
+            sha256: sha256.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let mut state = State::default();
+        let entry = state.record(BrowserKind::Chromium, "foo", &artifact("1.0", "a"));
+        entry.add_profile(&Target::from("/profile"));
+        entry.add_profile(&Target::from("/profile"));
+        entry.installed_at = 1;
+        entry.updated_at = 1;
+
+        // unchanged
+        let entry = state.record(BrowserKind::Chromium, "foo", &artifact("1.0", "a"));
+        assert_eq!(entry.updated_at, 1);
+        assert_eq!(entry.profiles, [Target::from("/profile")]);
+
+        // another link mode replaces the installation
+        let copy = Target {
+            link_mode: LinkMode::Copy,
+            ..Target::from("/profile")
+        };
+        entry.add_profile(&copy);
+        assert_eq!(entry.profiles, [copy]);
+
+        // updated
+        let entry = state.record(BrowserKind::Chromium, "foo", &artifact("1.1", "b"));
+        assert_eq!(entry.version, "1.1");
+        assert_eq!(entry.installed_at, 1);
+        assert!(entry.updated_at > 1);
+        entry.remove_profile("/profile");
+        assert!(entry.profiles.is_empty());
+
+        assert_eq!(state.artifacts.len(), 1);
+        assert!(state
+            .remove(Path::new("/storage/chromium/foo.crx"))
+            .is_some());
+        assert!(state.artifacts.is_empty());
+    }
+
+    #[test]
+    fn test_pinned() {
+        let mut state = State::default();
+        state.record(BrowserKind::Chromium, "foo", &artifact("1.0", "a"));
+        assert!(state.pinned(BrowserKind::Chromium, "foo").is_none());
+        state.artifacts[0].pinned = true;
+        assert!(state.pinned(BrowserKind::Chromium, "foo").is_some());
+        assert!(state.pinned(BrowserKind::Firefox, "foo").is_none());
+    }
+
+    #[test]
+    fn test_hold() {
+        let mut state = State::default();
+        let mut update = HeldUpdate {
+            name: "foo".to_string(),
+            from: "1.0".to_string(),
+            to: "1.1".to_string(),
+            permissions: vec!["proxy".to_string()],
+        };
+        state.hold(BrowserKind::Chromium, &update);
+        assert!(state
+            .approved_version(BrowserKind::Chromium, "foo")
+            .is_none());
+
+        state.pending[0].approved = true;
+        state.hold(BrowserKind::Chromium, &update);
+        assert_eq!(
+            state.approved_version(BrowserKind::Chromium, "foo"),
+            Some("1.1".to_string())
+        );
+
+        // a newer update needs to be approved again
+        update.to = "1.2".to_string();
+        state.hold(BrowserKind::Chromium, &update);
+        assert_eq!(state.pending.len(), 1);
+        assert!(state
+            .approved_version(BrowserKind::Chromium, "foo")
+            .is_none());
+
+        state.release_pending(BrowserKind::Chromium, "foo");
+        assert!(state.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_write_and_read() {
+        let dir = TempDir::new().unwrap();
+        let path = path(&dir.path().join("storage"));
+        let mut state = State::default();
+        state.record(BrowserKind::Chromium, "foo", &artifact("1.0", "a"));
+        write(&path, &mut state).await.unwrap();
+        assert_eq!(from_file(&path).await.unwrap(), state);
+    }
+
+    #[test]
+    fn test_read_profiles() {
+        let state: State = serde_json::from_str(
+            r#"{"artifacts": [{"browser": "chromium", "name": "foo", "id": "foo",
+                "path": "/storage/chromium/foo.crx", "version": "1.0", "sha256": "a",
+                "profiles": ["/a", {"profile": "/b", "install_mode": "unpacked", "link_mode": "copy"}],
+                "installed_at": 1, "updated_at": 1}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            state.artifacts[0].profiles,
+            [
+                Target::from("/a"),
+                Target {
+                    profile: "/b".to_string(),
+                    install_mode: InstallMode::Unpacked,
+                    link_mode: LinkMode::Copy,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(1700000000), "2023-11-14 22:13:20 UTC");
+    }
+}
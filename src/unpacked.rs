@@ -0,0 +1,206 @@
+use anyhow::{anyhow, Result};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{debug, info};
+
+use crate::chromium::crx3;
+use crate::manifest;
+
+/// The pointer to the extracted version loaded by the browser.
+pub const CURRENT: &str = "current";
+
+/// The file recording the stored file an extension was extracted from.
+const SOURCE: &str = "source";
+
+/// current_dir returns the pointer to the extracted version of the extension, which stays the
+/// same across updates.
+pub fn current_dir(extension_id: &str, dir: &Path) -> PathBuf {
+    dir.join(extension_id).join(CURRENT)
+}
+
+/// install extracts the stored CRX or XPI file to `<dir>/<id>/<version>` and points
+/// `<dir>/<id>/current` to it. Other extracted versions are removed afterwards.
+pub async fn install(file: &Path, extension_id: &str, dir: &Path) -> Result<()> {
+    let archive = read_archive(file).await?;
+    let version = manifest::from_bytes(&archive, None)?.version;
+    if is_installed(file, extension_id, &version, dir).await {
+        debug!("{:?} is already extracted to {:?}", file, dir);
+        return Ok(());
+    }
+    info!("Extracting {:?} to {:?}", file, dir.join(extension_id));
+    let ext_dir = dir.join(extension_id);
+    let version_dir = ext_dir.join(&version);
+    let tmp = ext_dir.join(format!("{version}.tmp"));
+    _ = fs::remove_dir_all(&tmp).await;
+    fs::create_dir_all(&tmp).await?;
+    zip::ZipArchive::new(Cursor::new(archive))?
+        .extract(&tmp)
+        .map_err(|err| anyhow!("Failed to extract {:?}: {}", file, err))?;
+    _ = fs::remove_dir_all(&version_dir).await;
+    fs::rename(&tmp, &version_dir).await?;
+
+    // the browser never sees a missing pointer
+    let current_tmp = ext_dir.join(format!("{CURRENT}.tmp"));
+    _ = fs::remove_file(&current_tmp).await;
+    create_dir_symlink(Path::new(&version), &current_tmp).await?;
+    fs::rename(&current_tmp, ext_dir.join(CURRENT)).await?;
+    fs::write(ext_dir.join(SOURCE), file.to_string_lossy().as_bytes()).await?;
+
+    let mut read_dir = fs::read_dir(&ext_dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name();
+        if name != CURRENT && name != SOURCE && name.to_string_lossy() != version {
+            debug!("Removing {:?}", entry.path());
+            fs::remove_dir_all(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// uninstall removes the extracted versions of all extensions extracted from `file`.
+pub async fn uninstall(file: &Path, dir: &Path) -> Result<()> {
+    for (ext_dir, source) in extracted(dir).await? {
+        if source == file {
+            debug!("Removing {:?}", ext_dir);
+            fs::remove_dir_all(&ext_dir).await?;
+        }
+    }
+    Ok(())
+}
+
+/// installed_extensions returns the stored files the extensions in `dir` were extracted from.
+pub async fn installed_extensions(dir: &Path) -> Result<Vec<PathBuf>> {
+    Ok(extracted(dir)
+        .await?
+        .into_iter()
+        .map(|(_, source)| source)
+        .collect())
+}
+
+/// is_installed returns whether `current` points to the given version extracted from `file`.
+pub async fn is_installed(file: &Path, extension_id: &str, version: &str, dir: &Path) -> bool {
+    let ext_dir = dir.join(extension_id);
+    let current = fs::read_link(ext_dir.join(CURRENT)).await;
+    let source = fs::read_to_string(ext_dir.join(SOURCE)).await;
+    match (current, source) {
+        (Ok(current), Ok(source)) => current == Path::new(version) && Path::new(&source) == file,
+        _ => false,
+    }
+}
+
+/// flags returns the command-line flag which makes Chromium load the extensions extracted to
+/// `dir`, if any.
+pub async fn flags(dir: &Path) -> Result<Option<String>> {
+    let mut dirs: Vec<_> = extracted(dir)
+        .await?
+        .into_iter()
+        .map(|(ext_dir, _)| ext_dir.join(CURRENT).to_string_lossy().into_owned())
+        .collect();
+    if dirs.is_empty() {
+        return Ok(None);
+    }
+    dirs.sort();
+    Ok(Some(format!("--load-extension={}", dirs.join(","))))
+}
+
+/// extracted returns the directories of the extensions in `dir` and the stored files they were
+/// extracted from.
+async fn extracted(dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut result = Vec::new();
+    let mut read_dir = match fs::read_dir(dir).await {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(result),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = read_dir.next_entry().await? {
+        if let Ok(source) = fs::read_to_string(entry.path().join(SOURCE)).await {
+            result.push((entry.path(), PathBuf::from(source)));
+        }
+    }
+    Ok(result)
+}
+
+/// read_archive returns the ZIP archive of a CRX or XPI file.
+async fn read_archive(file: &Path) -> Result<Vec<u8>> {
+    let contents = fs::read(file).await?;
+    if file.extension().is_some_and(|ext| ext == "crx") {
+        return Ok(crx3::parse_bytes(contents)?.zip_archive);
+    }
+    Ok(contents)
+}
+
+#[cfg(target_os = "windows")]
+async fn create_dir_symlink(src: &Path, dst: &Path) -> Result<()> {
+    fs::symlink_dir(src, dst).await?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+async fn create_dir_symlink(src: &Path, dst: &Path) -> Result<()> {
+    fs::symlink(src, dst).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use temp_dir::TempDir;
+
+    fn create_xpi(version: &str) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        writer
+            .start_file("manifest.json", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        write!(writer, r#"{{"name": "Foo", "version": "{version}"}}"#).unwrap();
+        writer
+            .start_file(
+                "content/script.js",
+                zip::write::SimpleFileOptions::default(),
+            )
+            .unwrap();
+        writer.write_all(b"console.log('foo');").unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[tokio::test]
+    async fn test_install() {
+        let dir = TempDir::new().unwrap();
+        let xpi_file = dir.path().join("storage").join("foo@example.com.xpi");
+        let target = dir.path().join("unpacked");
+        fs::create_dir_all(xpi_file.parent().unwrap())
+            .await
+            .unwrap();
+        fs::write(&xpi_file, create_xpi("1.0")).await.unwrap();
+
+        install(&xpi_file, "foo@example.com", &target)
+            .await
+            .unwrap();
+        let current = current_dir("foo@example.com", &target);
+        assert!(is_installed(&xpi_file, "foo@example.com", "1.0", &target).await);
+        assert!(current.join("content").join("script.js").exists());
+        assert_eq!(
+            installed_extensions(&target).await.unwrap(),
+            std::slice::from_ref(&xpi_file)
+        );
+        assert_eq!(
+            flags(&target).await.unwrap(),
+            Some(format!("--load-extension={}", current.display()))
+        );
+
+        // an update swaps the pointer and removes the previous version
+        fs::write(&xpi_file, create_xpi("1.1")).await.unwrap();
+        install(&xpi_file, "foo@example.com", &target)
+            .await
+            .unwrap();
+        assert!(is_installed(&xpi_file, "foo@example.com", "1.1", &target).await);
+        assert_eq!(fs::read_link(&current).await.unwrap(), Path::new("1.1"));
+        assert!(!target.join("foo@example.com").join("1.0").exists());
+
+        uninstall(&xpi_file, &target).await.unwrap();
+        assert!(installed_extensions(&target).await.unwrap().is_empty());
+        assert_eq!(flags(&target).await.unwrap(), None);
+        assert!(!target.join("foo@example.com").exists());
+    }
+}
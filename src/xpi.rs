@@ -0,0 +1,595 @@
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use cms::{
+    cert::CertificateChoices,
+    content_info::ContentInfo,
+    signed_data::{SignedData, SignerIdentifier},
+};
+use der::{
+    asn1::{ObjectIdentifier, OctetString},
+    oid::AssociatedOid,
+    Decode, DecodePem, Encode,
+};
+use rsa::{pkcs8::DecodePublicKey, RsaPublicKey};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+use x509_cert::ext::pkix::{BasicConstraints, KeyUsage};
+use x509_cert::Certificate;
+
+const ID_SIGNED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.2");
+const ID_MESSAGE_DIGEST: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+const ID_SHA1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.14.3.2.26");
+const ID_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.1");
+const RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+const SHA1_WITH_RSA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.5");
+const SHA256_WITH_RSA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.11");
+const SHA384_WITH_RSA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.12");
+const SHA512_WITH_RSA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.13");
+const ID_AT_COMMON_NAME: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.4.3");
+
+const MANIFEST_PATH: &str = "META-INF/manifest.mf";
+const SIGNATURE_FILE_PATH: &str = "META-INF/mozilla.sf";
+const SIGNATURE_BLOCK_PATH: &str = "META-INF/mozilla.rsa";
+
+/// The AMO production signing CA (`signingca1.addons.mozilla.org`, issued by Mozilla's
+/// `root-ca-production-amo`) which signs the add-ons distributed by addons.mozilla.org.
+const AMO_SIGNING_CA: &[u8] = include_bytes!("../certs/amo-signing-ca.pem");
+
+#[derive(Debug)]
+/// The CA certificate the signing certificates of an XPI file must chain up to: either a root
+/// certificate such as Mozilla's `root-ca-production-amo` or the intermediate certificate itself.
+pub struct TrustAnchor(Certificate);
+
+impl Default for TrustAnchor {
+    /// The bundled AMO signing CA.
+    fn default() -> Self {
+        TrustAnchor(Certificate::from_pem(AMO_SIGNING_CA).expect("bundled certificate is valid"))
+    }
+}
+
+/// load_trust_anchor reads a PEM or DER encoded root certificate.
+pub async fn load_trust_anchor<P: AsRef<Path>>(path: P) -> Result<TrustAnchor> {
+    let contents = tokio::fs::read(path.as_ref())
+        .await
+        .with_context(|| format!("Failed to read root certificate {:?}", path.as_ref()))?;
+    let cert = if contents.starts_with(b"-----BEGIN") {
+        Certificate::from_pem(&contents)?
+    } else {
+        Certificate::from_der(&contents)?
+    };
+    Ok(TrustAnchor(cert))
+}
+
+/// verify checks the Mozilla signature (`META-INF/mozilla.rsa`) of an XPI file:
+///
+/// * every file of the archive is listed in `META-INF/manifest.mf` with a matching SHA-256 digest,
+/// * `META-INF/mozilla.sf` contains the digest of `META-INF/manifest.mf`,
+/// * `META-INF/mozilla.rsa` is a valid PKCS#7 signature of `META-INF/mozilla.sf`,
+/// * the signing certificate is issued by the included intermediate certificate which in turn
+///   either is `anchor` or is issued by it, each certificate issued while its issuer was valid
+///   and used as intended,
+/// * the add-on ID in the signing certificate's common name equals `guid`.
+///
+/// Expiry isn't checked against the current time: add-ons signed before their signing CA
+/// expired (e.g. `signingca1.addons.mozilla.org` in April 2025) remain valid.
+pub fn verify(contents: &[u8], guid: &str, anchor: &TrustAnchor) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(contents))?;
+    let manifest = read_entry(&mut archive, MANIFEST_PATH)
+        .map_err(|_| anyhow!("{guid}: extension is not signed"))?;
+    let signature_file = read_entry(&mut archive, SIGNATURE_FILE_PATH)?;
+    let signature_block = read_entry(&mut archive, SIGNATURE_BLOCK_PATH)?;
+
+    let signer = verify_signature_block(&signature_block, &signature_file, anchor)
+        .map_err(|err| anyhow!("{guid}: invalid signature: {err}"))?;
+    let signed_id = common_name(&signer)
+        .ok_or_else(|| anyhow!("{guid}: signing certificate does not contain an add-on ID"))?;
+    if signed_id != guid {
+        return Err(anyhow!(
+            "{guid}: extension is signed for add-on {signed_id}"
+        ));
+    }
+
+    verify_signature_file(&signature_file, &manifest)
+        .map_err(|err| anyhow!("{guid}: invalid signature: {err}"))?;
+    verify_manifest(&mut archive, &manifest).map_err(|err| anyhow!("{guid}: {err}"))?;
+    debug!("Verified signature of {guid}");
+    Ok(())
+}
+
+fn read_entry(archive: &mut zip::ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Vec<u8>> {
+    let mut file = archive.by_name(name)?;
+    let mut contents = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// verify_manifest checks that every file of the archive is listed in the manifest
+/// with a matching digest and that every listed file exists.
+fn verify_manifest(archive: &mut zip::ZipArchive<Cursor<&[u8]>>, manifest: &[u8]) -> Result<()> {
+    let mut digests = parse_manifest(manifest)?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let name = file.name().to_string();
+        if file.is_dir()
+            || name == MANIFEST_PATH
+            || name == SIGNATURE_FILE_PATH
+            || name == SIGNATURE_BLOCK_PATH
+        {
+            continue;
+        }
+        let expected = digests
+            .remove(&name)
+            .ok_or_else(|| anyhow!("{name} is not covered by the signature"))?;
+        let mut contents = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut contents)?;
+        if Sha256::digest(&contents).as_slice() != expected.as_slice() {
+            return Err(anyhow!("{name} was modified after signing"));
+        }
+    }
+    if let Some(name) = digests.keys().next() {
+        return Err(anyhow!("{name} is signed but missing in the archive"));
+    }
+    Ok(())
+}
+
+/// parse_manifest parses a JAR manifest and returns the SHA-256 digest of every named entry.
+fn parse_manifest(manifest: &[u8]) -> Result<HashMap<String, Vec<u8>>> {
+    let mut digests = HashMap::new();
+    for section in parse_sections(manifest)? {
+        let Some(name) = section.get("Name") else {
+            continue; // main section
+        };
+        let digest = section
+            .get("SHA256-Digest")
+            .ok_or_else(|| anyhow!("{name} has no SHA-256 digest"))?;
+        digests.insert(name.clone(), BASE64.decode(digest)?);
+    }
+    Ok(digests)
+}
+
+/// verify_signature_file checks that the signature file contains the digest of the manifest.
+fn verify_signature_file(signature_file: &[u8], manifest: &[u8]) -> Result<()> {
+    let sections = parse_sections(signature_file)?;
+    let digest = sections
+        .first()
+        .and_then(|main| main.get("SHA256-Digest-Manifest"))
+        .ok_or_else(|| anyhow!("signature file has no SHA-256 manifest digest"))?;
+    if BASE64.decode(digest)? != Sha256::digest(manifest).as_slice() {
+        return Err(anyhow!("manifest was modified after signing"));
+    }
+    Ok(())
+}
+
+/// parse_sections splits a JAR manifest (or signature file) into its sections of `key: value` pairs.
+/// Lines starting with a space continue the previous line.
+fn parse_sections(contents: &[u8]) -> Result<Vec<HashMap<String, String>>> {
+    let contents = std::str::from_utf8(contents)?;
+    let mut sections = Vec::new();
+    let mut section: HashMap<String, String> = HashMap::new();
+    let mut last_key: Option<String> = None;
+    for line in contents.lines() {
+        if line.is_empty() {
+            if !section.is_empty() {
+                sections.push(std::mem::take(&mut section));
+            }
+            last_key = None;
+        } else if let Some(continuation) = line.strip_prefix(' ') {
+            let key = last_key
+                .as_ref()
+                .ok_or_else(|| anyhow!("Invalid manifest continuation line"))?;
+            section.get_mut(key).unwrap().push_str(continuation);
+        } else {
+            let (key, value) = line
+                .split_once(": ")
+                .ok_or_else(|| anyhow!("Invalid manifest line: {line}"))?;
+            section.insert(key.to_string(), value.to_string());
+            last_key = Some(key.to_string());
+        }
+    }
+    if !section.is_empty() {
+        sections.push(section);
+    }
+    Ok(sections)
+}
+
+/// verify_signature_block verifies the PKCS#7 signature of the signature file and the certificate
+/// chain of the signer. Returns the signing certificate.
+fn verify_signature_block(
+    signature_block: &[u8],
+    signature_file: &[u8],
+    anchor: &TrustAnchor,
+) -> Result<Certificate> {
+    let content_info = ContentInfo::from_der(signature_block)?;
+    if content_info.content_type != ID_SIGNED_DATA {
+        return Err(anyhow!("not a PKCS#7 signed-data structure"));
+    }
+    let signed_data: SignedData = content_info.content.decode_as()?;
+    let certificates: Vec<&Certificate> = signed_data
+        .certificates
+        .iter()
+        .flat_map(|set| set.0.iter())
+        .filter_map(|choice| match choice {
+            CertificateChoices::Certificate(cert) => Some(cert),
+            _ => None,
+        })
+        .collect();
+
+    let signer_info = signed_data
+        .signer_infos
+        .0
+        .iter()
+        .next()
+        .ok_or_else(|| anyhow!("no signer"))?;
+    let SignerIdentifier::IssuerAndSerialNumber(sid) = &signer_info.sid else {
+        return Err(anyhow!("unsupported signer identifier"));
+    };
+    let signer = certificates
+        .iter()
+        .find(|cert| {
+            cert.tbs_certificate.issuer == sid.issuer
+                && cert.tbs_certificate.serial_number == sid.serial_number
+        })
+        .ok_or_else(|| anyhow!("signing certificate is missing"))?;
+
+    let digest_alg = signer_info.digest_alg.oid;
+    let signed_message = match &signer_info.signed_attrs {
+        Some(attrs) => {
+            let message_digest: OctetString = attrs
+                .iter()
+                .find(|attr| attr.oid == ID_MESSAGE_DIGEST)
+                .and_then(|attr| attr.values.get(0))
+                .ok_or_else(|| anyhow!("message digest is missing"))?
+                .decode_as()?;
+            let digest = match digest_alg {
+                ID_SHA1 => Sha1::digest(signature_file).to_vec(),
+                ID_SHA256 => Sha256::digest(signature_file).to_vec(),
+                oid => return Err(anyhow!("unsupported digest algorithm {oid}")),
+            };
+            if message_digest.as_bytes() != digest.as_slice() {
+                return Err(anyhow!("signature file was modified after signing"));
+            }
+            attrs.to_der()?
+        }
+        None => signature_file.to_vec(),
+    };
+    let signature_alg = match (signer_info.signature_algorithm.oid, digest_alg) {
+        (RSA_ENCRYPTION, ID_SHA1) => SHA1_WITH_RSA,
+        (RSA_ENCRYPTION, ID_SHA256) => SHA256_WITH_RSA,
+        (oid, _) => oid,
+    };
+    verify_rsa(
+        signer,
+        signature_alg,
+        &signed_message,
+        signer_info.signature.as_bytes(),
+    )?;
+
+    // signer -> intermediate (-> anchor)
+    let intermediate = certificates
+        .iter()
+        .find(|cert| cert.tbs_certificate.subject == signer.tbs_certificate.issuer)
+        .ok_or_else(|| anyhow!("intermediate certificate is missing"))?;
+    verify_issued_by(signer, intermediate)?;
+    let TrustAnchor(anchor) = anchor;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    check_validity(signer, intermediate, now)?;
+    if *intermediate != anchor {
+        if intermediate.tbs_certificate.issuer != anchor.tbs_certificate.subject {
+            return Err(anyhow!(
+                "certificate chain does not lead to the trusted root"
+            ));
+        }
+        verify_issued_by(intermediate, anchor)?;
+        check_validity(intermediate, anchor, now)?;
+    }
+    check_signing_cert(signer)?;
+    check_ca_cert(intermediate)?;
+
+    Ok((*signer).clone())
+}
+
+/// check_validity fails if the certificate is not yet valid at `now` (since the Unix epoch) or
+/// was issued while `issuer` was not valid.
+fn check_validity(cert: &Certificate, issuer: &Certificate, now: Duration) -> Result<()> {
+    let name = common_name(cert).unwrap_or_default();
+    let issued = cert.tbs_certificate.validity.not_before;
+    if now < issued.to_unix_duration() {
+        return Err(anyhow!("certificate {name} is not valid before {issued}"));
+    }
+    let validity = &issuer.tbs_certificate.validity;
+    if issued.to_unix_duration() < validity.not_before.to_unix_duration()
+        || issued.to_unix_duration() > validity.not_after.to_unix_duration()
+    {
+        return Err(anyhow!(
+            "certificate {name} was issued at {issued}, but {} is only valid from {} until {}",
+            common_name(issuer).unwrap_or_default(),
+            validity.not_before,
+            validity.not_after
+        ));
+    }
+    Ok(())
+}
+
+/// check_ca_cert fails unless the certificate may issue certificates, i.e. it is marked as CA by
+/// its basic constraints and its key usage (if any) allows signing certificates.
+fn check_ca_cert(cert: &Certificate) -> Result<()> {
+    let name = common_name(cert).unwrap_or_default();
+    match cert.tbs_certificate.get::<BasicConstraints>()? {
+        Some((_, constraints)) if constraints.ca => {}
+        _ => return Err(anyhow!("certificate {name} is not a CA certificate")),
+    }
+    match cert.tbs_certificate.get::<KeyUsage>()? {
+        Some((_, usage)) if !usage.key_cert_sign() => Err(anyhow!(
+            "certificate {name} may not be used to sign certificates"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// check_signing_cert fails if the certificate is a CA certificate or its key usage (if any)
+/// doesn't allow digital signatures.
+fn check_signing_cert(cert: &Certificate) -> Result<()> {
+    let name = common_name(cert).unwrap_or_default();
+    if let Some((_, constraints)) = cert.tbs_certificate.get::<BasicConstraints>()? {
+        if constraints.ca {
+            return Err(anyhow!("signing certificate {name} is a CA certificate"));
+        }
+    }
+    match cert.tbs_certificate.get::<KeyUsage>()? {
+        Some((_, usage)) if !usage.digital_signature() => Err(anyhow!(
+            "certificate {name} may not be used for digital signatures"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// verify_issued_by verifies the signature of `cert` made by `issuer`.
+fn verify_issued_by(cert: &Certificate, issuer: &Certificate) -> Result<()> {
+    let tbs = cert.tbs_certificate.to_der()?;
+    let signature = cert
+        .signature
+        .as_bytes()
+        .ok_or_else(|| anyhow!("invalid certificate signature"))?;
+    verify_rsa(issuer, cert.signature_algorithm.oid, &tbs, signature)
+}
+
+fn verify_rsa(
+    cert: &Certificate,
+    algorithm: ObjectIdentifier,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    let public_key =
+        RsaPublicKey::from_public_key_der(&cert.tbs_certificate.subject_public_key_info.to_der()?)?;
+    match algorithm {
+        SHA1_WITH_RSA => verify_rsa_with::<Sha1>(public_key, message, signature),
+        SHA256_WITH_RSA => verify_rsa_with::<Sha256>(public_key, message, signature),
+        SHA384_WITH_RSA => verify_rsa_with::<Sha384>(public_key, message, signature),
+        SHA512_WITH_RSA => verify_rsa_with::<Sha512>(public_key, message, signature),
+        oid => Err(anyhow!("unsupported signature algorithm {oid}")),
+    }
+}
+
+fn verify_rsa_with<D: Digest + AssociatedOid>(
+    public_key: RsaPublicKey,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    use rsa::signature::Verifier;
+
+    let verifying_key = rsa::pkcs1v15::VerifyingKey::<D>::new(public_key);
+    let signature = rsa::pkcs1v15::Signature::try_from(signature)?;
+    verifying_key.verify(message, &signature)?;
+    Ok(())
+}
+
+/// common_name returns the common name (CN) of the certificate's subject.
+fn common_name(cert: &Certificate) -> Option<String> {
+    cert.tbs_certificate
+        .subject
+        .0
+        .iter()
+        .flat_map(|rdn| rdn.0.iter())
+        .find(|atv| atv.oid == ID_AT_COMMON_NAME)
+        .and_then(|atv| std::str::from_utf8(atv.value.value()).ok())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const FIXTURE: &str = "tests/fixtures/vimium_ff-2.1.2-test-signed.xpi";
+    const AMO_FIXTURE: &str = "tests/fixtures/vimium_ff-2.1.2.xpi";
+    const TEST_ROOT: &str = "tests/fixtures/test-root-ca.pem";
+    const GUID: &str = "{d7742d87-e61d-4b78-b8a1-b469842139fa}";
+
+    async fn test_anchor() -> TrustAnchor {
+        load_trust_anchor(TEST_ROOT).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_verify() {
+        let contents = std::fs::read(FIXTURE).unwrap();
+        verify(&contents, GUID, &test_anchor().await).unwrap();
+        let contents = std::fs::read("tests/fixtures/signed-test.xpi").unwrap();
+        verify(&contents, "signed-test@example.com", &test_anchor().await).unwrap();
+    }
+
+    #[test]
+    fn test_verify_amo() {
+        // signed by addons.mozilla.org with a signing CA which has expired since
+        let contents = std::fs::read(AMO_FIXTURE).unwrap();
+        verify(&contents, GUID, &TrustAnchor::default()).unwrap();
+        let contents = std::fs::read(FIXTURE).unwrap();
+        let err = verify(&contents, GUID, &TrustAnchor::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{GUID}: invalid signature: certificate chain does not lead to the trusted root"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_wrong_guid() {
+        let contents = std::fs::read(FIXTURE).unwrap();
+        let err = verify(&contents, "uBlock0@raymondhill.net", &test_anchor().await).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("uBlock0@raymondhill.net: extension is signed for add-on {GUID}")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_modified_file() {
+        let contents = std::fs::read(FIXTURE).unwrap();
+        let contents = rewrite_archive(&contents, |name, data| {
+            if name == "manifest.json" {
+                data.extend_from_slice(b" ");
+            }
+        });
+        let err = verify(&contents, GUID, &test_anchor().await).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("{GUID}: manifest.json was modified after signing")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_unsigned() {
+        let contents = std::fs::read(FIXTURE).unwrap();
+        let contents = rewrite_archive(&contents, |_, _| {});
+        let mut unsigned = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let mut archive = zip::ZipArchive::new(Cursor::new(contents.as_slice())).unwrap();
+        for i in 0..archive.len() {
+            let file = archive.by_index(i).unwrap();
+            if !file.name().starts_with("META-INF/") {
+                unsigned.raw_copy_file(file).unwrap();
+            }
+        }
+        let contents = unsigned.finish().unwrap().into_inner();
+        let err = verify(&contents, GUID, &test_anchor().await).unwrap_err();
+        assert_eq!(err.to_string(), format!("{GUID}: extension is not signed"));
+    }
+
+    #[tokio::test]
+    async fn test_verify_untrusted_root() {
+        // signed by addons.mozilla.org, not by the test root
+        let contents = std::fs::read(AMO_FIXTURE).unwrap();
+        let err = verify(&contents, GUID, &test_anchor().await).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{GUID}: invalid signature: certificate chain does not lead to the trusted root"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_chain() {
+        // signer, intermediate
+        let contents = std::fs::read(FIXTURE).unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(contents.as_slice())).unwrap();
+        let signature_block = read_entry(&mut archive, SIGNATURE_BLOCK_PATH).unwrap();
+        let signed_data: SignedData = ContentInfo::from_der(&signature_block)
+            .unwrap()
+            .content
+            .decode_as()
+            .unwrap();
+        let certificates: Vec<Certificate> = signed_data
+            .certificates
+            .unwrap()
+            .0
+            .into_vec()
+            .into_iter()
+            .filter_map(|choice| match choice {
+                CertificateChoices::Certificate(cert) => Some(cert),
+                _ => None,
+            })
+            .collect();
+        let signer = certificates
+            .iter()
+            .find(|cert| common_name(cert).as_deref() == Some(GUID))
+            .unwrap();
+        let intermediate = certificates
+            .iter()
+            .find(|cert| common_name(cert).as_deref() == Some("test-signing-ca"))
+            .unwrap();
+
+        check_signing_cert(signer).unwrap();
+        check_ca_cert(intermediate).unwrap();
+        assert_eq!(
+            check_ca_cert(signer).unwrap_err().to_string(),
+            format!("certificate {GUID} is not a CA certificate")
+        );
+        assert_eq!(
+            check_signing_cert(intermediate).unwrap_err().to_string(),
+            "signing certificate test-signing-ca is a CA certificate"
+        );
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        check_validity(signer, intermediate, now).unwrap();
+        let issued = signer.tbs_certificate.validity.not_before;
+        assert_eq!(
+            check_validity(signer, intermediate, Duration::ZERO)
+                .unwrap_err()
+                .to_string(),
+            format!("certificate {GUID} is not valid before {issued}")
+        );
+        // issued in 2015, before the test certificates
+        let TrustAnchor(amo) = TrustAnchor::default();
+        let validity = &intermediate.tbs_certificate.validity;
+        assert_eq!(
+            check_validity(&amo, intermediate, now)
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "certificate signingca1.addons.mozilla.org was issued at {}, but test-signing-ca is only valid from {} until {}",
+                amo.tbs_certificate.validity.not_before,
+                validity.not_before,
+                validity.not_after
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_sections_continuation() {
+        let sections = parse_sections(
+            b"Manifest-Version: 1.0\r\n\r\nName: a/very/long/path/which/is/wrapped/after/seventy/two/b\r\n ytes.js\r\nSHA256-Digest: abc\r\n",
+        )
+        .unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(
+            sections[1]["Name"],
+            "a/very/long/path/which/is/wrapped/after/seventy/two/bytes.js"
+        );
+        assert_eq!(sections[1]["SHA256-Digest"], "abc");
+    }
+
+    /// rewrite_archive re-creates a ZIP archive and lets `f` modify the contents of each file.
+    fn rewrite_archive(contents: &[u8], f: impl Fn(&str, &mut Vec<u8>)) -> Vec<u8> {
+        let mut archive = zip::ZipArchive::new(Cursor::new(contents)).unwrap();
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).unwrap();
+            let name = file.name().to_string();
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+            f(&name, &mut data);
+            writer
+                .start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(&data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+}